@@ -2,10 +2,13 @@ use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::str::FromStr;
 
+use crate::events;
+
 pub static LABEL_ADDRESSES: Lazy<std::sync::RwLock<HashMap<String, u8>>> =
     Lazy::new(|| std::sync::RwLock::new(HashMap::new()));
 
 /// Enum for register operation instructions
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum RegisterOp {
     ADDI,
@@ -42,6 +45,7 @@ impl FromStr for RegisterOp {
 }
 
 /// Enum for conditional jump instructions
+#[allow(clippy::upper_case_acronyms)]
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 enum ConditionalJump {
     JMPEQ,
@@ -80,19 +84,19 @@ impl FromStr for ConditionalJump {
     }
 }
 
-fn compare_length(rest_length: usize, expected_length: usize) {
+fn compare_length(rest_length: usize, expected_length: usize) -> Result<(), String> {
     if rest_length != expected_length {
-        panic!(
+        return Err(format!(
             "Error: Expected {} arguments, but got {}.",
             expected_length, rest_length
-        );
+        ));
     }
+    Ok(())
 }
 
 /// Checks if a string is a valid register (e.g., "R0".."RF"). Returns Ok(register_number) or Err(error message).
-fn parse_register(instruction_string: &str) -> Result<u8, String> {
-    if instruction_string.starts_with("R") {
-        let reg_str = &instruction_string[1..];
+pub fn parse_register(instruction_string: &str) -> Result<u8, String> {
+    if let Some(reg_str) = instruction_string.strip_prefix('R') {
         match u8::from_str_radix(reg_str, 16) {
             Ok(num) if num <= 15 => Ok(num),
             Ok(_) => Err(format!(
@@ -109,11 +113,6 @@ fn parse_register(instruction_string: &str) -> Result<u8, String> {
     }
 }
 
-/// Deprecated: Use parse_register instead.
-fn remove_r_from_register_string(instruction_string: &str) -> u8 {
-    parse_register(instruction_string).unwrap()
-}
-
 /// Checks if a string is a valid hex value (0-255). Returns Ok(value) or Err(error message).
 fn parse_hex_value(value: &str) -> Result<u8, String> {
     match u8::from_str_radix(value, 16) {
@@ -130,16 +129,26 @@ fn is_valid_memory(memory: &[String]) -> bool {
     memory.len() == 3 && memory[0] == "[" && memory[2] == "]"
 }
 
-fn confirm_equal_strings(instruction_string: &str, expected_string: &str) {
+fn confirm_equal_strings(instruction_string: &str, expected_string: &str) -> Result<(), String> {
     if instruction_string != expected_string {
-        panic!(
+        return Err(format!(
             "Error: Expected instruction '{}', but got '{}'.",
             expected_string, instruction_string
-        );
+        ));
     }
+    Ok(())
+}
+
+/// Splits a statement into tokens, giving `->` and `,` tokens of their own.
+pub fn tokenize(line: &str) -> Vec<String> {
+    line.replace("->", " -> ")
+        .replace(",", " , ")
+        .split_whitespace()
+        .map(|s| s.to_string())
+        .collect()
 }
 
-fn grab_memory_parts(source: &str) -> Vec<String> {
+pub fn grab_memory_parts(source: &str) -> Vec<String> {
     let source_parts: Vec<String> = source
         .replace("[", " [ ")
         .replace("]", " ] ")
@@ -150,23 +159,21 @@ fn grab_memory_parts(source: &str) -> Vec<String> {
     source_parts
 }
 
-fn process_rot_instruction(rest: &[String]) -> [u8; 2] {
-    compare_length(rest.len(), 3);
-    confirm_equal_strings(&rest[1], ",");
-
-    println!("Processing ROT instruction: R{}, {}", rest[0], rest[2]);
+fn process_rot_instruction(rest: &[String]) -> Result<[u8; 2], String> {
+    compare_length(rest.len(), 3)?;
+    confirm_equal_strings(&rest[1], ",")?;
 
-    let n_reg: u8 = remove_r_from_register_string(&rest[0]);
+    let n_reg: u8 = parse_register(&rest[0])?;
     let rot_amount: u8 = u8::from_str_radix(&rest[2], 16)
-        .unwrap_or_else(|_| panic!("Error: Invalid rotation amount '{}'", rest[2]));
+        .map_err(|_| format!("Error: Invalid rotation amount '{}'", rest[2]))?;
 
     if rot_amount > 15 {
-        panic!("Error: Rotation amount must be between 0 and 15.");
+        return Err("Error: Rotation amount must be between 0 and 15.".to_string());
     }
 
     let opcode: u8 = 0xA << 4 | (n_reg & 0x0F);
     let operand: u8 = rot_amount & 0x0F;
-    [opcode, operand]
+    Ok([opcode, operand])
 }
 
 fn process_register_operation_instructions(
@@ -176,7 +183,7 @@ fn process_register_operation_instructions(
     let op = RegisterOp::from_str(instruction_string)
         .map_err(|_| format!("Error: Invalid instruction '{}'.", instruction_string))?;
 
-    compare_length(rest.len(), 5);
+    compare_length(rest.len(), 5)?;
 
     let reg_n = parse_register(&rest[0])?;
     let reg_m = parse_register(&rest[2])?;
@@ -185,122 +192,112 @@ fn process_register_operation_instructions(
     let opcode: u8 = op.opcode() | (reg_p & 0x0F);
     let operand: u8 = (reg_n & 0x0F) << 4 | (reg_m & 0x0F);
 
-    println!(
-        "Processing instruction: {:?} R{}, R{}, R{} -> {:02X}, {:02X}",
-        op, reg_n, reg_m, reg_p, opcode, operand
-    );
-
     Ok([opcode, operand])
 }
 
-fn process_mov_instruction(rest: &[String]) -> [u8; 2] {
-    compare_length(rest.len(), 3);
-    confirm_equal_strings(&rest[1], "->");
+fn process_mov_instruction(rest: &[String]) -> Result<[u8; 2], String> {
+    compare_length(rest.len(), 3)?;
+    confirm_equal_strings(&rest[1], "->")?;
 
     let part_1_source_parts: Vec<String> = grab_memory_parts(&rest[0]);
     let part_2_source_parts: Vec<String> = grab_memory_parts(&rest[2]);
 
     if part_1_source_parts.len() == 1 && part_2_source_parts.len() == 1 {
-        let binding: [u8; 2] = mov_one_to_one_part(
+        mov_one_to_one_part(
             part_1_source_parts[0].as_str(),
             part_2_source_parts[0].as_str(),
-        );
-        return binding;
+        )
     } else if part_1_source_parts.len() == 1 && part_2_source_parts.len() == 3 {
-        let binding: [u8; 2] =
-            mov_one_to_three_parts(part_1_source_parts[0].as_str(), &part_2_source_parts);
-        return binding;
+        mov_one_to_three_parts(part_1_source_parts[0].as_str(), &part_2_source_parts)
     } else if part_1_source_parts.len() == 3 && part_2_source_parts.len() == 1 {
-        let bindings: [u8; 2] =
-            mov_three_to_one_parts(&part_1_source_parts, part_2_source_parts[0].as_str());
-        return bindings;
+        mov_three_to_one_parts(&part_1_source_parts, part_2_source_parts[0].as_str())
     } else {
-        panic!(
+        Err(format!(
             "1. Error: Invalid MOV instruction format. Expected one part in the first or second position, but got '{}', '{}'.",
             part_1_source_parts[0], part_2_source_parts[0]
-        );
+        ))
     }
 }
 
-fn mov_one_to_one_part(part_1: &str, part_2: &str) -> [u8; 2] {
+fn mov_one_to_one_part(part_1: &str, part_2: &str) -> Result<[u8; 2], String> {
     if parse_register(part_1).is_ok() && parse_register(part_2).is_ok() {
         let reg_m: u8 = parse_register(part_1).unwrap();
         let reg_n: u8 = parse_register(part_2).unwrap();
-        [0x40, (reg_m << 4) | reg_n]
+        Ok([0x40, (reg_m << 4) | reg_n])
     } else if parse_hex_value(part_1).is_ok() && parse_register(part_2).is_ok() {
         let value: u8 = parse_hex_value(part_1).unwrap();
         let reg_n: u8 = parse_register(part_2).unwrap();
-        [0x20 | (reg_n & 0x0F), value]
+        Ok([0x20 | (reg_n & 0x0F), value])
     } else {
-        panic!(
+        Err(format!(
             "2. Error: Invalid MOV instruction format. Expected one part in the first or second position, but got '{}', '{}'.",
             part_1, part_2
-        );
+        ))
     }
 }
 
-fn mov_one_to_three_parts(part_1: &str, part_2: &[String]) -> [u8; 2] {
+fn mov_one_to_three_parts(part_1: &str, part_2: &[String]) -> Result<[u8; 2], String> {
     if parse_register(part_1).is_ok() && is_valid_memory(part_2) {
         if parse_register(part_2[1].as_str()).is_ok() {
             let reg_m: u8 = parse_register(part_2[1].as_str()).unwrap();
             let reg_n: u8 = parse_register(part_1).unwrap();
-            [0xE0, (reg_n << 4) | reg_m]
+            Ok([0xE0, (reg_n << 4) | reg_m])
         } else if parse_hex_value(part_2[1].as_str()).is_ok() {
             let value: u8 = parse_hex_value(part_2[1].as_str()).unwrap();
             let reg_n: u8 = parse_register(part_1).unwrap();
-            [0x30 | (reg_n & 0x0F), value]
+            Ok([0x30 | (reg_n & 0x0F), value])
         } else {
-            panic!(
+            Err(format!(
                 "3. Error: Invalid MOV instruction format. Expected one part in the first or second position, but got '{}', '{}'.",
                 part_1, part_2[1]
-            );
+            ))
         }
     } else {
-        panic!(
+        Err(format!(
             "4. Error: Invalid MOV instruction format. Expected one part in the first or second position, but got '{}', '{:?}'.",
             part_1, part_2
-        );
+        ))
     }
 }
 
-fn mov_three_to_one_parts(part_1: &[String], part_2: &str) -> [u8; 2] {
+fn mov_three_to_one_parts(part_1: &[String], part_2: &str) -> Result<[u8; 2], String> {
     if is_valid_memory(part_1) && parse_register(part_2).is_ok() {
         if parse_register(part_1[1].as_str()).is_ok() {
             let reg_n: u8 = parse_register(part_1[1].as_str()).unwrap();
             let memory_address: u8 = parse_register(part_2).unwrap();
-            [0xD0, (memory_address << 4) | reg_n]
+            Ok([0xD0, (memory_address << 4) | reg_n])
         } else if parse_hex_value(part_1[1].as_str()).is_ok() {
             let value: u8 = parse_hex_value(part_1[1].as_str()).unwrap();
             let memory_address: u8 = parse_register(part_2).unwrap();
-            [0x10 | (memory_address & 0x0F), value]
+            Ok([0x10 | (memory_address & 0x0F), value])
         } else {
-            panic!(
+            Err(format!(
                 "5. Error: Invalid MOV instruction format. Expected one part in the first or second position, but got '{:?}', '{}'.",
                 part_1, part_2
-            );
+            ))
         }
     } else {
-        panic!(
+        Err(format!(
             "6. Error: Invalid MOV instruction format. Expected one part in the first or second position, but got '{:?}', '{}'.",
             part_1, part_2
-        );
+        ))
     }
 }
 
-fn process_jmp_instruction(rest: &[String]) -> [u8; 2] {
-    compare_length(rest.len(), 1);
+fn process_jmp_instruction(rest: &[String]) -> Result<[u8; 2], String> {
+    compare_length(rest.len(), 1)?;
 
     if parse_register(&rest[0]).is_ok() {
         let reg_n: u8 = parse_register(&rest[0]).unwrap();
-        return [0xF0, 0x00 | (reg_n & 0x0F)];
+        Ok([0xF0, reg_n & 0x0F])
     } else if parse_hex_value(&rest[0]).is_ok() {
         let value: u8 = parse_hex_value(&rest[0]).unwrap();
-        return [0xB0, value];
+        Ok([0xB0, value])
     } else {
-        panic!(
+        Err(format!(
             "Error: Invalid JMP instruction format. Expected a register or a value, but got '{}'.",
             rest[0]
-        );
+        ))
     }
 }
 
@@ -308,8 +305,8 @@ fn process_conditional_jump_instruction(
     instruction_string: &str,
     rest: &[String],
 ) -> Result<[u8; 2], String> {
-    compare_length(rest.len(), 3);
-    confirm_equal_strings(rest[1].as_str(), ",");
+    compare_length(rest.len(), 3)?;
+    confirm_equal_strings(rest[1].as_str(), ",")?;
 
     // Special case for JMPEQ with value
     if instruction_string == "JMPEQ" {
@@ -331,39 +328,44 @@ fn process_conditional_jump_instruction(
     Ok([0xF << 4 | (reg_m & 0x0F), jump.code() << 4 | (reg_n & 0x0F)])
 }
 
-fn parse_instructions(lines: Vec<String>) -> Result<Vec<u8>, String> {
-    let mut bytes: Vec<u8> = Vec::new();
-
-    for line in lines {
-        let split_line: Vec<String> = line
-            .replace("->", " -> ")
-            .replace(",", " , ")
-            .split_whitespace()
-            .map(|s| s.to_string())
-            .collect();
-
-        let mut code: Vec<u8> = Vec::new();
-
-        match split_line.get(0).map(|s| s.as_str()) {
-            Some("HALT") => code.extend_from_slice(&[0xC0, 0x00]),
-            Some("NOP") => code.extend_from_slice(&[0x0F, 0xFF]),
-            Some("ROT") => code.extend_from_slice(&process_rot_instruction(&split_line[1..])),
-            Some("MOV") => code.extend_from_slice(&process_mov_instruction(&split_line[1..])),
-            Some("ADDI") | Some("ADDF") | Some("OR") | Some("AND") | Some("XOR") => {
-                let returned_code =
-                    process_register_operation_instructions(&split_line[0], &split_line[1..])?;
-                code.extend_from_slice(&returned_code);
-            }
-            Some("JMP") => code.extend_from_slice(&process_jmp_instruction(&split_line[1..])),
-            Some("JMPEQ") | Some("JMPNE") | Some("JMPGE") | Some("JMPLE") | Some("JMPGT")
-            | Some("JMPLT") => {
-                let returned_code =
-                    process_conditional_jump_instruction(&split_line[0], &split_line[1..])?;
-                code.extend_from_slice(&returned_code);
-            }
-            _ => return Err(format!("Error: Invalid instruction '{}'.", line)),
+/// Every instruction mnemonic the assembler accepts.
+pub const MNEMONICS: [&str; 16] = [
+    "HALT", "NOP", "ROT", "MOV", "ADDI", "ADDF", "OR", "AND", "XOR", "JMP", "JMPEQ", "JMPNE",
+    "JMPGE", "JMPLE", "JMPGT", "JMPLT",
+];
+
+/// Assembles one cleaned instruction line.
+pub fn assemble_line(line: &str) -> Result<[u8; 2], String> {
+    let split_line: Vec<String> = tokenize(line);
+
+    match split_line.first().map(|s| s.as_str()) {
+        Some("HALT") => Ok([0xC0, 0x00]),
+        Some("NOP") => Ok([0x0F, 0xFF]),
+        Some("ROT") => process_rot_instruction(&split_line[1..]),
+        Some("MOV") => process_mov_instruction(&split_line[1..]),
+        Some("ADDI") | Some("ADDF") | Some("OR") | Some("AND") | Some("XOR") => {
+            process_register_operation_instructions(&split_line[0], &split_line[1..])
         }
+        Some("JMP") => process_jmp_instruction(&split_line[1..]),
+        Some("JMPEQ") | Some("JMPNE") | Some("JMPGE") | Some("JMPLE") | Some("JMPGT")
+        | Some("JMPLT") => process_conditional_jump_instruction(&split_line[0], &split_line[1..]),
+        _ => Err(format!("Error: Invalid instruction '{}'.", line)),
+    }
+}
+
+/// An instruction that failed to assemble, by its position in the cleaned lines.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct InstructionError {
+    pub index: usize,
+    pub message: String,
+}
 
+fn parse_instructions(lines: Vec<String>) -> Result<Vec<u8>, InstructionError> {
+    let mut bytes: Vec<u8> = Vec::new();
+
+    for (index, line) in lines.iter().enumerate() {
+        let code: [u8; 2] =
+            assemble_line(line).map_err(|message| InstructionError { index, message })?;
         bytes.extend(code);
     }
 
@@ -373,7 +375,7 @@ fn parse_instructions(lines: Vec<String>) -> Result<Vec<u8>, String> {
 pub fn assembler(
     cleaned_lines: Vec<String>,
     label_addresses: HashMap<String, u8>,
-) -> Result<Vec<u8>, String> {
+) -> Result<Vec<u8>, InstructionError> {
     // Store the label addresses in the static variable using write lock
     {
         let mut map: std::sync::RwLockWriteGuard<'_, HashMap<String, u8>> =
@@ -383,10 +385,7 @@ pub fn assembler(
 
     let bytes = parse_instructions(cleaned_lines)?;
 
-    println!("Final assembled code:");
-    for line in &bytes {
-        println!("{:02X}", line);
-    }
+    events::phase("encode", || format!("{:02X?}", bytes));
 
     Ok(bytes)
 }
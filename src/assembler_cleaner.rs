@@ -1,114 +1,697 @@
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::fs;
 use std::io::Error;
+use std::path::{Path, PathBuf};
 
-fn read_initial_data() -> Result<Vec<String>, Error> {
-    let contents: String = fs::read_to_string("./test1.nha")?;
-    let lines: Vec<String> = contents.lines().map(|line| line.to_string()).collect();
-    Ok(lines)
+use crate::assembler2::parse_register;
+use crate::display;
+use crate::events;
+use crate::float8;
+use crate::optimizer::{self, Optimization};
+use crate::program::AssemblyError;
+use crate::source_map::{SourceLocation, SourceMap};
+
+pub type CleanedSource = (
+    Vec<String>,
+    HashMap<String, u8>,
+    Vec<u8>,
+    SourceMap,
+    Vec<Optimization>,
+);
+
+/// A statement that produced bytes, remembered so the source map can be built after layout.
+struct Origin {
+    line_index: usize,
+    statement: String,
+    label: Option<String>,
+}
+
+struct Layout {
+    labels: HashMap<String, u8>,
+    lines: Vec<String>,
+    data_labels: HashMap<String, u8>,
+    data_entries: Vec<u8>,
+    // One entry per instruction line, and one per data byte
+    line_origins: Vec<Origin>,
+    data_origins: Vec<Origin>,
+    table_entries: Vec<TableEntry>,
+}
+
+fn read_initial_data(path: &str) -> Result<String, Error> {
+    fs::read_to_string(path)
+}
+
+/// Splits a line into its code and its `//` comment, if any.
+pub fn split_comment(line: &str) -> (&str, Option<&str>) {
+    match line.find("//") {
+        Some(index) => (&line[..index], Some(&line[index..])),
+        None => (line, None),
+    }
+}
+
+/// Splits a statement into its label, if any, and the rest of the statement.
+pub fn split_label(code: &str) -> (Option<&str>, &str) {
+    match code.split_once(':') {
+        Some((label, rest)) => (Some(label.trim()), rest.trim()),
+        None => (None, code.trim()),
+    }
 }
 
 fn remove_comments(lines: Vec<String>) -> Vec<String> {
     let mut new_lines: Vec<String> = Vec::new();
 
     for line in lines {
-        match line.find("//") {
-            Some(index) => {
-                new_lines.push(line[..index].trim().to_string());
-            }
-            None => {
-                new_lines.push(line.trim().to_string());
-            }
-        };
+        let (code, _) = split_comment(&line);
+        new_lines.push(code.trim().to_string());
     }
     new_lines
 }
 
-fn remove_whitespace(lines: Vec<String>) -> Vec<String> {
-    let mut new_lines: Vec<String> = Vec::new();
+/// Drops empty lines, keeping the index of each remaining line in the source file.
+fn remove_whitespace(lines: Vec<String>) -> Vec<(usize, String)> {
+    let mut new_lines: Vec<(usize, String)> = Vec::new();
 
-    for line in lines.into_iter() {
+    for (line_index, line) in lines.into_iter().enumerate() {
         if !line.is_empty() {
             let cleaned_lines: String = line.trim().to_string();
-            new_lines.push(cleaned_lines);
+            new_lines.push((line_index, cleaned_lines));
         }
     }
     new_lines
 }
 
-fn fill_label_address(
-    lines: Vec<String>,
-) -> (
-    HashMap<String, u8>,
-    Vec<String>,
-    HashMap<String, u8>,
-    Vec<u8>,
-) {
+pub const CONTROL_DIRECTIVES: [&str; 5] = [".IF", ".ELSE", ".ENDIF", ".WHILE", ".ENDW"];
+
+/// An open `.IF` or `.WHILE`, with the line that opened it.
+enum Block {
+    If {
+        line_index: usize,
+        else_label: String,
+        end_label: String,
+        has_else: bool,
+    },
+    While {
+        line_index: usize,
+        top_label: String,
+        end_label: String,
+    },
+}
+
+/// Parses `Rn == Rm` or `Rn != Rm` into the two registers and whether the test is equality.
+fn parse_condition(text: &str) -> Result<(String, bool, String), String> {
+    let spaced: String = text.replace("==", " == ").replace("!=", " != ");
+    let parts: Vec<&str> = spaced.split_whitespace().collect();
+    if parts.len() != 3 || (parts[1] != "==" && parts[1] != "!=") {
+        return Err(format!(
+            "Error: Invalid condition '{}'. Expected 'Rn == Rm' or 'Rn != Rm'.",
+            text.trim()
+        ));
+    }
+    let mut left: u8 = parse_register(parts[0])?;
+    let mut right: u8 = parse_register(parts[2])?;
+    // The right register is moved into R0 first, which would overwrite R0 on
+    // the left; both tests are symmetric, so compare the other way round
+    if left == 0 {
+        std::mem::swap(&mut left, &mut right);
+    }
+    Ok((
+        format!("R{:X}", left),
+        parts[1] == "==",
+        format!("R{:X}", right),
+    ))
+}
+
+/// Expands `.IF`/`.ELSE`/`.ENDIF` and `.WHILE`/`.ENDW` into plain instructions.
+/// The machine only compares against R0, so each condition becomes a MOV into
+/// R0 and a JMPEQ. Generated labels start with `_` and stand on their own lines.
+fn lower_directives(lines: Vec<(usize, String)>) -> Result<Vec<(usize, String)>, (usize, String)> {
+    let used: HashSet<String> = lines
+        .iter()
+        .filter_map(|(_, line)| split_label(line).0)
+        .map(|label| label.to_string())
+        .collect();
+    let mut counter: usize = 0;
+    let mut fresh_label = |kind: &str| -> String {
+        loop {
+            counter += 1;
+            let label: String = format!("_{}{}", kind, counter);
+            if !used.contains(&label) {
+                return label;
+            }
+        }
+    };
+
+    let mut lowered: Vec<(usize, String)> = Vec::new();
+    let mut blocks: Vec<Block> = Vec::new();
+
+    for (line_index, line) in lines {
+        let (label, statement) = split_label(&line);
+        let (directive, rest) = match statement.split_once(char::is_whitespace) {
+            Some((directive, rest)) => (directive.to_uppercase(), rest),
+            None => (statement.to_uppercase(), ""),
+        };
+        if !CONTROL_DIRECTIVES.contains(&directive.as_str()) {
+            lowered.push((line_index, line));
+            continue;
+        }
+
+        let mut emit = |text: String| lowered.push((line_index, text));
+        if let Some(label) = label {
+            emit(format!("{}:", label));
+        }
+
+        match directive.as_str() {
+            ".IF" => {
+                let (left, equal, right) =
+                    parse_condition(rest).map_err(|message| (line_index, message))?;
+                let else_label: String = fresh_label("else");
+                let end_label: String = fresh_label("endif");
+                emit(format!("MOV {} -> R0", right));
+                if equal {
+                    let then_label: String = fresh_label("then");
+                    emit(format!("JMPEQ {}, {}", then_label, left));
+                    emit(format!("JMP {}", else_label));
+                    emit(format!("{}:", then_label));
+                } else {
+                    emit(format!("JMPEQ {}, {}", else_label, left));
+                }
+                blocks.push(Block::If {
+                    line_index,
+                    else_label,
+                    end_label,
+                    has_else: false,
+                });
+            }
+            ".ELSE" => match blocks.last_mut() {
+                Some(Block::If {
+                    else_label,
+                    end_label,
+                    has_else: has_else @ false,
+                    ..
+                }) => {
+                    *has_else = true;
+                    emit(format!("JMP {}", end_label));
+                    emit(format!("{}:", else_label));
+                }
+                _ => {
+                    return Err((
+                        line_index,
+                        "Error: .ELSE without a matching .IF.".to_string(),
+                    ))
+                }
+            },
+            ".ENDIF" => match blocks.pop() {
+                Some(Block::If {
+                    else_label,
+                    end_label,
+                    has_else,
+                    ..
+                }) => {
+                    if !has_else {
+                        emit(format!("{}:", else_label));
+                    }
+                    emit(format!("{}:", end_label));
+                }
+                _ => {
+                    return Err((
+                        line_index,
+                        "Error: .ENDIF without a matching .IF.".to_string(),
+                    ))
+                }
+            },
+            ".WHILE" => {
+                let (left, equal, right) =
+                    parse_condition(rest).map_err(|message| (line_index, message))?;
+                let top_label: String = fresh_label("while");
+                let end_label: String = fresh_label("endw");
+                emit(format!("{}:", top_label));
+                emit(format!("MOV {} -> R0", right));
+                if equal {
+                    let body_label: String = fresh_label("do");
+                    emit(format!("JMPEQ {}, {}", body_label, left));
+                    emit(format!("JMP {}", end_label));
+                    emit(format!("{}:", body_label));
+                } else {
+                    emit(format!("JMPEQ {}, {}", end_label, left));
+                }
+                blocks.push(Block::While {
+                    line_index,
+                    top_label,
+                    end_label,
+                });
+            }
+            ".ENDW" => match blocks.pop() {
+                Some(Block::While {
+                    top_label,
+                    end_label,
+                    ..
+                }) => {
+                    emit(format!("JMP {}", top_label));
+                    emit(format!("{}:", end_label));
+                }
+                _ => {
+                    return Err((
+                        line_index,
+                        "Error: .ENDW without a matching .WHILE.".to_string(),
+                    ))
+                }
+            },
+            _ => unreachable!("not a control directive: {}", directive),
+        }
+    }
+
+    match blocks.pop() {
+        Some(Block::If { line_index, .. }) => Err((
+            line_index,
+            "Error: .IF without a matching .ENDIF.".to_string(),
+        )),
+        Some(Block::While { line_index, .. }) => Err((
+            line_index,
+            "Error: .WHILE without a matching .ENDW.".to_string(),
+        )),
+        None => Ok(lowered),
+    }
+}
+
+/// Inline `.bitmap` art: rows of `#` (lit) and `.` (unlit), separated by `/`.
+fn is_bitmap_art(text: &str) -> bool {
+    !text.is_empty() && text.chars().all(|c| matches!(c, '#' | '.' | '/'))
+}
+
+/// The statement's keyword, uppercased, and the rest of it.
+fn split_keyword(statement: &str) -> (String, &str) {
+    match statement.split_once(char::is_whitespace) {
+        Some((keyword, operands)) => (keyword.to_uppercase(), operands.trim()),
+        None => (statement.to_uppercase(), ""),
+    }
+}
+
+/// The PBM file a `.bitmap` statement names, relative to the source file.
+fn bitmap_file(source_path: &str, operand: &str) -> Option<PathBuf> {
+    let operand: &str = operand.trim().trim_matches('"');
+    if operand.is_empty() || is_bitmap_art(operand) {
+        return None;
+    }
+    let directory: &Path = Path::new(source_path).parent().unwrap_or(Path::new(""));
+    Some(directory.join(operand))
+}
+
+/// Every image file the source's `.bitmap` directives read.
+pub fn bitmap_files(source_path: &str, contents: &str) -> Vec<PathBuf> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (code, _) = split_comment(line);
+            let (_, statement) = split_label(code.trim());
+            let (keyword, operands) = split_keyword(statement.trim());
+            (keyword == ".BITMAP")
+                .then(|| bitmap_file(source_path, operands))
+                .flatten()
+        })
+        .collect()
+}
+
+/// Turns every `.bitmap` into a one-line `.bitmap` with inline art. A bare
+/// `.bitmap` takes the art rows on the lines up to `.endbitmap`; otherwise
+/// its operand is inline art or the path of a PBM image.
+fn expand_bitmaps(
+    lines: Vec<(usize, String)>,
+    source_path: &str,
+) -> Result<Vec<(usize, String)>, (usize, String)> {
+    let mut expanded: Vec<(usize, String)> = Vec::new();
+    let mut lines = lines.into_iter();
+
+    while let Some((line_index, line)) = lines.next() {
+        let (label, statement) = split_label(&line);
+        let (keyword, operands) = split_keyword(statement);
+        if keyword != ".BITMAP" || is_bitmap_art(operands) {
+            expanded.push((line_index, line));
+            continue;
+        }
+
+        let art: String = match bitmap_file(source_path, operands) {
+            Some(file) => {
+                let data: Vec<u8> = fs::read(&file).map_err(|e| {
+                    (
+                        line_index,
+                        format!("Error reading bitmap {}: {}", file.display(), e),
+                    )
+                })?;
+                let rows: Vec<Vec<bool>> =
+                    display::parse_pbm(&data).map_err(|message| (line_index, message))?;
+                rows.iter()
+                    .map(|row| row.iter().map(|lit| if *lit { '#' } else { '.' }).collect())
+                    .collect::<Vec<String>>()
+                    .join("/")
+            }
+            None => {
+                let mut rows: Vec<String> = Vec::new();
+                loop {
+                    match lines.next() {
+                        Some((_, row)) if row.eq_ignore_ascii_case(".ENDBITMAP") => break,
+                        Some((row_index, row)) => {
+                            if !is_bitmap_art(&row) || row.contains('/') {
+                                return Err((
+                                    row_index,
+                                    format!(
+                                        "Error: Invalid .bitmap row '{}'. Use '#' and '.'.",
+                                        row
+                                    ),
+                                ));
+                            }
+                            rows.push(row);
+                        }
+                        None => {
+                            return Err((
+                                line_index,
+                                "Error: .BITMAP without a matching .ENDBITMAP.".to_string(),
+                            ))
+                        }
+                    }
+                }
+                rows.join("/")
+            }
+        };
+
+        let statement: String = format!(".bitmap {}", art);
+        expanded.push((
+            line_index,
+            match label {
+                Some(label) => format!("{}: {}", label, statement),
+                None => statement,
+            },
+        ));
+    }
+
+    Ok(expanded)
+}
+
+/// Keywords that lay out bytes in the data area, after the code, like DATA.
+pub const DATA_DIRECTIVES: [&str; 6] = ["RES", ".SPACE", ".FILL", ".ALIGN", ".TABLE", ".BITMAP"];
+
+fn is_data_statement(statement: &str) -> bool {
+    let keyword: String = statement
+        .split_whitespace()
+        .next()
+        .unwrap_or("")
+        .to_uppercase();
+    statement.to_uppercase().starts_with("DATA") || DATA_DIRECTIVES.contains(&keyword.as_str())
+}
+
+/// A count for RES, .space, .fill or .align. Like every other number, it is hex.
+fn parse_count(text: &str, directive: &str) -> Result<usize, String> {
+    u8::from_str_radix(text.trim(), 16)
+        .map(|count| count as usize)
+        .map_err(|_| format!("Error: Invalid count '{}' for {}.", text.trim(), directive))
+}
+
+/// The bytes a data statement lays out at `address`: zero padding that comes
+/// before its label, its own bytes, and for `.table`, the labels whose
+/// addresses fill them once every label is known.
+fn data_bytes(statement: &str, address: u8) -> Result<(usize, Vec<u8>, Vec<String>), String> {
+    let (keyword, operands) = split_keyword(statement);
+
+    match keyword.as_str() {
+        "RES" | ".SPACE" => Ok((0, vec![0; parse_count(operands, &keyword)?], Vec::new())),
+        ".FILL" => {
+            let (count, value) = operands.split_once(',').ok_or_else(|| {
+                format!(
+                    "Error: Invalid .fill '{}'. Expected '.fill n, value'.",
+                    operands
+                )
+            })?;
+            let bytes: Vec<u8> = data_entry(value)?;
+            if bytes.len() != 1 {
+                return Err(format!(
+                    "Error: .fill needs a single byte value, got '{}'.",
+                    value.trim()
+                ));
+            }
+            Ok((0, vec![bytes[0]; parse_count(count, &keyword)?], Vec::new()))
+        }
+        ".ALIGN" => {
+            let alignment: usize = parse_count(operands, &keyword)?.max(1);
+            let padding: usize = (alignment - address as usize % alignment) % alignment;
+            Ok((padding, Vec::new(), Vec::new()))
+        }
+        ".TABLE" => {
+            let labels: Vec<String> = operands
+                .split(',')
+                .map(|label| label.trim().to_string())
+                .filter(|label| !label.is_empty())
+                .collect();
+            Ok((0, vec![0; labels.len()], labels))
+        }
+        ".BITMAP" => {
+            let rows: Vec<Vec<bool>> = operands
+                .split('/')
+                .filter(|row| !row.is_empty())
+                .map(|row| row.chars().map(|c| c == '#').collect())
+                .collect();
+            let width: usize = rows.first().map_or(0, |row| row.len());
+            if rows.iter().any(|row| row.len() != width) {
+                return Err("Error: Every .bitmap row must be the same width.".to_string());
+            }
+            if width == 0 || width > display::WIDTH || rows.len() > display::HEIGHT {
+                return Err(format!(
+                    "Error: A .bitmap must be between 1×1 and {}×{} pixels, got {}×{}.",
+                    display::WIDTH,
+                    display::HEIGHT,
+                    width,
+                    rows.len()
+                ));
+            }
+
+            // A full-screen image goes straight into the display region
+            let padding: usize = if width == display::WIDTH && rows.len() == display::HEIGHT {
+                if address as usize > display::DISPLAY_START {
+                    return Err(format!(
+                        "Error: A full-screen .bitmap must start at {:02X}, but code and data already reach {:02X}.",
+                        display::DISPLAY_START,
+                        address
+                    ));
+                }
+                display::DISPLAY_START - address as usize
+            } else {
+                0
+            };
+            Ok((padding, display::pack_rows(&rows), Vec::new()))
+        }
+        _ => {
+            let data_str: &str = statement.trim_start_matches("DATA");
+            Ok((0, data_entry(data_str)?, Vec::new()))
+        }
+    }
+}
+
+/// A `.table` byte still waiting for the address of its label.
+struct TableEntry {
+    offset: usize,
+    label: String,
+    line_index: usize,
+}
+
+/// Lays out code and DATA. Errors carry the index of the offending source line.
+fn fill_label_address(lines: Vec<(usize, String)>) -> Result<Layout, (usize, String)> {
     let mut label_hashmap: HashMap<String, u8> = HashMap::new();
     let mut data_hashmap: HashMap<String, u8> = HashMap::new();
     let mut new_lines: Vec<String> = Vec::new();
     let mut data_entries: Vec<u8> = Vec::new();
+    let mut line_origins: Vec<Origin> = Vec::new();
+    let mut data_origins: Vec<Origin> = Vec::new();
+    let mut table_entries: Vec<TableEntry> = Vec::new();
 
-    // First pass: count instructions
+    // First pass: count instructions. Labels on their own lines take no space.
     let mut instruction_count: i32 = 0;
-    for line in &lines {
-        if !line.contains(':') || (!line.trim().ends_with("DATA") && !line.contains("DATA")) {
+    for (_, line) in &lines {
+        let (_, statement) = split_label(line);
+        if !statement.is_empty() && !is_data_statement(statement) {
             instruction_count += 1;
         }
     }
 
     let mut data_pc: u8 = (instruction_count * 2) as u8;
 
-    for (index, line) in lines.iter().enumerate() {
-        if line.contains(':') {
-            let split_line: Vec<&str> = line.split(':').collect();
-            if split_line.len() == 2 {
-                let variable_name: String = split_line[0].trim().to_string();
-                let value_part: &str = split_line[1].trim();
+    for (line_index, line) in lines.iter() {
+        if line.split(':').count() > 2 {
+            return Err((
+                *line_index,
+                format!("Error: Invalid label format in line '{}'", line),
+            ));
+        }
+        let (label, statement) = split_label(line);
+        let label: Option<String> = label.map(|label| label.to_string());
 
-                if value_part.to_uppercase().starts_with("DATA") {
-                    let data_str = value_part.trim_start_matches("DATA").trim().to_string();
+        if is_data_statement(statement) {
+            let (padding, bytes, table_labels) =
+                data_bytes(statement, data_pc).map_err(|message| (*line_index, message))?;
+            for _ in 0..padding {
+                data_origins.push(Origin {
+                    line_index: *line_index,
+                    statement: statement.to_string(),
+                    label: None,
+                });
+            }
+            data_pc = data_pc.wrapping_add(padding as u8);
+            data_entries.extend(vec![0; padding]);
 
-                    // Register label pointing to this data address
-                    label_hashmap.insert(variable_name.clone(), data_pc);
-                    data_hashmap.insert(variable_name.clone(), data_pc);
+            // Register label pointing to this data address
+            if let Some(label) = &label {
+                label_hashmap.insert(label.clone(), data_pc);
+                data_hashmap.insert(label.clone(), data_pc);
+            }
 
-                    // Store value
-                    data_entries.extend(data_entry(&data_str));
+            for (offset, table_label) in table_labels.into_iter().enumerate() {
+                table_entries.push(TableEntry {
+                    offset: data_entries.len() + offset,
+                    label: table_label,
+                    line_index: *line_index,
+                });
+            }
+            for _ in &bytes {
+                data_origins.push(Origin {
+                    line_index: *line_index,
+                    statement: statement.to_string(),
+                    label: label.clone(),
+                });
+            }
 
-                    // Move data_pc forward
-                    data_pc += 1; // assuming one byte per data entry; adjust if multi-byte
-                } else {
-                    // Register label pointing to the instruction address
-                    println!(
-                        "Registering label: {} at instruction count: {}",
-                        variable_name,
-                        index * 2
-                    );
-                    label_hashmap.insert(variable_name.clone(), index as u8 * 2);
-                    new_lines.push(value_part.trim().to_string());
-                }
-            } else {
-                panic!("Error: Invalid label format in line '{}'", line);
+            // Move data_pc past every byte laid out
+            data_pc = data_pc.wrapping_add(bytes.len() as u8);
+            data_entries.extend(bytes);
+        } else if statement.is_empty() {
+            // A label on its own line names the next instruction
+            if let Some(label) = label {
+                label_hashmap.insert(label, (new_lines.len() * 2) as u8);
             }
         } else {
-            new_lines.push(line.trim().to_string());
+            // Register label pointing to the instruction address
+            if let Some(label) = &label {
+                label_hashmap.insert(label.clone(), (new_lines.len() * 2) as u8);
+            }
+            line_origins.push(Origin {
+                line_index: *line_index,
+                statement: statement.to_string(),
+                label,
+            });
+            new_lines.push(statement.to_string());
         }
     }
 
-    println!("Size of new lines: {}", new_lines.len());
+    Ok(Layout {
+        labels: label_hashmap,
+        lines: new_lines,
+        data_labels: data_hashmap,
+        data_entries,
+        line_origins,
+        data_origins,
+        table_entries,
+    })
+}
 
-    (label_hashmap, new_lines, data_hashmap, data_entries)
+/// Fills each `.table` byte with its label's address, once labels are final.
+fn resolve_tables(layout: &mut Layout) -> Result<(), (usize, String)> {
+    for entry in &layout.table_entries {
+        let address: u8 = *layout.labels.get(&entry.label).ok_or_else(|| {
+            (
+                entry.line_index,
+                format!("Error: Unknown label '{}' in .table.", entry.label),
+            )
+        })?;
+        layout.data_entries[entry.offset] = address;
+    }
+    Ok(())
 }
 
-fn data_entry(line: &str) -> Vec<u8> {
+fn locate(path: &str, source: &[String], origin: &Origin) -> SourceLocation {
+    let raw: &str = &source[origin.line_index];
+    // Point at the statement itself rather than at its label
+    let search_from: usize = match &origin.label {
+        Some(_) => raw.find(':').map_or(0, |index| index + 1),
+        None => 0,
+    };
+    let column: usize = match raw[search_from..].find(origin.statement.as_str()) {
+        Some(offset) => raw[..search_from + offset].chars().count() + 1,
+        None => 1,
+    };
+    SourceLocation {
+        file: path.to_string(),
+        line: origin.line_index + 1,
+        column,
+        label: origin.label.clone(),
+    }
+}
+
+fn build_source_map(path: &str, source: &[String], layout: &Layout) -> SourceMap {
+    let mut source_map: SourceMap = SourceMap::new();
+    let data_start: usize = layout.lines.len() * 2;
+
+    for (index, origin) in layout.line_origins.iter().enumerate() {
+        let location: SourceLocation = locate(path, source, origin);
+        source_map.insert((index * 2) as u8, location.clone());
+        source_map.insert((index * 2 + 1) as u8, location);
+    }
+    for (index, origin) in layout.data_origins.iter().enumerate() {
+        source_map.insert((data_start + index) as u8, locate(path, source, origin));
+    }
+
+    source_map
+}
+
+/// Runs the peephole pass until it finds nothing more, laying the program out
+/// again after each round so that labels, alignment and the display-pinned
+/// bitmap all follow the shorter code. Returns the layout and what was removed.
+fn optimize_layout(
+    path: &str,
+    source: &[String],
+    mut lines: Vec<(usize, String)>,
+) -> Result<(Layout, Vec<Optimization>), (usize, String)> {
+    let mut optimizations: Vec<Optimization> = Vec::new();
+
+    loop {
+        let layout: Layout = fill_label_address(lines.clone())?;
+        let resolved: Vec<String> =
+            insert_data_labels(&layout.lines, &layout.data_labels, &layout.labels);
+        let removals: Vec<(usize, &'static str)> = optimizer::removals(
+            &layout.lines,
+            &resolved,
+            &layout.labels,
+            layout.data_entries.len(),
+        );
+        if removals.is_empty() {
+            return Ok((layout, optimizations));
+        }
+
+        for (index, reason) in &removals {
+            optimizations.push(Optimization {
+                location: locate(path, source, &layout.line_origins[*index]),
+                statement: layout.line_origins[*index].statement.clone(),
+                reason,
+            });
+        }
+
+        // Instructions are the source lines that are neither data nor bare
+        // labels, in order. A label on a removed one now names what follows.
+        let mut instruction: usize = 0;
+        for (_, line) in lines.iter_mut() {
+            let (label, statement) = split_label(line);
+            if statement.is_empty() || is_data_statement(statement) {
+                continue;
+            }
+            if removals.iter().any(|(removed, _)| *removed == instruction) {
+                *line = label.map(|label| format!("{}:", label)).unwrap_or_default();
+            }
+            instruction += 1;
+        }
+        lines.retain(|(_, line)| !line.is_empty());
+    }
+}
+
+fn data_entry(line: &str) -> Result<Vec<u8>, String> {
     // This function is a placeholder for handling DATA entries.
     // It can be expanded to handle specific logic related to DATA labels.
-    println!("Data entry found: {}", line);
     let trimmed: String = line.trim().to_string();
 
-    println!("Trimmed data entry: '{}'", trimmed);
-
     // Check if the trimmed string is an 8-character binary value (only '0' or '1')
     if trimmed
         .chars()
@@ -116,116 +699,356 @@ fn data_entry(line: &str) -> Vec<u8> {
     {
         // Parse an 8-digit binary (base 2)
 
-        let binding: u8 = u8::from_str_radix(&trimmed, 2).unwrap_or_else(|_| {
-            panic!("Error: Invalid binary data value '{}'", trimmed);
-        });
-        return vec![binding];
+        let binding: u8 = u8::from_str_radix(&trimmed, 2)
+            .map_err(|_| format!("Error: Invalid binary data value '{}'", trimmed))?;
+        return Ok(vec![binding]);
         // return binding;
         // Check if the trimmed string is a 2-character hexadecimal value
-    } else if trimmed.len() == 2 && trimmed.chars().all(|c| c.is_digit(16)) {
+    } else if trimmed.len() == 2 && trimmed.chars().all(|c| c.is_ascii_hexdigit()) {
         // Parse as hexadecimal (base 16)
-        let binding: u8 = u8::from_str_radix(&trimmed, 16).unwrap_or_else(|_| {
-            panic!("Error: Invalid hexadecimal data value '{}'", trimmed);
-        });
-        return vec![binding];
+        let binding: u8 = u8::from_str_radix(&trimmed, 16)
+            .map_err(|_| format!("Error: Invalid hexadecimal data value '{}'", trimmed))?;
+        return Ok(vec![binding]);
         // return binding;
         // Check if the trimmed string is a single decimal digit
-    } else if trimmed.len() == 1 && trimmed.chars().all(|c| c.is_digit(10)) {
+    } else if trimmed.len() == 1 && trimmed.chars().all(|c| c.is_ascii_digit()) {
         // Parse as decimal (base 10)
-        let binding: u8 = trimmed.parse().unwrap_or_else(|_| {
-            panic!("Error: Invalid decimal data value '{}'", trimmed);
-        });
-        return vec![binding];
+        let binding: u8 = trimmed
+            .parse()
+            .map_err(|_| format!("Error: Invalid decimal data value '{}'", trimmed))?;
+        return Ok(vec![binding]);
         // return binding;
     }
-    if trimmed.starts_with("'") && trimmed.ends_with("'") {
+    // A floating-point literal, in the 8-bit format ADDF uses
+    if trimmed.contains('.') && !trimmed.starts_with('\'') {
+        return Ok(vec![float8::parse_literal(&trimmed)?]);
+    }
+    if trimmed.len() >= 2 && trimmed.starts_with("'") && trimmed.ends_with("'") {
         // Return a vector of u8 values representing the ASCII characters
         let mut ascii_values: Vec<u8> = Vec::new();
         for c in trimmed[1..trimmed.len() - 1].chars() {
             ascii_values.push(c as u8);
         }
-        return ascii_values;
+        Ok(ascii_values)
     } else {
-        // If none of the above, panic with an error
-        panic!("Error: Invalid data entry '{}'", line);
+        // If none of the above, report an error
+        Err(format!("Error: Invalid data entry '{}'", line))
     }
 }
 
+fn is_word_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || c == '_'
+}
+
+/// Replaces `name` wherever it appears as a whole word, so that one label is
+/// never rewritten inside a longer one (`x` inside `x_1`) or inside a number.
+pub fn replace_word(line: &str, name: &str, replacement: &str) -> String {
+    let mut replaced: String = String::with_capacity(line.len());
+    let mut rest: &str = line;
+
+    while let Some(index) = rest.find(name) {
+        let before: Option<char> = rest[..index]
+            .chars()
+            .next_back()
+            .or(replaced.chars().next_back());
+        let after: Option<char> = rest[index + name.len()..].chars().next();
+        replaced.push_str(&rest[..index]);
+        if before.is_some_and(is_word_char) || after.is_some_and(is_word_char) {
+            replaced.push_str(name);
+        } else {
+            replaced.push_str(replacement);
+        }
+        rest = &rest[index + name.len()..];
+    }
+    replaced.push_str(rest);
+    replaced
+}
+
 fn insert_data_labels(
-    lines: &Vec<String>,
+    lines: &[String],
     data_hashmap: &HashMap<String, u8>,
     label_addresses: &HashMap<String, u8>,
 ) -> Vec<String> {
-    let variable_names_list: Vec<String> = data_hashmap.keys().cloned().collect();
-
     let mut new_lines: Vec<String> = Vec::new();
 
     for line in lines.iter() {
         let mut new_line: String = line.clone();
 
-        for variable_name in &variable_names_list {
-            if new_line.contains(variable_name) {
-                let value: u8 = *data_hashmap.get(variable_name).unwrap();
-                let value_str: String = format!("{:02X}", value);
-                new_line = new_line.replace(variable_name, &value_str);
+        // DATA labels first, then the rest; both maps agree on DATA addresses
+        for names in [data_hashmap, label_addresses] {
+            for (name, address) in names {
+                new_line = replace_word(&new_line, name, &format!("{:02X}", address));
             }
         }
 
         new_lines.push(new_line);
     }
 
-    let label_names_list: Vec<String> = label_addresses.keys().cloned().collect();
+    new_lines
+}
+
+pub fn assember_cleaning(path: &str) -> Result<CleanedSource, AssemblyError> {
+    match read_initial_data(path) {
+        Ok(contents) => clean_source(path, &contents, false),
+        Err(e) => Err(AssemblyError {
+            location: None,
+            message: format!("Error reading file {}: {}", path, e),
+        }),
+    }
+}
 
-    let mut new_lines_with_labels: Vec<String> = Vec::new();
+/// Cleans source text that has already been read; `path` is only used for the
+/// source map. With `optimize`, the peephole pass runs once labels are laid out.
+pub fn clean_source(
+    path: &str,
+    contents: &str,
+    optimize: bool,
+) -> Result<CleanedSource, AssemblyError> {
+    let lines: Vec<String> = contents.lines().map(|line| line.to_string()).collect();
 
-    for line in new_lines.iter() {
-        let mut new_line: String = line.clone();
+    events::phase("read", || format!("{:?}", lines));
 
-        for label_name in &label_names_list {
-            if new_line.contains(label_name) {
-                let address: u8 = *label_addresses.get(label_name).unwrap();
-                let address_str = format!("{:02X}", address);
+    let removed_comments: Vec<String> = remove_comments(lines.clone());
+    let trimmed_lines: Vec<(usize, String)> = remove_whitespace(removed_comments.clone());
 
-                new_line = new_line.replace(label_name, &address_str);
-            }
-        }
-        new_lines_with_labels.push(new_line);
-    }
+    let line_error = |(line_index, message): (usize, String)| AssemblyError {
+        location: Some(SourceLocation {
+            file: path.to_string(),
+            line: line_index + 1,
+            column: 1,
+            label: None,
+        }),
+        message,
+    };
 
-    return new_lines_with_labels;
-}
+    let bitmap_lines: Vec<(usize, String)> =
+        expand_bitmaps(trimmed_lines, path).map_err(line_error)?;
+    let lowered_lines: Vec<(usize, String)> = lower_directives(bitmap_lines).map_err(line_error)?;
+    events::phase("directives", || format!("{:?}", lowered_lines));
 
-pub fn assember_cleaning() -> (Vec<String>, HashMap<String, u8>, Vec<u8>) {
-    let lines: Vec<String> = match read_initial_data() {
-        Ok(lines) => lines,
-        Err(_e) => {
-            panic!("Error reading file: test1.nha");
-        }
+    let (mut layout, optimizations) = if optimize {
+        optimize_layout(path, &lines, lowered_lines).map_err(line_error)?
+    } else {
+        (
+            fill_label_address(lowered_lines).map_err(line_error)?,
+            Vec::new(),
+        )
     };
 
-    println!("Initial lines: {:?}", lines);
+    events::phase("layout", || {
+        format!(
+            "labels: {:?}, lines: {:?}, data labels: {:?}, data: {:02X?}",
+            layout.labels, layout.lines, layout.data_labels, layout.data_entries
+        )
+    });
 
-    let removed_comments: Vec<String> = remove_comments(lines.clone());
-    let trimmed_lines: Vec<String> = remove_whitespace(removed_comments.clone());
+    resolve_tables(&mut layout).map_err(line_error)?;
+
+    let source_map: SourceMap = build_source_map(path, &lines, &layout);
+
+    let final_lines: Vec<String> =
+        insert_data_labels(&layout.lines, &layout.data_labels, &layout.labels);
+
+    events::phase("resolve_labels", || format!("{:?}", final_lines));
+
+    Ok((
+        final_lines,
+        layout.labels,
+        layout.data_entries,
+        source_map,
+        optimizations,
+    ))
+}
 
-    let label_address: HashMap<String, u8>;
-    let cleaned_lines: Vec<String>;
-    let data_lines: HashMap<String, u8>;
-    let data_entries: Vec<u8>;
+#[cfg(test)]
+mod tests {
+    use super::*;
 
-    (label_address, cleaned_lines, data_lines, data_entries) =
-        fill_label_address(trimmed_lines.clone());
+    #[test]
+    fn test_labels_are_replaced_as_whole_words() {
+        let mut labels: HashMap<String, u8> = HashMap::new();
+        labels.insert("x".to_string(), 0x10);
+        labels.insert("x_1".to_string(), 0x11);
+        labels.insert("a".to_string(), 0x12);
 
-    println!("Label addresses: {:?}", label_address);
-    println!("Cleaned lines: {:?}", cleaned_lines);
-    println!("Data lines: \n{:?}", data_lines);
-    println!("Data entries: {:?}", data_entries);
+        let lines: Vec<String> = vec![
+            "MOV [x_1] -> R1".to_string(),
+            "MOV 0a -> Ra".to_string(),
+            "MOV R1 -> [x]".to_string(),
+        ];
+        assert_eq!(
+            insert_data_labels(&lines, &HashMap::new(), &labels),
+            vec!["MOV [11] -> R1", "MOV 0a -> Ra", "MOV R1 -> [10]"]
+        );
+    }
+
+    #[test]
+    fn test_control_flow_directives() {
+        let source: &str = "        MOV 0 -> R1
+        MOV 0 -> R2
+        MOV 5 -> R3
+        MOV 1 -> R4
+        .WHILE R2 != R3
+        ADDI R2, R4 -> R2
+        ADDI R1, R2 -> R1
+        .ENDW
+        .IF R1 == R3
+        MOV 1 -> R5
+        .ELSE
+        MOV 2 -> R5
+        .ENDIF
+        .if R2==R3
+        MOV 7 -> R6
+        .endif
+        HALT
+";
+        let program = crate::program::Program::assemble_source("test.nha", source).unwrap();
+        let mut emulator = crate::emulator2::Emulator::new(program.bytes);
+        emulator.run().unwrap();
+        assert_eq!(&emulator.registers()[1..7], &[0x0F, 5, 5, 1, 2, 7]);
+    }
 
-    let final_lines: Vec<String> = insert_data_labels(&cleaned_lines, &data_lines, &label_address);
+    #[test]
+    fn test_condition_with_r0_on_the_left() {
+        let source: &str = "        MOV 5 -> R0
+        MOV 7 -> R1
+        MOV 1 -> R3
+        .IF R0 == R1
+        MOV 1 -> R2
+        .ENDIF
+        .WHILE R0 != R1
+        ADDI R0, R3 -> R0
+        .ENDW
+        HALT
+";
+        let program = crate::program::Program::assemble_source("test.nha", source).unwrap();
+        let mut emulator = crate::emulator2::Emulator::new(program.bytes);
+        emulator.run().unwrap();
+        assert_eq!(emulator.registers()[2], 0x00);
+        assert_eq!(emulator.registers()[0], 0x07);
+    }
+
+    #[test]
+    fn test_unbalanced_directives() {
+        let error = clean_source(
+            "test.nha",
+            "  .WHILE R1 != R2
+  HALT
+",
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(error.location.unwrap().line, 1);
+        assert_eq!(error.message, "Error: .WHILE without a matching .ENDW.");
 
-    println!("Final lines after inserting data labels: {:?}", final_lines);
+        let error = clean_source(
+            "test.nha",
+            "  HALT
+  .ELSE
+",
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(error.location.unwrap().line, 2);
+    }
 
-    // std::process::exit(0);
+    #[test]
+    fn test_data_layout_directives() {
+        let source: &str = "        MOV table -> R1
+        MOV 1 -> R2
+        ADDI R1, R2 -> R1
+        MOV [R1] -> R3
+        JMP R3
+first:  MOV AA -> R4
+        HALT
+second: MOV BB -> R4
+        HALT
+msg:    DATA 'Hi'
+buffer: RES 3
+        .align 2
+table:  .table first, second
+ones:   .fill 2, FF
+";
+        let program = crate::program::Program::assemble_source("test.nha", source).unwrap();
+        assert_eq!(program.code_length, 0x12);
+        // Multi-byte DATA moves the next label past all of its bytes
+        assert_eq!(program.labels["buffer"], 0x14);
+        assert_eq!(program.labels["table"], 0x18);
+        assert_eq!(program.labels["ones"], 0x1A);
+        assert_eq!(
+            &program.bytes[0x12..],
+            &[b'H', b'i', 0, 0, 0, 0, 0x0A, 0x0E, 0xFF, 0xFF]
+        );
 
-    (final_lines, label_address, data_entries)
+        let mut emulator = crate::emulator2::Emulator::new(program.bytes);
+        emulator.run().unwrap();
+        assert_eq!(emulator.registers()[4], 0xBB);
+    }
+
+    #[test]
+    fn test_table_with_unknown_label() {
+        let error = clean_source(
+            "test.nha",
+            "  HALT
+t: .table nowhere
+",
+            false,
+        )
+        .unwrap_err();
+        assert_eq!(error.location.unwrap().line, 2);
+        assert_eq!(error.message, "Error: Unknown label 'nowhere' in .table.");
+    }
+
+    #[test]
+    fn test_fill_with_more_than_one_byte() {
+        let error = clean_source("test.nha", "  HALT\n  .fill 4, 'AB'\n", false).unwrap_err();
+        assert_eq!(
+            error.message,
+            "Error: .fill needs a single byte value, got ''AB''."
+        );
+    }
+
+    #[test]
+    fn test_bitmap_directive() {
+        let source: &str = "        MOV [sprite] -> R1
+        HALT
+sprite: .bitmap
+        #.......#
+        .#.......
+        .endbitmap
+dot:    .bitmap .#
+";
+        let program = crate::program::Program::assemble_source("test.nha", source).unwrap();
+        assert_eq!(program.labels["sprite"], 0x04);
+        assert_eq!(program.labels["dot"], 0x08);
+        assert_eq!(&program.bytes[0x04..], &[0x80, 0x80, 0x40, 0x00, 0x40]);
+
+        // A full-screen image from a PBM file lands in the display region
+        let directory: PathBuf = std::env::temp_dir().join("bitmap_directive_test");
+        fs::create_dir_all(&directory).unwrap();
+        let mut pbm: String = String::from("P1\n32 32\n");
+        for y in 0..32 {
+            for x in 0..32 {
+                pbm.push_str(if x == y { "1 " } else { "0 " });
+            }
+            pbm.push('\n');
+        }
+        fs::write(directory.join("diagonal.pbm"), pbm).unwrap();
+        let source_path: PathBuf = directory.join("screen.nha");
+        let source: &str = "        HALT\nscreen: .bitmap \"diagonal.pbm\"\n";
+        assert_eq!(
+            bitmap_files(&source_path.display().to_string(), source),
+            vec![directory.join("diagonal.pbm")]
+        );
+
+        let program =
+            crate::program::Program::assemble_source(&source_path.display().to_string(), source)
+                .unwrap();
+        assert_eq!(program.labels["screen"], 0x80);
+        let mut emulator = crate::emulator2::Emulator::new(program.bytes);
+        emulator.run().unwrap();
+        assert_eq!(display::lit_pixels(emulator.memory()), 32);
+        assert!(display::pixel(emulator.memory(), 31, 31));
+        assert!(!display::pixel(emulator.memory(), 1, 0));
+    }
 }
@@ -0,0 +1,751 @@
+mod parser;
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+
+use crate::assembler_cleaner::replace_word;
+use crate::formatter;
+use parser::{BinaryOp, Comparison, Condition, Expr, Statement, StatementKind, Target};
+
+// Register roles in generated code. R0 is the comparison register; R1 to RD are
+// shared between variables and temporaries; RE reloads spilled values and RF
+// holds constants and jump addresses for a single instruction or two.
+const FIRST_POOL_REGISTER: u8 = 0x1;
+const LAST_POOL_REGISTER: u8 = 0xD;
+const RELOAD_REGISTER: u8 = 0xE;
+const SCRATCH_REGISTER: u8 = 0xF;
+
+/// Pool registers kept back from variables for temporaries. Expressions need
+/// at least two.
+const TEMPORARIES: u8 = 4;
+
+const DISPLAY_START: u8 = 0x80;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Home {
+    Register(u8),
+    Memory,
+    Array,
+}
+
+/// A value in a register. Owned registers are temporaries the holder must
+/// free; the others belong to a variable and must not be written.
+#[derive(Debug, Clone, Copy)]
+struct Operand {
+    register: u8,
+    owned: bool,
+}
+
+enum Item {
+    Label(String),
+    Comment(String),
+    Instruction(String),
+}
+
+struct Generator<'a> {
+    source_lines: Vec<&'a str>,
+    items: Vec<Item>,
+    homes: HashMap<String, Home>,
+    /// Free temporaries, lowest register last so it is used first.
+    free: Vec<u8>,
+    spill_depth: usize,
+    spill_slots: usize,
+    next_label: usize,
+}
+
+/// Adds up how often each scalar variable is used, counting uses inside loops
+/// eight times over per level of nesting.
+fn count_uses(statements: &[Statement], weight: usize, counts: &mut HashMap<String, usize>) {
+    fn expression(expr: &Expr, weight: usize, counts: &mut HashMap<String, usize>) {
+        match expr {
+            Expr::Variable(name) => *counts.entry(name.clone()).or_default() += weight,
+            Expr::Index(_, index) => expression(index, weight, counts),
+            Expr::Binary(_, left, right) => {
+                expression(left, weight, counts);
+                expression(right, weight, counts);
+            }
+            Expr::Number(_) | Expr::Address(_) => {}
+        }
+    }
+    fn condition(condition: &Condition, weight: usize, counts: &mut HashMap<String, usize>) {
+        expression(&condition.left, weight, counts);
+        expression(&condition.right, weight, counts);
+    }
+
+    for statement in statements {
+        match &statement.kind {
+            StatementKind::Var { name, value } => {
+                counts.entry(name.clone()).or_default();
+                if let Some(value) = value {
+                    *counts.entry(name.clone()).or_default() += weight;
+                    expression(value, weight, counts);
+                }
+            }
+            StatementKind::Array { .. } => {}
+            StatementKind::Assign { target, value } => {
+                match target {
+                    Target::Variable(name) => *counts.entry(name.clone()).or_default() += weight,
+                    Target::Index(_, index) => expression(index, weight, counts),
+                }
+                expression(value, weight, counts);
+            }
+            StatementKind::If {
+                condition: test,
+                then,
+                otherwise,
+            } => {
+                condition(test, weight, counts);
+                count_uses(then, weight, counts);
+                count_uses(otherwise, weight, counts);
+            }
+            StatementKind::While {
+                condition: test,
+                body,
+            } => {
+                let inner: usize = weight.saturating_mul(8);
+                condition(test, inner, counts);
+                count_uses(body, inner, counts);
+            }
+            StatementKind::Poke { offset, value } => {
+                expression(offset, weight, counts);
+                expression(value, weight, counts);
+            }
+        }
+    }
+}
+
+/// Finds every declaration, in order, rejecting names declared twice.
+fn declarations(
+    statements: &[Statement],
+    found: &mut Vec<(String, Option<u8>)>,
+) -> Result<(), String> {
+    for statement in statements {
+        let (name, size): (&String, Option<u8>) = match &statement.kind {
+            StatementKind::Var { name, .. } => (name, None),
+            StatementKind::Array { name, size } => (name, Some(*size)),
+            StatementKind::If {
+                then, otherwise, ..
+            } => {
+                declarations(then, found)?;
+                declarations(otherwise, found)?;
+                continue;
+            }
+            StatementKind::While { body, .. } => {
+                declarations(body, found)?;
+                continue;
+            }
+            _ => continue,
+        };
+        if found.iter().any(|(declared, _)| declared == name) {
+            return Err(format!(
+                "Error: line {}: '{}' is already declared.",
+                statement.line, name
+            ));
+        }
+        found.push((name.clone(), size));
+    }
+    Ok(())
+}
+
+impl<'a> Generator<'a> {
+    fn emit(&mut self, instruction: String) {
+        self.items.push(Item::Instruction(instruction));
+    }
+
+    fn new_label(&mut self, kind: &str) -> String {
+        self.next_label += 1;
+        format!("_{}{}", kind, self.next_label)
+    }
+
+    fn allocate(&mut self) -> u8 {
+        // Expressions spill before the pool can run dry
+        self.free.pop().expect("no free temporary register")
+    }
+
+    fn release(&mut self, operand: Operand) {
+        if operand.owned && operand.register != RELOAD_REGISTER {
+            self.free.push(operand.register);
+            self.free.sort_by(|a, b| b.cmp(a));
+        }
+    }
+
+    fn home(&self, name: &str, line: usize) -> Result<Home, String> {
+        self.homes
+            .get(name)
+            .copied()
+            .ok_or_else(|| format!("Error: line {}: '{}' is not declared.", line, name))
+    }
+
+    fn scalar_home(&self, name: &str, line: usize) -> Result<Home, String> {
+        match self.home(name, line)? {
+            Home::Array => Err(format!(
+                "Error: line {}: '{}' is an array and needs an index.",
+                line, name
+            )),
+            home => Ok(home),
+        }
+    }
+
+    fn array(&self, name: &str, line: usize) -> Result<(), String> {
+        match self.home(name, line)? {
+            Home::Array => Ok(()),
+            _ => Err(format!("Error: line {}: '{}' is not an array.", line, name)),
+        }
+    }
+
+    /// Evaluates an expression into some register.
+    fn eval(&mut self, expr: &Expr, line: usize) -> Result<Operand, String> {
+        match expr {
+            Expr::Number(value) => {
+                let register: u8 = self.allocate();
+                self.emit(format!("MOV {:02X} -> R{:X}", value, register));
+                Ok(Operand {
+                    register,
+                    owned: true,
+                })
+            }
+            Expr::Address(name) => {
+                let register: u8 = self.allocate();
+                self.emit(format!("MOV {} -> R{:X}", name, register));
+                Ok(Operand {
+                    register,
+                    owned: true,
+                })
+            }
+            Expr::Variable(name) => match self.scalar_home(name, line)? {
+                Home::Register(register) => Ok(Operand {
+                    register,
+                    owned: false,
+                }),
+                _ => {
+                    let register: u8 = self.allocate();
+                    self.emit(format!("MOV [{}] -> R{:X}", name, register));
+                    Ok(Operand {
+                        register,
+                        owned: true,
+                    })
+                }
+            },
+            Expr::Index(name, index) => {
+                self.array(name, line)?;
+                let address: Operand = self.eval(&element_address(name, index), line)?;
+                self.emit(format!(
+                    "MOV [R{:X}] -> R{:X}",
+                    address.register, address.register
+                ));
+                Ok(address)
+            }
+            Expr::Binary(op, left, right) => {
+                if let (Expr::Number(left), Expr::Number(right)) = (left.as_ref(), right.as_ref()) {
+                    return self.eval(&Expr::Number(fold(*op, *left, *right)), line);
+                }
+                let (left, right) = self.eval_pair(left, right, line)?;
+                Ok(self.combine(*op, left, right))
+            }
+        }
+    }
+
+    /// Evaluates into a temporary the caller may overwrite.
+    fn eval_owned(&mut self, expr: &Expr, line: usize) -> Result<u8, String> {
+        let operand: Operand = self.eval(expr, line)?;
+        if operand.owned {
+            return Ok(operand.register);
+        }
+        let register: u8 = self.allocate();
+        self.emit(format!("MOV R{:X} -> R{:X}", operand.register, register));
+        Ok(register)
+    }
+
+    /// Evaluates two expressions, the first into a writable register. If the
+    /// first uses up the last temporary it is spilled to memory while the
+    /// second is evaluated, then reloaded into a temporary or RE.
+    fn eval_pair(
+        &mut self,
+        left: &Expr,
+        right: &Expr,
+        line: usize,
+    ) -> Result<(u8, Operand), String> {
+        let left: u8 = self.eval_owned(left, line)?;
+
+        let spill: Option<String> = if self.free.is_empty() {
+            let slot: String = format!("_spill{}", self.spill_depth);
+            self.spill_depth += 1;
+            self.spill_slots = self.spill_slots.max(self.spill_depth);
+            self.emit(format!("MOV R{:X} -> [{}]", left, slot));
+            self.release(Operand {
+                register: left,
+                owned: true,
+            });
+            Some(slot)
+        } else {
+            None
+        };
+
+        let right: Operand = self.eval(right, line)?;
+
+        let left: u8 = match spill {
+            Some(slot) => {
+                self.spill_depth -= 1;
+                let register: u8 = self.free.pop().unwrap_or(RELOAD_REGISTER);
+                self.emit(format!("MOV [{}] -> R{:X}", slot, register));
+                register
+            }
+            None => left,
+        };
+        Ok((left, right))
+    }
+
+    /// Applies `op` to two evaluated operands. The left one is overwritten.
+    fn combine(&mut self, op: BinaryOp, left: u8, right: Operand) -> Operand {
+        // A left operand reloaded into RE means no temporary was free, so the
+        // right operand holds one and can take the result
+        let dest: u8 = if left == RELOAD_REGISTER {
+            right.register
+        } else {
+            left
+        };
+
+        let mnemonic: &str = match op {
+            BinaryOp::Add => "ADDI",
+            BinaryOp::And => "AND",
+            BinaryOp::Or => "OR",
+            BinaryOp::Xor => "XOR",
+            BinaryOp::Sub => {
+                // a - b is NOT (NOT a + b)
+                self.emit(format!("MOV FF -> R{:X}", SCRATCH_REGISTER));
+                self.emit(format!(
+                    "XOR R{:X}, R{:X} -> R{:X}",
+                    left, SCRATCH_REGISTER, left
+                ));
+                self.emit(format!(
+                    "ADDI R{:X}, R{:X} -> R{:X}",
+                    left, right.register, left
+                ));
+                self.emit(format!(
+                    "XOR R{:X}, R{:X} -> R{:X}",
+                    left, SCRATCH_REGISTER, dest
+                ));
+                self.finish_combine(right, dest);
+                return Operand {
+                    register: dest,
+                    owned: true,
+                };
+            }
+        };
+        self.emit(format!(
+            "{} R{:X}, R{:X} -> R{:X}",
+            mnemonic, left, right.register, dest
+        ));
+        self.finish_combine(right, dest);
+        Operand {
+            register: dest,
+            owned: true,
+        }
+    }
+
+    fn finish_combine(&mut self, right: Operand, dest: u8) {
+        if right.register != dest {
+            self.release(right);
+        }
+    }
+
+    /// Stores a value at an address computed from `address`.
+    fn store_indirect(&mut self, address: &Expr, value: &Expr, line: usize) -> Result<(), String> {
+        let (address, value) = self.eval_pair(address, value, line)?;
+        self.emit(format!("MOV R{:X} -> [R{:X}]", value.register, address));
+        self.release(value);
+        self.release(Operand {
+            register: address,
+            owned: true,
+        });
+        Ok(())
+    }
+
+    fn assign(&mut self, name: &str, value: &Expr, line: usize) -> Result<(), String> {
+        let home: Home = self.scalar_home(name, line)?;
+        if let (Home::Register(register), Expr::Number(value)) = (home, value) {
+            self.emit(format!("MOV {:02X} -> R{:X}", value, register));
+            return Ok(());
+        }
+        let operand: Operand = self.eval(value, line)?;
+        match home {
+            Home::Register(register) if register != operand.register => {
+                self.emit(format!("MOV R{:X} -> R{:X}", operand.register, register))
+            }
+            Home::Register(_) => {}
+            _ => self.emit(format!("MOV R{:X} -> [{}]", operand.register, name)),
+        }
+        self.release(operand);
+        Ok(())
+    }
+
+    /// Jumps to `label` unless the condition holds.
+    fn jump_unless(
+        &mut self,
+        condition: &Condition,
+        label: &str,
+        line: usize,
+    ) -> Result<(), String> {
+        match &condition.right {
+            Expr::Number(value) => self.emit(format!("MOV {:02X} -> R0", value)),
+            right => {
+                let right: Operand = self.eval(right, line)?;
+                self.emit(format!("MOV R{:X} -> R0", right.register));
+                self.release(right);
+            }
+        }
+
+        let left: Operand = self.eval(&condition.left, line)?;
+        match condition.comparison.negated() {
+            // Equality can jump straight to an address
+            Comparison::Equal => self.emit(format!("JMPEQ {}, R{:X}", label, left.register)),
+            negated => {
+                self.emit(format!("MOV {} -> R{:X}", label, SCRATCH_REGISTER));
+                self.emit(format!(
+                    "{} R{:X}, R{:X}",
+                    negated.mnemonic(),
+                    SCRATCH_REGISTER,
+                    left.register
+                ));
+            }
+        }
+        self.release(left);
+        Ok(())
+    }
+
+    fn statements(&mut self, statements: &[Statement]) -> Result<(), String> {
+        for statement in statements {
+            self.statement(statement)?;
+        }
+        Ok(())
+    }
+
+    fn statement(&mut self, statement: &Statement) -> Result<(), String> {
+        let line: usize = statement.line;
+        let text: &str = self.source_lines.get(line - 1).map_or("", |l| l.trim());
+        self.items.push(Item::Comment(text.to_string()));
+
+        match &statement.kind {
+            StatementKind::Var {
+                name,
+                value: Some(value),
+            } => self.assign(name, value, line)?,
+            StatementKind::Var { value: None, .. } | StatementKind::Array { .. } => {}
+            StatementKind::Assign {
+                target: Target::Variable(name),
+                value,
+            } => self.assign(name, value, line)?,
+            StatementKind::Assign {
+                target: Target::Index(name, index),
+                value,
+            } => {
+                self.array(name, line)?;
+                self.store_indirect(&element_address(name, index), value, line)?;
+            }
+            StatementKind::Poke { offset, value } => {
+                let address: Expr = match offset {
+                    Expr::Number(offset) if *offset >= DISPLAY_START => {
+                        return Err(format!(
+                            "Error: line {}: Display offset {} is past the end of the display.",
+                            line, offset
+                        ))
+                    }
+                    Expr::Number(offset) => Expr::Number(DISPLAY_START + offset),
+                    offset => Expr::Binary(
+                        BinaryOp::Add,
+                        Box::new(Expr::Number(DISPLAY_START)),
+                        Box::new(offset.clone()),
+                    ),
+                };
+                self.store_indirect(&address, value, line)?;
+            }
+            StatementKind::If {
+                condition,
+                then,
+                otherwise,
+            } => {
+                let end: String = self.new_label("endif");
+                if otherwise.is_empty() {
+                    self.jump_unless(condition, &end, line)?;
+                    self.statements(then)?;
+                } else {
+                    let other: String = self.new_label("else");
+                    self.jump_unless(condition, &other, line)?;
+                    self.statements(then)?;
+                    self.emit(format!("JMP {}", end));
+                    self.items.push(Item::Label(other));
+                    self.statements(otherwise)?;
+                }
+                self.items.push(Item::Label(end));
+            }
+            StatementKind::While { condition, body } => {
+                let top: String = self.new_label("while");
+                let end: String = self.new_label("endwhile");
+                self.items.push(Item::Label(top.clone()));
+                self.jump_unless(condition, &end, line)?;
+                self.statements(body)?;
+                self.emit(format!("JMP {}", top));
+                self.items.push(Item::Label(end));
+            }
+        }
+        Ok(())
+    }
+
+    /// Writes the items out as assembly, giving each label the instruction
+    /// that follows it. Labels that land on the same instruction are merged.
+    fn render(&self) -> (Vec<String>, HashMap<String, String>) {
+        let mut lines: Vec<String> = Vec::new();
+        let mut aliases: HashMap<String, String> = HashMap::new();
+        let mut pending: Option<String> = None;
+
+        for item in &self.items {
+            match item {
+                Item::Label(label) => match &pending {
+                    Some(first) => {
+                        aliases.insert(label.clone(), first.clone());
+                    }
+                    None => pending = Some(label.clone()),
+                },
+                Item::Comment(text) => lines.push(format!("// {}", text)),
+                Item::Instruction(text) => match pending.take() {
+                    Some(label) => lines.push(format!("{}: {}", label, text)),
+                    None => lines.push(text.clone()),
+                },
+            }
+        }
+        (lines, aliases)
+    }
+}
+
+fn element_address(name: &str, index: &Expr) -> Expr {
+    match index {
+        Expr::Number(0) => Expr::Address(name.to_string()),
+        index => Expr::Binary(
+            BinaryOp::Add,
+            Box::new(Expr::Address(name.to_string())),
+            Box::new(index.clone()),
+        ),
+    }
+}
+
+fn fold(op: BinaryOp, left: u8, right: u8) -> u8 {
+    match op {
+        BinaryOp::Add => left.wrapping_add(right),
+        BinaryOp::Sub => left.wrapping_sub(right),
+        BinaryOp::And => left & right,
+        BinaryOp::Or => left | right,
+        BinaryOp::Xor => left ^ right,
+    }
+}
+
+/// Where `compile` writes its output: `output` if given, or the source path
+/// with a `.nha` extension. Refuses a path that would overwrite the source.
+pub fn output_path(source_path: &str, output: Option<&str>) -> Result<String, String> {
+    let path: String = match output {
+        Some(output) => output.to_string(),
+        None => Path::new(source_path)
+            .with_extension("nha")
+            .to_string_lossy()
+            .into_owned(),
+    };
+    let same_file: bool = match (fs::canonicalize(source_path), fs::canonicalize(&path)) {
+        (Ok(source), Ok(output)) => source == output,
+        _ => Path::new(source_path) == Path::new(&path),
+    };
+    if same_file {
+        return Err(format!(
+            "Error: The output {} would overwrite the source; name another file with -o.",
+            path
+        ));
+    }
+    Ok(path)
+}
+
+/// Compiles a program in the structured language to `.nha` source.
+pub fn compile(source: &str, source_name: &str) -> Result<String, String> {
+    compile_with_pool(source, source_name, LAST_POOL_REGISTER)
+}
+
+/// Compiles using R1 up to `last_pool_register` for variables and temporaries.
+fn compile_with_pool(
+    source: &str,
+    source_name: &str,
+    last_pool_register: u8,
+) -> Result<String, String> {
+    let statements: Vec<Statement> = parser::parse(source)?;
+
+    let mut declared: Vec<(String, Option<u8>)> = Vec::new();
+    declarations(&statements, &mut declared)?;
+    let mut counts: HashMap<String, usize> = HashMap::new();
+    count_uses(&statements, 1, &mut counts);
+
+    // The busiest variables live in registers, from RD down
+    let variable_registers: usize =
+        (last_pool_register + 1 - FIRST_POOL_REGISTER).saturating_sub(TEMPORARIES) as usize;
+    let mut scalars: Vec<&String> = declared
+        .iter()
+        .filter(|(_, size)| size.is_none())
+        .map(|(name, _)| name)
+        .collect();
+    scalars.sort_by_key(|name| std::cmp::Reverse(counts.get(*name).copied().unwrap_or(0)));
+
+    let mut homes: HashMap<String, Home> = HashMap::new();
+    let mut register: u8 = last_pool_register;
+    for (rank, name) in scalars.iter().enumerate() {
+        let home: Home = if rank < variable_registers {
+            register -= 1;
+            Home::Register(register + 1)
+        } else {
+            Home::Memory
+        };
+        homes.insert(name.to_string(), home);
+    }
+    for (name, size) in &declared {
+        if size.is_some() {
+            homes.insert(name.clone(), Home::Array);
+        }
+    }
+
+    let mut generator: Generator = Generator {
+        source_lines: source.lines().collect(),
+        items: Vec::new(),
+        homes,
+        free: (FIRST_POOL_REGISTER..=register).rev().collect(),
+        spill_depth: 0,
+        spill_slots: 0,
+        next_label: 0,
+    };
+    generator.statements(&statements)?;
+    generator
+        .items
+        .push(Item::Comment("end of program".to_string()));
+    generator.emit("HALT".to_string());
+
+    let (code, aliases) = generator.render();
+
+    let mut output: Vec<String> = vec![format!("// Compiled from {}", source_name)];
+    for (name, size) in &declared {
+        let placement: String = match (generator.homes[name], size) {
+            (Home::Register(register), _) => format!("R{:X}", register),
+            (_, Some(size)) => format!("memory, {} bytes", size),
+            _ => "memory".to_string(),
+        };
+        output.push(format!("//   {}: {}", name, placement));
+    }
+    output.push(String::new());
+
+    for line in code {
+        let mut line: String = line;
+        for (alias, label) in &aliases {
+            line = replace_word(&line, alias, label);
+        }
+        output.push(line);
+    }
+
+    output.push(String::new());
+    for (name, size) in &declared {
+        match (generator.homes[name], size) {
+            (Home::Memory, _) => output.push(format!("{}: DATA 00", name)),
+            (_, Some(size)) => output.push(format!("{}: RES {:02X}", name, size)),
+            _ => {}
+        }
+    }
+    for slot in 0..generator.spill_slots {
+        output.push(format!("_spill{}: DATA 00", slot));
+    }
+
+    Ok(formatter::format_source(&output.join("\n")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator2::Emulator;
+    use crate::program::Program;
+
+    /// Compiles, assembles and runs a program, returning the final memory and
+    /// the assembled program for looking up labels.
+    fn run(source: &str, last_pool_register: u8) -> ([u8; 256], Program) {
+        let assembly: String = compile_with_pool(source, "test.bk", last_pool_register).unwrap();
+        let program: Program = Program::assemble_source("test.nha", &assembly)
+            .unwrap_or_else(|e| panic!("{}\n{}", e, assembly));
+        let mut emulator: Emulator = Emulator::new(program.bytes.clone());
+        emulator.run().unwrap();
+        (*emulator.memory(), program)
+    }
+
+    const PROGRAM: &str = "
+        var total = 0;
+        var i = 0;
+        array squares[5];
+        while i < 5 {
+            squares[i] = i + i;
+            total = total + squares[i];
+            i = i + 1;
+        }
+        var difference = 0;
+        if total >= 20 {
+            difference = total - 30;
+        } else {
+            difference = 1;
+        }
+        poke(2, total ^ 0xFF);
+    ";
+
+    #[test]
+    fn test_loops_arrays_and_branches() {
+        let (memory, program) = run(PROGRAM, LAST_POOL_REGISTER);
+        let squares: usize = program.labels["squares"] as usize;
+        assert_eq!(&memory[squares..squares + 5], &[0, 2, 4, 6, 8]);
+        assert_eq!(memory[0x82], 20 ^ 0xFF);
+    }
+
+    #[test]
+    fn test_spilling_with_two_temporaries() {
+        // Every variable lives in memory and nested expressions need spills
+        let source: &str = "
+            var a = 7;
+            var b = 3;
+            var c = 0;
+            c = (a - b) + ((a & 6) | (b + (a ^ 1)));
+        ";
+        let assembly: String = compile_with_pool(source, "test.bk", 0x2).unwrap();
+        assert!(assembly.contains("_spill0"));
+
+        let (memory, program) = run(source, 0x2);
+        let expected: u8 = (7 - 3) + ((7 & 6) | (3 + (7 ^ 1)));
+        assert_eq!(memory[program.labels["c"] as usize], expected);
+    }
+
+    #[test]
+    fn test_busiest_variable_gets_a_register() {
+        let source: &str =
+            "var once = 1;\nvar often = 0;\nwhile often != 9 { often = often + 1; }\n";
+        let assembly: String = compile_with_pool(source, "test.bk", 0x5).unwrap();
+        assert!(assembly.contains("//   often: R5"));
+        assert!(assembly.contains("//   once: memory"));
+    }
+
+    #[test]
+    fn test_undeclared_variable() {
+        assert_eq!(
+            compile("x = 1;", "test.bk").unwrap_err(),
+            "Error: line 1: 'x' is not declared."
+        );
+    }
+
+    #[test]
+    fn test_output_path() {
+        assert_eq!(output_path("stripes.bk", None).unwrap(), "stripes.nha");
+        assert_eq!(
+            output_path("stripes.bk", Some("out.nha")).unwrap(),
+            "out.nha"
+        );
+        assert_eq!(
+            output_path("./test1.nha", None).unwrap_err(),
+            "Error: The output ./test1.nha would overwrite the source; name another file with -o."
+        );
+        assert!(output_path("test1.nha", Some("./test1.nha")).is_err());
+    }
+}
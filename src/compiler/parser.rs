@@ -0,0 +1,479 @@
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Add,
+    Sub,
+    And,
+    Or,
+    Xor,
+}
+
+/// Comparisons are unsigned, like the machine's conditional jumps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessEqual,
+    GreaterEqual,
+}
+
+impl Comparison {
+    /// The comparison that holds exactly when this one does not.
+    pub fn negated(self) -> Comparison {
+        match self {
+            Comparison::Equal => Comparison::NotEqual,
+            Comparison::NotEqual => Comparison::Equal,
+            Comparison::Less => Comparison::GreaterEqual,
+            Comparison::Greater => Comparison::LessEqual,
+            Comparison::LessEqual => Comparison::Greater,
+            Comparison::GreaterEqual => Comparison::Less,
+        }
+    }
+
+    /// The conditional jump that tests this comparison.
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            Comparison::Equal => "JMPEQ",
+            Comparison::NotEqual => "JMPNE",
+            Comparison::Less => "JMPLT",
+            Comparison::Greater => "JMPGT",
+            Comparison::LessEqual => "JMPLE",
+            Comparison::GreaterEqual => "JMPGE",
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Number(u8),
+    Variable(String),
+    Index(String, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+    /// The address of an array's first element. Never parsed; the compiler
+    /// builds it when lowering indexing.
+    Address(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    pub left: Expr,
+    pub comparison: Comparison,
+    pub right: Expr,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Target {
+    Variable(String),
+    Index(String, Expr),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StatementKind {
+    Var {
+        name: String,
+        value: Option<Expr>,
+    },
+    Array {
+        name: String,
+        size: u8,
+    },
+    Assign {
+        target: Target,
+        value: Expr,
+    },
+    If {
+        condition: Condition,
+        then: Vec<Statement>,
+        otherwise: Vec<Statement>,
+    },
+    While {
+        condition: Condition,
+        body: Vec<Statement>,
+    },
+    /// `poke(offset, value)` writes to display memory at 0x80 + offset.
+    Poke {
+        offset: Expr,
+        value: Expr,
+    },
+}
+
+/// A statement and the 1-based source line it starts on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Statement {
+    pub line: usize,
+    pub kind: StatementKind,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    Identifier(String),
+    Number(u8),
+    Symbol(&'static str),
+}
+
+const SYMBOLS: [&str; 20] = [
+    "==", "!=", "<=", ">=", "=", "<", ">", "+", "-", "&", "|", "^", "(", ")", "[", "]", "{", "}",
+    ";", ",",
+];
+
+const KEYWORDS: [&str; 6] = ["var", "array", "if", "else", "while", "poke"];
+
+/// Keywords, and names the generated assembly spells the same way for
+/// something else: mnemonics, registers and two-digit hex literals.
+fn is_reserved(name: &str) -> bool {
+    let is_upper_hex = |text: &str| {
+        text.chars()
+            .all(|c| c.is_ascii_digit() || ('A'..='F').contains(&c))
+    };
+    let is_register: bool = name.len() == 2 && name.starts_with('R') && is_upper_hex(&name[1..]);
+    let is_hex_byte: bool = name.len() == 2 && is_upper_hex(name);
+
+    KEYWORDS.contains(&name)
+        || crate::assembler2::MNEMONICS.contains(&name)
+        || name == "DATA"
+        || is_register
+        || is_hex_byte
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, String> {
+    let mut tokens: Vec<(usize, Token)> = Vec::new();
+
+    for (line_index, line) in source.lines().enumerate() {
+        let line_number: usize = line_index + 1;
+        let code: &str = line.split("//").next().unwrap_or("");
+        let chars: Vec<char> = code.chars().collect();
+        let mut position: usize = 0;
+
+        while position < chars.len() {
+            let c: char = chars[position];
+            if c.is_whitespace() {
+                position += 1;
+            } else if c.is_ascii_alphabetic() {
+                let start: usize = position;
+                while position < chars.len()
+                    && (chars[position].is_ascii_alphanumeric() || chars[position] == '_')
+                {
+                    position += 1;
+                }
+                let word: String = chars[start..position].iter().collect();
+                tokens.push((line_number, Token::Identifier(word)));
+            } else if c.is_ascii_digit() {
+                let start: usize = position;
+                while position < chars.len() && chars[position].is_ascii_alphanumeric() {
+                    position += 1;
+                }
+                let text: String = chars[start..position].iter().collect();
+                let value: Result<u8, _> = match text.strip_prefix("0x") {
+                    Some(hex) => u8::from_str_radix(hex, 16),
+                    None => text.parse::<u8>(),
+                };
+                let value: u8 = value.map_err(|_| {
+                    format!(
+                        "Error: line {}: '{}' is not a number from 0 to 255.",
+                        line_number, text
+                    )
+                })?;
+                tokens.push((line_number, Token::Number(value)));
+            } else {
+                let rest: String = chars[position..].iter().take(2).collect();
+                let symbol: &'static str = SYMBOLS
+                    .iter()
+                    .find(|symbol| rest.starts_with(**symbol))
+                    .ok_or_else(|| {
+                        format!("Error: line {}: Unexpected character '{}'.", line_number, c)
+                    })?;
+                tokens.push((line_number, Token::Symbol(symbol)));
+                position += symbol.len();
+            }
+        }
+    }
+
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+}
+
+impl Parser {
+    fn line(&self) -> usize {
+        match self.tokens.get(self.position).or(self.tokens.last()) {
+            Some((line, _)) => *line,
+            None => 1,
+        }
+    }
+
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position).map(|(_, token)| token)
+    }
+
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        Err(format!("Error: line {}: {}", self.line(), message))
+    }
+
+    fn at_symbol(&self, symbol: &str) -> bool {
+        matches!(self.peek(), Some(Token::Symbol(s)) if *s == symbol)
+    }
+
+    fn at_keyword(&self, keyword: &str) -> bool {
+        matches!(self.peek(), Some(Token::Identifier(word)) if word == keyword)
+    }
+
+    fn expect_symbol(&mut self, symbol: &str) -> Result<(), String> {
+        if self.at_symbol(symbol) {
+            self.position += 1;
+            Ok(())
+        } else {
+            self.error(&format!("Expected '{}'.", symbol))
+        }
+    }
+
+    fn expect_name(&mut self) -> Result<String, String> {
+        match self.peek() {
+            Some(Token::Identifier(name)) if is_reserved(name) => {
+                let message: String = format!("'{}' cannot be used as a name.", name);
+                self.error(&message)
+            }
+            Some(Token::Identifier(name)) => {
+                let name: String = name.clone();
+                self.position += 1;
+                Ok(name)
+            }
+            _ => self.error("Expected a name."),
+        }
+    }
+
+    fn block(&mut self) -> Result<Vec<Statement>, String> {
+        self.expect_symbol("{")?;
+        let mut statements: Vec<Statement> = Vec::new();
+        while !self.at_symbol("}") {
+            if self.peek().is_none() {
+                return self.error("Expected '}' before the end of the file.");
+            }
+            statements.push(self.statement()?);
+        }
+        self.position += 1;
+        Ok(statements)
+    }
+
+    fn statement(&mut self) -> Result<Statement, String> {
+        let line: usize = self.line();
+        let kind: StatementKind = if self.at_keyword("var") {
+            self.position += 1;
+            let name: String = self.expect_name()?;
+            let value: Option<Expr> = if self.at_symbol("=") {
+                self.position += 1;
+                Some(self.expression()?)
+            } else {
+                None
+            };
+            self.expect_symbol(";")?;
+            StatementKind::Var { name, value }
+        } else if self.at_keyword("array") {
+            self.position += 1;
+            let name: String = self.expect_name()?;
+            self.expect_symbol("[")?;
+            let size: u8 = match self.peek() {
+                Some(Token::Number(size)) if *size > 0 => *size,
+                _ => return self.error("Expected an array size from 1 to 255."),
+            };
+            self.position += 1;
+            self.expect_symbol("]")?;
+            self.expect_symbol(";")?;
+            StatementKind::Array { name, size }
+        } else if self.at_keyword("if") {
+            self.position += 1;
+            let condition: Condition = self.condition()?;
+            let then: Vec<Statement> = self.block()?;
+            let otherwise: Vec<Statement> = if self.at_keyword("else") {
+                self.position += 1;
+                if self.at_keyword("if") {
+                    vec![self.statement()?]
+                } else {
+                    self.block()?
+                }
+            } else {
+                Vec::new()
+            };
+            StatementKind::If {
+                condition,
+                then,
+                otherwise,
+            }
+        } else if self.at_keyword("while") {
+            self.position += 1;
+            let condition: Condition = self.condition()?;
+            let body: Vec<Statement> = self.block()?;
+            StatementKind::While { condition, body }
+        } else if self.at_keyword("poke") {
+            self.position += 1;
+            self.expect_symbol("(")?;
+            let offset: Expr = self.expression()?;
+            self.expect_symbol(",")?;
+            let value: Expr = self.expression()?;
+            self.expect_symbol(")")?;
+            self.expect_symbol(";")?;
+            StatementKind::Poke { offset, value }
+        } else {
+            let name: String = self.expect_name()?;
+            let target: Target = if self.at_symbol("[") {
+                self.position += 1;
+                let index: Expr = self.expression()?;
+                self.expect_symbol("]")?;
+                Target::Index(name, index)
+            } else {
+                Target::Variable(name)
+            };
+            self.expect_symbol("=")?;
+            let value: Expr = self.expression()?;
+            self.expect_symbol(";")?;
+            StatementKind::Assign { target, value }
+        };
+        Ok(Statement { line, kind })
+    }
+
+    fn condition(&mut self) -> Result<Condition, String> {
+        let left: Expr = self.expression()?;
+        let comparison: Comparison = match self.peek() {
+            Some(Token::Symbol("==")) => Comparison::Equal,
+            Some(Token::Symbol("!=")) => Comparison::NotEqual,
+            Some(Token::Symbol("<")) => Comparison::Less,
+            Some(Token::Symbol(">")) => Comparison::Greater,
+            Some(Token::Symbol("<=")) => Comparison::LessEqual,
+            Some(Token::Symbol(">=")) => Comparison::GreaterEqual,
+            _ => return self.error("Expected a comparison (==, !=, <, >, <= or >=)."),
+        };
+        self.position += 1;
+        let right: Expr = self.expression()?;
+        Ok(Condition {
+            left,
+            comparison,
+            right,
+        })
+    }
+
+    /// Precedence from loosest to tightest: `|`, `^`, `&`, then `+` and `-`.
+    fn expression(&mut self) -> Result<Expr, String> {
+        self.binary(0)
+    }
+
+    fn binary(&mut self, level: usize) -> Result<Expr, String> {
+        const LEVELS: [&[(&str, BinaryOp)]; 4] = [
+            &[("|", BinaryOp::Or)],
+            &[("^", BinaryOp::Xor)],
+            &[("&", BinaryOp::And)],
+            &[("+", BinaryOp::Add), ("-", BinaryOp::Sub)],
+        ];
+        if level == LEVELS.len() {
+            return self.primary();
+        }
+
+        let mut left: Expr = self.binary(level + 1)?;
+        while let Some((_, op)) = LEVELS[level]
+            .iter()
+            .find(|(symbol, _)| self.at_symbol(symbol))
+        {
+            self.position += 1;
+            let right: Expr = self.binary(level + 1)?;
+            left = Expr::Binary(*op, Box::new(left), Box::new(right));
+        }
+        Ok(left)
+    }
+
+    fn primary(&mut self) -> Result<Expr, String> {
+        match self.peek() {
+            Some(Token::Number(value)) => {
+                let value: u8 = *value;
+                self.position += 1;
+                Ok(Expr::Number(value))
+            }
+            Some(Token::Symbol("(")) => {
+                self.position += 1;
+                let expression: Expr = self.expression()?;
+                self.expect_symbol(")")?;
+                Ok(expression)
+            }
+            Some(Token::Identifier(_)) => {
+                let name: String = self.expect_name()?;
+                if self.at_symbol("[") {
+                    self.position += 1;
+                    let index: Expr = self.expression()?;
+                    self.expect_symbol("]")?;
+                    Ok(Expr::Index(name, Box::new(index)))
+                } else {
+                    Ok(Expr::Variable(name))
+                }
+            }
+            _ => self.error("Expected a number, a name or '('."),
+        }
+    }
+}
+
+pub fn parse(source: &str) -> Result<Vec<Statement>, String> {
+    let mut parser: Parser = Parser {
+        tokens: tokenize(source)?,
+        position: 0,
+    };
+    let mut statements: Vec<Statement> = Vec::new();
+    while parser.peek().is_some() {
+        statements.push(parser.statement()?);
+    }
+    Ok(statements)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_precedence_and_else_if() {
+        let statements = parse("if x < 3 { y = a | b + 1 & c; } else if x == 4 { }").unwrap();
+        let StatementKind::If {
+            then, otherwise, ..
+        } = &statements[0].kind
+        else {
+            panic!("expected an if statement");
+        };
+
+        let variable = |name: &str| Box::new(Expr::Variable(name.to_string()));
+        let expected = Expr::Binary(
+            BinaryOp::Or,
+            variable("a"),
+            Box::new(Expr::Binary(
+                BinaryOp::And,
+                Box::new(Expr::Binary(
+                    BinaryOp::Add,
+                    variable("b"),
+                    Box::new(Expr::Number(1)),
+                )),
+                variable("c"),
+            )),
+        );
+        assert_eq!(
+            then[0].kind,
+            StatementKind::Assign {
+                target: Target::Variable("y".to_string()),
+                value: expected,
+            }
+        );
+        assert!(matches!(otherwise[0].kind, StatementKind::If { .. }));
+    }
+
+    #[test]
+    fn test_errors_name_the_line() {
+        assert_eq!(
+            parse("var x;\nx = 300;").unwrap_err(),
+            "Error: line 2: '300' is not a number from 0 to 255."
+        );
+        assert_eq!(
+            parse("var R1;").unwrap_err(),
+            "Error: line 1: 'R1' cannot be used as a name."
+        );
+        assert_eq!(
+            parse("while x { }").unwrap_err(),
+            "Error: line 1: Expected a comparison (==, !=, <, >, <= or >=)."
+        );
+    }
+}
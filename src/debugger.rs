@@ -0,0 +1,867 @@
+mod expression;
+
+use std::collections::{BTreeMap, VecDeque};
+use std::io::{self, BufRead, Write};
+
+use crate::display;
+use crate::emulator2::{Emulator, StepRecord, DEFAULT_STEP_LIMIT};
+use crate::instruction::Instruction;
+use crate::program::Program;
+use crate::snapshot::Snapshot;
+use expression::Expression;
+
+const HELP: &str = "\
+break [ADDR]       set a breakpoint, or list them (b)
+break ADDR if EXP  stop at ADDR only when EXP holds
+delete ADDR        remove a breakpoint
+watch ADDR [LEN]   stop when memory is written
+rwatch ADDR [LEN]  stop when memory is read by a load
+watch RN           stop when a register changes
+watch EXP          stop when EXP becomes true, e.g. R1 == 80 && [7F] != 0
+unwatch N          remove a watchpoint
+step [N]           execute N instructions, default 1 (s)
+next               run until the instruction after this one (n)
+continue           run until a breakpoint or the machine stops (c)
+step-back [N]      undo N instructions, default 1 (sb)
+reverse-continue   undo until a breakpoint or watchpoint (rc)
+goto N             go to the state after N instructions
+regs               show the registers and PC
+mem ADDR [LEN]     dump LEN bytes of memory, default 10
+set RN = VALUE     change a register
+poke ADDR VALUE    change a memory cell
+disasm [ADDR]      disassemble around ADDR, default the PC
+display            show the 32x32 display
+save FILE          save the machine state to a snapshot file
+load FILE          restore the machine state from a snapshot file
+quit               leave the debugger (q)
+Addresses are labels or hex; values and lengths are hex.";
+
+/// Instructions shown before the starting address by `disasm`.
+const DISASM_BEFORE: u8 = 3;
+const DISASM_LINES: u8 = 8;
+
+/// How many steps back the debugger can go.
+const HISTORY_LIMIT: usize = 100_000;
+
+/// What sets off a watchpoint.
+enum Watch {
+    Write {
+        start: u8,
+        end: u8,
+    },
+    Read {
+        start: u8,
+        end: u8,
+    },
+    Register(u8),
+    /// Fires when the expression goes from false to true.
+    Condition {
+        expression: Expression,
+        was_true: bool,
+    },
+}
+
+struct Watchpoint {
+    watch: Watch,
+    text: String,
+}
+
+/// An emulator under interactive control, with the program's labels for
+/// naming addresses.
+pub struct Debugger {
+    emulator: Emulator,
+    program: Program,
+    /// Breakpoint addresses, each with an optional condition.
+    breakpoints: BTreeMap<u8, Option<(String, Expression)>>,
+    watchpoints: BTreeMap<usize, Watchpoint>,
+    next_watchpoint: usize,
+    /// The most recent steps, oldest first, for stepping backwards.
+    history: VecDeque<StepRecord>,
+    /// How many instructions one command may run before giving up.
+    step_limit: usize,
+}
+
+impl Debugger {
+    pub fn new(program: Program) -> Self {
+        // The limit applies to each command, not to the whole session
+        let mut emulator: Emulator = Emulator::new(program.bytes.clone());
+        emulator.set_step_limit(None);
+        Debugger {
+            emulator,
+            program,
+            breakpoints: BTreeMap::new(),
+            watchpoints: BTreeMap::new(),
+            next_watchpoint: 0,
+            history: VecDeque::new(),
+            step_limit: DEFAULT_STEP_LIMIT,
+        }
+    }
+
+    /// Runs one command and returns what it prints.
+    pub fn execute(&mut self, line: &str) -> String {
+        let mut words = line.split_whitespace();
+        let command: &str = words.next().unwrap_or("");
+        let rest: Vec<&str> = words.collect();
+
+        let result: Result<String, String> = match command {
+            "break" | "b" => self.break_command(&rest),
+            "delete" => self.delete_command(&rest),
+            "step" | "s" => {
+                Debugger::count(&rest).map(|count| self.run(|_, executed| executed >= count, true))
+            }
+            "next" | "n" => {
+                let target: u8 = self.emulator.program_counter().wrapping_add(2);
+                Ok(self.run(
+                    |debugger, _| debugger.emulator.program_counter() == target,
+                    false,
+                ))
+            }
+            "continue" | "c" => Ok(self.run(|_, _| false, false)),
+            "step-back" | "sb" => {
+                Debugger::count(&rest).map(|count| self.reverse(|_, undone| undone >= count))
+            }
+            "reverse-continue" | "rc" => Ok(self.reverse(|_, _| false)),
+            "goto" => self.goto_command(&rest),
+            "watch" => self.watch_command(&rest, false),
+            "rwatch" => self.watch_command(&rest, true),
+            "unwatch" => self.unwatch_command(&rest),
+            "regs" => Ok(self.registers()),
+            "mem" => self.memory_command(&rest),
+            "set" => self.set_command(&rest.join(" ")),
+            "poke" => self.poke_command(&rest),
+            "disasm" => self.disasm_command(&rest),
+            "display" => Ok(display::render_ascii(self.emulator.memory())),
+            "save" => self.save_command(&rest),
+            "load" => self.load_command(&rest),
+            "help" => Ok(format!("{}\n", HELP)),
+            _ => Err(format!(
+                "Error: Unknown command '{}'. Type 'help' for a list.",
+                command
+            )),
+        };
+        result.unwrap_or_else(|message| format!("{}\n", message))
+    }
+
+    /// A label or a hex address.
+    fn address(&self, text: &str) -> Result<u8, String> {
+        if let Some(address) = self.program.labels.get(text) {
+            return Ok(*address);
+        }
+        u8::from_str_radix(text, 16)
+            .map_err(|_| format!("Error: Unknown label or address '{}'.", text))
+    }
+
+    fn value(text: &str) -> Result<u8, String> {
+        u8::from_str_radix(text, 16).map_err(|_| format!("Error: Invalid hex value '{}'.", text))
+    }
+
+    /// The first label, alphabetically, naming `address`.
+    fn label_at(&self, address: u8) -> Option<&str> {
+        self.program
+            .labels
+            .iter()
+            .filter(|(_, at)| **at == address)
+            .map(|(label, _)| label.as_str())
+            .min()
+    }
+
+    /// An address with its label and source location, when it has them.
+    fn describe_address(&self, address: u8) -> String {
+        let mut text: String = format!("{:02X}", address);
+        if let Some(label) = self.label_at(address) {
+            text.push_str(&format!(" <{}>", label));
+        }
+        if let Some(location) = self.program.source_map.lookup(address) {
+            text.push_str(&format!(" ({}:{})", location.file, location.line));
+        }
+        text
+    }
+
+    fn break_command(&mut self, args: &[&str]) -> Result<String, String> {
+        let Some(text) = args.first() else {
+            if self.breakpoints.is_empty() {
+                return Ok("No breakpoints.\n".to_string());
+            }
+            return Ok(self
+                .breakpoints
+                .iter()
+                .map(|(address, condition)| {
+                    format!(
+                        "Breakpoint at {}{}\n",
+                        self.describe_address(*address),
+                        condition
+                            .as_ref()
+                            .map(|(text, _)| format!(" if {}", text))
+                            .unwrap_or_default()
+                    )
+                })
+                .collect());
+        };
+        let address: u8 = self.address(text)?;
+        let condition: Option<(String, Expression)> = match args.get(1) {
+            Some(&"if") => {
+                let text: String = args[2..].join(" ");
+                let expression: Expression = expression::parse(&text, &self.program.labels)?;
+                Some((text, expression))
+            }
+            Some(word) => {
+                return Err(format!(
+                    "Error: Expected 'if' after the address, found '{}'.",
+                    word
+                ))
+            }
+            None => None,
+        };
+        self.breakpoints.insert(address, condition);
+        Ok(format!(
+            "Breakpoint at {}\n",
+            self.describe_address(address)
+        ))
+    }
+
+    fn delete_command(&mut self, args: &[&str]) -> Result<String, String> {
+        let text: &str = args
+            .first()
+            .ok_or_else(|| "Error: delete needs an address.".to_string())?;
+        let address: u8 = self.address(text)?;
+        if self.breakpoints.remove(&address).is_some() {
+            Ok(format!("Deleted breakpoint at {:02X}\n", address))
+        } else {
+            Err(format!("Error: No breakpoint at {:02X}.", address))
+        }
+    }
+
+    /// A decimal instruction count, 1 when it is left out.
+    fn count(args: &[&str]) -> Result<usize, String> {
+        match args.first() {
+            Some(text) => text
+                .parse()
+                .map_err(|_| format!("Error: Invalid step count '{}'.", text)),
+            None => Ok(1),
+        }
+    }
+
+    /// Runs until `done` holds, a breakpoint or watchpoint fires, or the
+    /// machine stops. At least one instruction runs, so a breakpoint at the PC
+    /// does not stop it. `done` gets the number of instructions run so far.
+    fn run<F>(&mut self, done: F, show_steps: bool) -> String
+    where
+        F: Fn(&Debugger, usize) -> bool,
+    {
+        let mut output: String = String::new();
+        let mut executed: usize = 0;
+        let stop: String = loop {
+            if !self.emulator.is_running() {
+                break format!(
+                    "Stopped after {} instructions without halting.\n",
+                    self.emulator.steps()
+                );
+            }
+            if executed == self.step_limit {
+                break format!("Stopped after {} instructions without halting.\n", executed);
+            }
+            let record: StepRecord = match self.emulator.step() {
+                Ok(record) => record,
+                Err(error) => break format!("{}\n", error),
+            };
+            executed += 1;
+            if show_steps {
+                output.push_str(&format!("{}\n", record));
+            }
+            self.remember(record.clone());
+
+            let pc: u8 = self.emulator.program_counter();
+            // Check every watchpoint, so conditions all see this step
+            if let Some(message) = self.triggered_watchpoint(&record, false) {
+                break format!("{}\nStopped at {}\n", message, self.describe_address(pc));
+            }
+            if self.emulator.is_halted() {
+                break format!("Halted at {}\n", self.describe_address(pc));
+            }
+            if self.at_breakpoint() {
+                break format!("Breakpoint at {}\n", self.describe_address(pc));
+            }
+            if done(self, executed) {
+                break format!("Stopped at {}\n", self.describe_address(pc));
+            }
+        };
+        if !show_steps {
+            output.push_str(&format!("Executed {} instruction(s).\n", executed));
+        }
+        output.push_str(&stop);
+        output
+    }
+
+    fn remember(&mut self, record: StepRecord) {
+        if self.history.len() == HISTORY_LIMIT {
+            self.history.pop_front();
+        }
+        self.history.push_back(record);
+    }
+
+    /// Undoes steps until `done` holds, a breakpoint is reached, or the step
+    /// just undone set off a watchpoint, so that stepping forward repeats it.
+    fn reverse<F>(&mut self, done: F) -> String
+    where
+        F: Fn(&Debugger, usize) -> bool,
+    {
+        let mut undone: usize = 0;
+        let stop: String = loop {
+            let Some(record) = self.history.pop_back() else {
+                break "Reached the start of the recorded history.\n".to_string();
+            };
+            if let Err(message) = self.emulator.undo(&record) {
+                self.history.push_back(record);
+                break format!("{}\n", message);
+            }
+            undone += 1;
+
+            let pc: u8 = self.emulator.program_counter();
+            if let Some(message) = self.triggered_watchpoint(&record, true) {
+                break format!("{}\nStopped at {}\n", message, self.describe_address(pc));
+            }
+            if self.at_breakpoint() {
+                break format!("Breakpoint at {}\n", self.describe_address(pc));
+            }
+            if done(self, undone) {
+                break format!("Stopped at {}\n", self.describe_address(pc));
+            }
+        };
+        format!("Stepped back {} instruction(s).\n{}", undone, stop)
+    }
+
+    /// `goto N`: undo or execute instructions until exactly N have run, without
+    /// stopping for breakpoints or watchpoints.
+    fn goto_command(&mut self, args: &[&str]) -> Result<String, String> {
+        let target: usize = args
+            .first()
+            .and_then(|text| text.parse().ok())
+            .ok_or_else(|| "Error: goto needs a step number.".to_string())?;
+        let earliest: usize = self
+            .history
+            .front()
+            .map_or(self.emulator.steps(), |record| record.step);
+        if target < earliest {
+            return Err(format!(
+                "Error: Step {} is no longer recorded; the earliest is {}.",
+                target, earliest
+            ));
+        }
+
+        while self.emulator.steps() > target {
+            if let Some(record) = self.history.pop_back() {
+                if let Err(message) = self.emulator.undo(&record) {
+                    self.history.push_back(record);
+                    return Err(message);
+                }
+            }
+        }
+        while self.emulator.steps() < target && self.emulator.is_running() {
+            let record: StepRecord = self.emulator.step().map_err(|error| error.to_string())?;
+            self.remember(record);
+        }
+
+        // Conditions should fire on changes from here, not from where we were
+        for watchpoint in self.watchpoints.values_mut() {
+            if let Watch::Condition {
+                expression,
+                was_true,
+            } = &mut watchpoint.watch
+            {
+                *was_true = expression.evaluate(&self.emulator);
+            }
+        }
+        Ok(format!(
+            "At step {}, {}\n",
+            self.emulator.steps(),
+            self.describe_address(self.emulator.program_counter())
+        ))
+    }
+
+    /// Whether the PC is at a breakpoint whose condition, if any, holds.
+    fn at_breakpoint(&self) -> bool {
+        match self.breakpoints.get(&self.emulator.program_counter()) {
+            Some(Some((_, condition))) => condition.evaluate(&self.emulator),
+            Some(None) => true,
+            None => false,
+        }
+    }
+
+    /// Describes the first watchpoint the step set off. Every condition
+    /// watchpoint is re-evaluated, so each only fires when it becomes true.
+    /// With `reverse`, the step has just been undone, so a condition fires when
+    /// the step had made it true.
+    fn triggered_watchpoint(&mut self, record: &StepRecord, reverse: bool) -> Option<String> {
+        let mut message: Option<String> = None;
+        for (number, watchpoint) in self.watchpoints.iter_mut() {
+            let fired: Option<String> = match &mut watchpoint.watch {
+                Watch::Write { start, end } => record
+                    .memory_writes
+                    .iter()
+                    .find(|write| (*start..=*end).contains(&write.address))
+                    .map(|write| {
+                        format!(
+                            "[{:02X}] written {:02X} -> {:02X}",
+                            write.address, write.old, write.new
+                        )
+                    }),
+                Watch::Read { start, end } => record
+                    .memory_reads
+                    .iter()
+                    .find(|address| (*start..=*end).contains(*address))
+                    .map(|address| format!("[{:02X}] read", address)),
+                Watch::Register(register) => record
+                    .register_writes
+                    .iter()
+                    .find(|write| write.register == *register && write.old != write.new)
+                    .map(|write| {
+                        format!(
+                            "R{:X} changed {:02X} -> {:02X}",
+                            write.register, write.old, write.new
+                        )
+                    }),
+                Watch::Condition {
+                    expression,
+                    was_true,
+                } => {
+                    let now: bool = expression.evaluate(&self.emulator);
+                    let (before, after) = if reverse {
+                        (now, *was_true)
+                    } else {
+                        (*was_true, now)
+                    };
+                    *was_true = now;
+                    (after && !before).then(|| "became true".to_string())
+                }
+            };
+            if let (None, Some(fired)) = (&message, fired) {
+                message = Some(format!(
+                    "Watchpoint {} ({}) at {:02X}: {}",
+                    number, watchpoint.text, record.pc, fired
+                ));
+            }
+        }
+        message
+    }
+
+    /// A hex address or label followed by an optional hex length, as an
+    /// inclusive range.
+    fn range(&self, args: &[&str]) -> Result<(u8, u8), String> {
+        let start: u8 = self.address(
+            args.first()
+                .ok_or_else(|| "Error: Expected an address.".to_string())?,
+        )?;
+        let length: u8 = match args.get(1) {
+            Some(text) => Debugger::value(text)?.max(1),
+            None => 1,
+        };
+        Ok((start, start.saturating_add(length - 1)))
+    }
+
+    fn watch_command(&mut self, args: &[&str], read: bool) -> Result<String, String> {
+        if args.is_empty() {
+            if self.watchpoints.is_empty() {
+                return Ok("No watchpoints.\n".to_string());
+            }
+            return Ok(self
+                .watchpoints
+                .iter()
+                .map(|(number, watchpoint)| format!("Watchpoint {}: {}\n", number, watchpoint.text))
+                .collect());
+        }
+
+        let text: String = args.join(" ");
+        let register: Option<u8> = text
+            .strip_prefix(['R', 'r'])
+            .filter(|digit| digit.len() == 1)
+            .and_then(|digit| u8::from_str_radix(digit, 16).ok());
+        let watch: Watch = if read {
+            let (start, end) = self.range(args)?;
+            Watch::Read { start, end }
+        } else if let Some(register) = register {
+            Watch::Register(register)
+        } else if expression::is_expression(&text) {
+            let expression: Expression = expression::parse(&text, &self.program.labels)?;
+            Watch::Condition {
+                was_true: expression.evaluate(&self.emulator),
+                expression,
+            }
+        } else {
+            let (start, end) = self.range(args)?;
+            Watch::Write { start, end }
+        };
+
+        let described: String = match &watch {
+            Watch::Write { start, end } | Watch::Read { start, end } => {
+                let access: &str = if read { "read" } else { "write" };
+                if start == end {
+                    format!("{} [{:02X}]", access, start)
+                } else {
+                    format!("{} [{:02X}..{:02X}]", access, start, end)
+                }
+            }
+            Watch::Register(register) => format!("change R{:X}", register),
+            Watch::Condition { .. } => text,
+        };
+        self.next_watchpoint += 1;
+        self.watchpoints.insert(
+            self.next_watchpoint,
+            Watchpoint {
+                watch,
+                text: described.clone(),
+            },
+        );
+        Ok(format!(
+            "Watchpoint {}: {}\n",
+            self.next_watchpoint, described
+        ))
+    }
+
+    fn unwatch_command(&mut self, args: &[&str]) -> Result<String, String> {
+        let number: usize = args
+            .first()
+            .and_then(|text| text.parse().ok())
+            .ok_or_else(|| "Error: unwatch needs a watchpoint number.".to_string())?;
+        match self.watchpoints.remove(&number) {
+            Some(_) => Ok(format!("Deleted watchpoint {}\n", number)),
+            None => Err(format!("Error: No watchpoint {}.", number)),
+        }
+    }
+
+    fn registers(&self) -> String {
+        let registers: &[u8; 16] = self.emulator.registers();
+        let mut text: String = String::new();
+        for (row, values) in registers.chunks(8).enumerate() {
+            let cells: Vec<String> = values
+                .iter()
+                .enumerate()
+                .map(|(column, value)| format!("R{:X}={:02X}", row * 8 + column, value))
+                .collect();
+            text.push_str(&cells.join(" "));
+            text.push('\n');
+        }
+        text.push_str(&format!(
+            "PC={:02X} after {} instruction(s)\n",
+            self.emulator.program_counter(),
+            self.emulator.steps()
+        ));
+        text
+    }
+
+    fn memory_command(&self, args: &[&str]) -> Result<String, String> {
+        let start: u8 = self.address(
+            args.first()
+                .ok_or_else(|| "Error: mem needs an address.".to_string())?,
+        )?;
+        let length: usize = match args.get(1) {
+            Some(text) => Debugger::value(text)? as usize,
+            None => 0x10,
+        };
+        let memory: &[u8; 256] = self.emulator.memory();
+        let mut text: String = String::new();
+        for row in (0..length).step_by(8) {
+            let address: u8 = start.wrapping_add(row as u8);
+            text.push_str(&format!("{:02X}:", address));
+            for offset in row..length.min(row + 8) {
+                text.push_str(&format!(
+                    " {:02X}",
+                    memory[start.wrapping_add(offset as u8) as usize]
+                ));
+            }
+            text.push('\n');
+        }
+        Ok(text)
+    }
+
+    /// `set RN = VALUE`, with or without spaces around `=`.
+    fn set_command(&mut self, args: &str) -> Result<String, String> {
+        let (register, value) = args
+            .split_once('=')
+            .ok_or_else(|| "Error: Expected 'set RN = VALUE'.".to_string())?;
+        let register: &str = register.trim();
+        let number: u8 = register
+            .strip_prefix(['R', 'r'])
+            .filter(|digit| digit.len() == 1)
+            .and_then(|digit| u8::from_str_radix(digit, 16).ok())
+            .ok_or_else(|| format!("Error: Invalid register '{}'.", register))?;
+        let value: u8 = Debugger::value(value.trim())?;
+        self.emulator.set_register(number, value);
+        Ok(format!("R{:X}={:02X}\n", number, value))
+    }
+
+    fn poke_command(&mut self, args: &[&str]) -> Result<String, String> {
+        let [address, value] = args else {
+            return Err("Error: Expected 'poke ADDR VALUE'.".to_string());
+        };
+        let address: u8 = self.address(address)?;
+        let value: u8 = Debugger::value(value)?;
+        self.emulator.set_memory(address, value);
+        Ok(format!("[{:02X}]={:02X}\n", address, value))
+    }
+
+    fn save_command(&self, args: &[&str]) -> Result<String, String> {
+        let [path] = args else {
+            return Err("Error: Expected 'save FILE'.".to_string());
+        };
+        self.emulator.snapshot().save(path)?;
+        Ok(format!("Saved the machine state to {}\n", path))
+    }
+
+    /// Restores a snapshot. The undo history belongs to the old state, so it
+    /// is dropped.
+    fn load_command(&mut self, args: &[&str]) -> Result<String, String> {
+        let [path] = args else {
+            return Err("Error: Expected 'load FILE'.".to_string());
+        };
+        let snapshot: Snapshot = Snapshot::load(path)?;
+        self.emulator.restore(&snapshot)?;
+        self.history.clear();
+        Ok(format!(
+            "Loaded {} at PC={:02X} after {} instruction(s)\n",
+            path, snapshot.pc, snapshot.steps
+        ))
+    }
+
+    fn disasm_command(&self, args: &[&str]) -> Result<String, String> {
+        let pc: u8 = self.emulator.program_counter();
+        let (start, centre) = match args.first() {
+            Some(text) => (self.address(text)?, None),
+            None => (pc.saturating_sub(DISASM_BEFORE * 2), Some(pc)),
+        };
+        let memory: &[u8; 256] = self.emulator.memory();
+        let mut text: String = String::new();
+        for index in 0..DISASM_LINES {
+            let address: u8 = start.wrapping_add(index * 2);
+            if index > 0 && address < start {
+                break;
+            }
+            if let Some(label) = self.label_at(address) {
+                text.push_str(&format!("{}:\n", label));
+            }
+            let word: u16 = u16::from_be_bytes([
+                memory[address as usize],
+                memory[address.wrapping_add(1) as usize],
+            ]);
+            let instruction: String = Instruction::decode(word)
+                .map(|instruction| instruction.to_string())
+                .unwrap_or_else(|| "?".to_string());
+            text.push_str(&format!(
+                "{}{} {:02X}: {:04X}  {}\n",
+                if centre == Some(address) { "=>" } else { "  " },
+                if self.breakpoints.contains_key(&address) {
+                    "*"
+                } else {
+                    " "
+                },
+                address,
+                word,
+                instruction
+            ));
+        }
+        Ok(text)
+    }
+}
+
+/// Reads debugger commands from stdin until `quit` or end of input. An empty
+/// line repeats the previous command.
+pub fn repl(program: Program) -> io::Result<()> {
+    let mut debugger: Debugger = Debugger::new(program);
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    let mut previous: String = String::new();
+
+    println!("Type 'help' for a list of commands.");
+    print!("{}", debugger.execute("disasm"));
+    loop {
+        print!("(debug) ");
+        stdout.flush()?;
+        let mut line: String = String::new();
+        if stdin.lock().read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        let line: String = match line.trim() {
+            "" => previous.clone(),
+            line => line.to_string(),
+        };
+        if matches!(line.as_str(), "quit" | "q" | "exit") {
+            return Ok(());
+        }
+        print!("{}", debugger.execute(&line));
+        previous = line;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chessboard() -> Debugger {
+        let source: String = std::fs::read_to_string("./test1.nha").unwrap();
+        Debugger::new(Program::assemble_source("test1.nha", &source).unwrap())
+    }
+
+    #[test]
+    fn test_breakpoints_and_stepping() {
+        let mut debugger: Debugger = chessboard();
+        assert_eq!(
+            debugger.execute("break oddrow"),
+            "Breakpoint at 18 <oddrow> (test1.nha:30)\n"
+        );
+
+        let output: String = debugger.execute("continue");
+        assert!(output.ends_with("Breakpoint at 18 <oddrow> (test1.nha:30)\n"));
+        assert_eq!(debugger.emulator.registers()[1], 0x90);
+
+        let output: String = debugger.execute("step");
+        assert!(output.starts_with("18: E041  MOV R4 -> [R1]"));
+        assert!(output.ends_with("Stopped at 1A <endloop> (test1.nha:32)\n"));
+
+        assert_eq!(
+            debugger.execute("delete oddrow"),
+            "Deleted breakpoint at 18\n"
+        );
+        assert!(debugger.execute("c").starts_with("Executed"));
+        assert!(debugger.emulator.is_halted());
+        assert_eq!(
+            display::lit_pixels(debugger.emulator.memory()),
+            512,
+            "{}",
+            debugger.execute("display")
+        );
+    }
+
+    #[test]
+    fn test_step_limit_applies_to_each_command() {
+        let program: Program = Program::assemble_source("test.nha", "loop: JMP loop\n").unwrap();
+        let mut debugger: Debugger = Debugger::new(program);
+        debugger.step_limit = 5;
+        for _ in 0..2 {
+            assert_eq!(
+                debugger.execute("continue"),
+                "Executed 5 instruction(s).\nStopped after 5 instructions without halting.\n"
+            );
+        }
+        assert_eq!(debugger.emulator.steps(), 10);
+    }
+
+    #[test]
+    fn test_inspecting_and_changing_state() {
+        let mut debugger: Debugger = chessboard();
+        assert_eq!(debugger.execute("set R3 = 1F"), "R3=1F\n");
+        assert_eq!(debugger.execute("poke 80 aa"), "[80]=AA\n");
+        assert!(debugger
+            .execute("regs")
+            .starts_with("R0=00 R1=00 R2=00 R3=1F"));
+        assert_eq!(debugger.execute("mem 7E 4"), "7E: 00 00 AA 00\n");
+
+        let listing: String = debugger.execute("disasm");
+        assert!(listing.starts_with("=>  00: 1122  MOV [22] -> R1\n"));
+        assert!(listing.contains("startloop:\n    08: 5112  ADDI R1, R2 -> R1\n"));
+
+        assert_eq!(
+            debugger.execute("break nowhere"),
+            "Error: Unknown label or address 'nowhere'.\n"
+        );
+    }
+
+    #[test]
+    fn test_watchpoints_and_conditions() {
+        let mut debugger: Debugger = chessboard();
+        assert_eq!(
+            debugger.execute("watch 80 4"),
+            "Watchpoint 1: write [80..83]\n"
+        );
+        assert_eq!(
+            debugger.execute("rwatch endmem"),
+            "Watchpoint 2: read [23]\n"
+        );
+
+        let output: String = debugger.execute("c");
+        assert!(output.contains("Watchpoint 1 (write [80..83]) at 14: [80] written 00 -> 0F\n"));
+        assert!(output.ends_with("Stopped at 16 (test1.nha:29)\n"));
+        assert_eq!(debugger.execute("unwatch 1"), "Deleted watchpoint 1\n");
+
+        let output: String = debugger.execute("c");
+        assert!(output.contains("Watchpoint 2 (read [23]) at 1A: [23] read\n"));
+        assert_eq!(debugger.execute("unwatch 2"), "Deleted watchpoint 2\n");
+
+        debugger.execute("watch R1 == 84 && [83] != 0");
+        let output: String = debugger.execute("c");
+        assert!(output.contains("(R1 == 84 && [83] != 0) at 08: became true"));
+        assert_eq!(debugger.emulator.registers()[1], 0x84);
+        debugger.execute("unwatch 3");
+
+        debugger.execute("watch RA");
+        assert!(debugger.execute("c").contains("RA changed 00 -> 84"));
+        debugger.execute("unwatch 4");
+
+        debugger.execute("break startloop if R1 == 90");
+        assert!(debugger
+            .execute("c")
+            .ends_with("Breakpoint at 08 <startloop> (test1.nha:22)\n"));
+        assert_eq!(debugger.emulator.registers()[1], 0x90);
+    }
+
+    #[test]
+    fn test_stepping_backwards() {
+        let mut debugger: Debugger = chessboard();
+        debugger.execute("watch 80 4");
+        debugger.execute("c");
+        debugger.execute("unwatch 1");
+        assert_eq!(debugger.emulator.memory()[0x80], 0x0F);
+        let step: usize = debugger.emulator.steps();
+
+        // Back over the store, to just before it
+        let output: String = debugger.execute("sb");
+        assert_eq!(
+            output,
+            "Stepped back 1 instruction(s).\nStopped at 14 (test1.nha:28)\n"
+        );
+        assert_eq!(debugger.emulator.memory()[0x80], 0x00);
+
+        // Run on, then go back to the store that wrote the wrong byte
+        debugger.execute("c");
+        assert!(debugger.emulator.is_halted());
+        debugger.execute("watch 80");
+        let output: String = debugger.execute("rc");
+        assert!(output.contains("[80] written 00 -> 0F"));
+        assert_eq!(debugger.emulator.steps(), step - 1);
+        assert_eq!(debugger.emulator.registers()[1], 0x80);
+
+        assert_eq!(debugger.execute("goto 3"), "At step 3, 06 (test1.nha:20)\n");
+        assert_eq!(debugger.emulator.registers()[4], 0x00);
+        debugger.execute("goto 4");
+        assert_eq!(debugger.emulator.registers()[4], 0xF0);
+        assert_eq!(
+            debugger.execute("sb 10"),
+            "Stepped back 4 instruction(s).\nReached the start of the recorded history.\n"
+        );
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path: String = std::env::temp_dir()
+            .join("debugger_snapshot_test.txt")
+            .to_string_lossy()
+            .into_owned();
+        let mut debugger: Debugger = chessboard();
+        debugger.execute("s 5");
+        assert_eq!(
+            debugger.execute(&format!("save {}", path)),
+            format!("Saved the machine state to {}\n", path)
+        );
+        let registers: [u8; 16] = *debugger.emulator.registers();
+
+        debugger.execute("c");
+        assert_eq!(
+            debugger.execute(&format!("load {}", path)),
+            format!("Loaded {} at PC=0A after 5 instruction(s)\n", path)
+        );
+        assert_eq!(*debugger.emulator.registers(), registers);
+        assert!(!debugger.emulator.is_halted());
+        assert_eq!(
+            debugger.execute("sb"),
+            "Stepped back 0 instruction(s).\nReached the start of the recorded history.\n"
+        );
+    }
+}
@@ -0,0 +1,251 @@
+use std::collections::HashMap;
+
+use crate::emulator2::Emulator;
+
+/// A condition over the machine state, such as `R1 == 80 && [7F] != 0`.
+/// Numbers are hex, `[ADDR]` reads memory, and comparisons are unsigned.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expression {
+    Or(Box<Expression>, Box<Expression>),
+    And(Box<Expression>, Box<Expression>),
+    Compare(Operand, Comparison, Operand),
+    /// An operand on its own is true when it is not zero.
+    NonZero(Operand),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operand {
+    Register(u8),
+    Memory(u8),
+    ProgramCounter,
+    Value(u8),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Comparison {
+    Equal,
+    NotEqual,
+    Less,
+    Greater,
+    LessOrEqual,
+    GreaterOrEqual,
+}
+
+impl Operand {
+    fn value(self, emulator: &Emulator) -> u8 {
+        match self {
+            Operand::Register(register) => emulator.registers()[register as usize],
+            Operand::Memory(address) => emulator.memory()[address as usize],
+            Operand::ProgramCounter => emulator.program_counter(),
+            Operand::Value(value) => value,
+        }
+    }
+}
+
+impl Expression {
+    pub fn evaluate(&self, emulator: &Emulator) -> bool {
+        match self {
+            Expression::Or(left, right) => left.evaluate(emulator) || right.evaluate(emulator),
+            Expression::And(left, right) => left.evaluate(emulator) && right.evaluate(emulator),
+            Expression::NonZero(operand) => operand.value(emulator) != 0,
+            Expression::Compare(left, comparison, right) => {
+                let (left, right) = (left.value(emulator), right.value(emulator));
+                match comparison {
+                    Comparison::Equal => left == right,
+                    Comparison::NotEqual => left != right,
+                    Comparison::Less => left < right,
+                    Comparison::Greater => left > right,
+                    Comparison::LessOrEqual => left <= right,
+                    Comparison::GreaterOrEqual => left >= right,
+                }
+            }
+        }
+    }
+}
+
+/// Splits an expression into operators, brackets and words.
+fn tokenize(text: &str) -> Vec<String> {
+    let mut tokens: Vec<String> = Vec::new();
+    let chars: Vec<char> = text.chars().collect();
+    let mut index: usize = 0;
+    while index < chars.len() {
+        let c: char = chars[index];
+        let pair: String = chars[index..chars.len().min(index + 2)].iter().collect();
+        if c.is_whitespace() {
+            index += 1;
+        } else if ["==", "!=", "<=", ">=", "&&", "||"].contains(&pair.as_str()) {
+            tokens.push(pair);
+            index += 2;
+        } else if "()[]<>".contains(c) {
+            tokens.push(c.to_string());
+            index += 1;
+        } else {
+            let start: usize = index;
+            while index < chars.len()
+                && (chars[index].is_ascii_alphanumeric() || chars[index] == '_')
+            {
+                index += 1;
+            }
+            if index == start {
+                // Anything else stands alone, for the parser to reject
+                index += 1;
+            }
+            tokens.push(chars[start..index].iter().collect());
+        }
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: Vec<String>,
+    position: usize,
+    labels: &'a HashMap<String, u8>,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<&str> {
+        self.tokens.get(self.position).map(|token| token.as_str())
+    }
+
+    fn next(&mut self) -> Result<String, String> {
+        let token: Option<String> = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token.ok_or_else(|| "Error: The expression ends too early.".to_string())
+    }
+
+    fn expect(&mut self, expected: &str) -> Result<(), String> {
+        match self.next()? {
+            token if token == expected => Ok(()),
+            token => Err(format!(
+                "Error: Expected '{}' in the expression, found '{}'.",
+                expected, token
+            )),
+        }
+    }
+
+    fn or(&mut self) -> Result<Expression, String> {
+        let mut expression: Expression = self.and()?;
+        while self.peek() == Some("||") {
+            self.position += 1;
+            expression = Expression::Or(Box::new(expression), Box::new(self.and()?));
+        }
+        Ok(expression)
+    }
+
+    fn and(&mut self) -> Result<Expression, String> {
+        let mut expression: Expression = self.comparison()?;
+        while self.peek() == Some("&&") {
+            self.position += 1;
+            expression = Expression::And(Box::new(expression), Box::new(self.comparison()?));
+        }
+        Ok(expression)
+    }
+
+    fn comparison(&mut self) -> Result<Expression, String> {
+        if self.peek() == Some("(") {
+            self.position += 1;
+            let expression: Expression = self.or()?;
+            self.expect(")")?;
+            return Ok(expression);
+        }
+
+        let left: Operand = self.operand()?;
+        let comparison: Comparison = match self.peek() {
+            Some("==") => Comparison::Equal,
+            Some("!=") => Comparison::NotEqual,
+            Some("<") => Comparison::Less,
+            Some(">") => Comparison::Greater,
+            Some("<=") => Comparison::LessOrEqual,
+            Some(">=") => Comparison::GreaterOrEqual,
+            _ => return Ok(Expression::NonZero(left)),
+        };
+        self.position += 1;
+        Ok(Expression::Compare(left, comparison, self.operand()?))
+    }
+
+    /// A label or hex number.
+    fn number(&self, token: &str) -> Result<u8, String> {
+        if let Some(address) = self.labels.get(token) {
+            return Ok(*address);
+        }
+        u8::from_str_radix(token, 16).map_err(|_| {
+            format!(
+                "Error: Unknown label or value '{}' in the expression.",
+                token
+            )
+        })
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        let token: String = self.next()?;
+        if token == "[" {
+            let token: String = self.next()?;
+            let address: u8 = self.number(&token)?;
+            self.expect("]")?;
+            return Ok(Operand::Memory(address));
+        }
+        if token.eq_ignore_ascii_case("PC") {
+            return Ok(Operand::ProgramCounter);
+        }
+        let register: Option<u8> = token
+            .strip_prefix(['R', 'r'])
+            .filter(|digit| digit.len() == 1)
+            .and_then(|digit| u8::from_str_radix(digit, 16).ok());
+        match register {
+            Some(register) => Ok(Operand::Register(register)),
+            None => self.number(&token).map(Operand::Value),
+        }
+    }
+}
+
+/// Parses an expression; `labels` may stand in for numbers and addresses.
+pub fn parse(text: &str, labels: &HashMap<String, u8>) -> Result<Expression, String> {
+    let mut parser: Parser = Parser {
+        tokens: tokenize(text),
+        position: 0,
+        labels,
+    };
+    let expression: Expression = parser.or()?;
+    match parser.peek() {
+        None => Ok(expression),
+        Some(token) => Err(format!("Error: Unexpected '{}' in the expression.", token)),
+    }
+}
+
+/// Whether `text` is an expression rather than a plain address or register.
+pub fn is_expression(text: &str) -> bool {
+    ["==", "!=", "<", ">", "&&", "||", "("]
+        .iter()
+        .any(|operator| text.contains(operator))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_evaluate() {
+        let mut labels: HashMap<String, u8> = HashMap::new();
+        labels.insert("flag".to_string(), 0x7F);
+        let expression: Expression = parse("R1 == 80 && [flag] != 0", &labels).unwrap();
+
+        let mut emulator: Emulator = Emulator::new(Vec::new());
+        assert!(!expression.evaluate(&emulator));
+        emulator.set_register(1, 0x80);
+        assert!(!expression.evaluate(&emulator));
+        emulator.set_memory(0x7F, 0x01);
+        assert!(expression.evaluate(&emulator));
+
+        let grouped: Expression = parse("(R2 >= 10 || PC < 4) && r1", &labels).unwrap();
+        assert!(grouped.evaluate(&emulator));
+
+        assert_eq!(
+            parse("R1 == ", &labels).unwrap_err(),
+            "Error: The expression ends too early."
+        );
+        assert_eq!(
+            parse("R1 = 2", &labels).unwrap_err(),
+            "Error: Unexpected '=' in the expression."
+        );
+    }
+}
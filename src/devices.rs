@@ -0,0 +1,509 @@
+use std::collections::VecDeque;
+use std::io::{self, BufRead, Read, Write};
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+
+use crate::emulator2::Device;
+
+/// Counts instructions. A load gives the count so far, wrapping at 256, and a
+/// store sets it, so a program can time itself or wait a number of steps.
+#[derive(Default)]
+pub struct Timer {
+    count: u8,
+}
+
+impl Device for Timer {
+    fn read(&mut self, _offset: u8) -> u8 {
+        self.count
+    }
+
+    fn write(&mut self, _offset: u8, value: u8) {
+        self.count = value;
+    }
+
+    fn tick(&mut self) {
+        self.count = self.count.wrapping_add(1);
+    }
+
+    fn save_state(&self) -> String {
+        format!("{:02X}", self.count)
+    }
+
+    fn load_state(&mut self, state: &str) -> Result<(), String> {
+        self.count = u8::from_str_radix(state, 16)
+            .map_err(|_| format!("Error: Invalid timer state '{}'.", state))?;
+        Ok(())
+    }
+}
+
+/// Console port offsets from the device's address.
+const CONSOLE_OUTPUT: u8 = 0;
+const CONSOLE_STATUS: u8 = 1;
+const CONSOLE_INPUT: u8 = 2;
+
+/// Where a console's input comes from.
+enum Input {
+    /// Scripted input, read a line at a time when the program asks for it.
+    Reader(Box<dyn BufRead>),
+    /// Bytes from a thread reading stdin, so polling never waits for the user.
+    Channel(Receiver<u8>),
+}
+
+/// Text in and out through three ports: storing a byte to the output port
+/// prints it as ASCII, the status port reads 1 while input is waiting, and
+/// loading from the input port takes the next input byte, or 0 if there is
+/// none.
+pub struct Console {
+    /// `None` once the input has run out.
+    input: Option<Input>,
+    /// Input read but not yet loaded by the program.
+    pending: VecDeque<u8>,
+    /// Bytes taken from `input` so far, pending ones included.
+    consumed: usize,
+    output: Box<dyn Write>,
+}
+
+impl Console {
+    pub fn new(input: Box<dyn BufRead>, output: Box<dyn Write>) -> Self {
+        Console {
+            input: Some(Input::Reader(input)),
+            pending: VecDeque::new(),
+            consumed: 0,
+            output,
+        }
+    }
+
+    /// A console whose input is read on a background thread. Until a byte
+    /// arrives the status port reads 0, so a program can poll the terminal.
+    pub fn background(mut input: impl Read + Send + 'static, output: Box<dyn Write>) -> Self {
+        let (sender, receiver) = mpsc::channel::<u8>();
+        thread::spawn(move || {
+            let mut buffer: [u8; 256] = [0; 256];
+            // Stops at the end of the input, or once the console is dropped
+            while let Ok(count @ 1..) = input.read(&mut buffer) {
+                if buffer[..count]
+                    .iter()
+                    .any(|byte| sender.send(*byte).is_err())
+                {
+                    break;
+                }
+            }
+        });
+        Console {
+            input: Some(Input::Channel(receiver)),
+            pending: VecDeque::new(),
+            consumed: 0,
+            output,
+        }
+    }
+
+    /// Whether a byte is waiting. Scripted input is read a line at a time
+    /// when none is; background input is only checked, never waited for.
+    fn has_input(&mut self) -> bool {
+        while self.pending.is_empty() {
+            match &mut self.input {
+                None => return false,
+                Some(Input::Reader(reader)) => {
+                    let mut line: Vec<u8> = Vec::new();
+                    match reader.read_until(b'\n', &mut line) {
+                        Ok(0) | Err(_) => self.input = None,
+                        Ok(count) => {
+                            self.consumed += count;
+                            self.pending.extend(line);
+                        }
+                    }
+                }
+                Some(Input::Channel(receiver)) => match receiver.try_recv() {
+                    Ok(byte) => {
+                        self.consumed += 1;
+                        self.pending.push_back(byte);
+                    }
+                    Err(TryRecvError::Empty) => return false,
+                    Err(TryRecvError::Disconnected) => self.input = None,
+                },
+            }
+        }
+        true
+    }
+
+    /// Reads and throws away `count` bytes of input, returning how many there
+    /// were. Background input is waited for here.
+    fn skip(&mut self, count: usize) -> Result<usize, String> {
+        match &mut self.input {
+            None => Ok(0),
+            Some(Input::Reader(reader)) => {
+                let skipped: u64 =
+                    io::copy(&mut reader.by_ref().take(count as u64), &mut io::sink())
+                        .map_err(|e| format!("Error reading console input: {}", e))?;
+                Ok(skipped as usize)
+            }
+            Some(Input::Channel(receiver)) => {
+                Ok((0..count).take_while(|_| receiver.recv().is_ok()).count())
+            }
+        }
+    }
+}
+
+impl Device for Console {
+    fn read(&mut self, offset: u8) -> u8 {
+        match offset {
+            CONSOLE_STATUS => self.has_input() as u8,
+            CONSOLE_INPUT => {
+                self.has_input();
+                self.pending.pop_front().unwrap_or(0)
+            }
+            _ => 0,
+        }
+    }
+
+    fn write(&mut self, offset: u8, value: u8) {
+        if offset == CONSOLE_OUTPUT {
+            // A program has no way to hear about a failed write, so it is dropped
+            let _ = self
+                .output
+                .write_all(&[value])
+                .and_then(|_| self.output.flush());
+        }
+    }
+
+    /// How many input bytes were read, in decimal, then those not yet loaded
+    /// as hex bytes, such as `read 5 pending 62 0A`.
+    fn save_state(&self) -> String {
+        let pending: Vec<String> = self
+            .pending
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect();
+        if pending.is_empty() {
+            format!("read {}", self.consumed)
+        } else {
+            format!("read {} pending {}", self.consumed, pending.join(" "))
+        }
+    }
+
+    /// Skips the input a saved console had already read, so a resumed
+    /// program carries on where it stopped instead of reading it again.
+    fn load_state(&mut self, state: &str) -> Result<(), String> {
+        let invalid = || format!("Error: Invalid console state '{}'.", state);
+        let (read, pending) = match state.split_once("pending") {
+            Some((read, pending)) => (read.trim(), pending),
+            None => (state.trim(), ""),
+        };
+        let read: usize = match read.strip_prefix("read") {
+            Some(count) => count.trim().parse().map_err(|_| invalid())?,
+            None if read.is_empty() => 0,
+            None => return Err(invalid()),
+        };
+        let pending: VecDeque<u8> = pending
+            .split_whitespace()
+            .map(|byte| u8::from_str_radix(byte, 16))
+            .collect::<Result<_, _>>()
+            .map_err(|_| invalid())?;
+
+        if read > self.consumed {
+            let wanted: usize = read - self.consumed;
+            if self.skip(wanted)? < wanted {
+                return Err(format!(
+                    "Error: The console input ends before the {} bytes the snapshot had read.",
+                    read
+                ));
+            }
+        }
+        self.consumed = self.consumed.max(read);
+        self.pending = pending;
+        Ok(())
+    }
+}
+
+/// A device to attach, with the name snapshots know it by and the addresses
+/// it takes up.
+pub struct DeviceSpec {
+    pub name: String,
+    pub start: u8,
+    pub end: u8,
+    pub device: Box<dyn Device>,
+}
+
+/// Parses a `--device` argument: a kind and a hex address, such as `timer@F0`.
+/// A console reads `input_path` if given, or stdin on a background thread.
+pub fn parse(text: &str, input_path: Option<&str>) -> Result<DeviceSpec, String> {
+    let (kind, address) = text
+        .split_once('@')
+        .ok_or_else(|| format!("Error: Expected KIND@ADDR for a device, found '{}'.", text))?;
+    let start: u8 = u8::from_str_radix(address, 16)
+        .map_err(|_| format!("Error: Invalid device address '{}'.", address))?;
+    let (size, device): (u8, Box<dyn Device>) = match kind {
+        "timer" => (1, Box::<Timer>::default()),
+        "console" => {
+            let output: Box<dyn Write> = Box::new(io::stdout());
+            let console: Console = match input_path {
+                Some(path) => {
+                    let file: std::fs::File = std::fs::File::open(path)
+                        .map_err(|e| format!("Error reading file {}: {}", path, e))?;
+                    Console::new(Box::new(io::BufReader::new(file)), output)
+                }
+                None => Console::background(io::stdin(), output),
+            };
+            (3, Box::new(console))
+        }
+        _ => {
+            return Err(format!(
+                "Error: Unknown device '{}'. Expected timer or console.",
+                kind
+            ))
+        }
+    };
+    let end: u8 = start.checked_add(size - 1).ok_or_else(|| {
+        format!(
+            "Error: Device {} does not fit in memory at {:02X}.",
+            kind, start
+        )
+    })?;
+    Ok(DeviceSpec {
+        name: format!("{}@{:02X}", kind, start),
+        start,
+        end,
+        device,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator2::Emulator;
+    use crate::program::Program;
+    use crate::snapshot::Snapshot;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn attach(emulator: &mut Emulator, text: &str) {
+        let spec: DeviceSpec = parse(text, None).unwrap();
+        emulator
+            .attach_device(&spec.name, spec.start, spec.end, spec.device)
+            .unwrap();
+    }
+
+    #[test]
+    fn test_loads_and_stores_reach_devices() {
+        // Reset the timer, spend two instructions, then read it directly and
+        // indirectly; memory under the timer is never touched
+        let source: &str = "        MOV 00 -> R1
+        MOV R1 -> [F0]
+        NOP
+        MOV [F0] -> R2
+        MOV F0 -> R3
+        MOV [R3] -> R4
+        MOV R4 -> [R3]
+        HALT
+";
+        let program: Program = Program::assemble_source("test.nha", source).unwrap();
+        let mut emulator: Emulator = Emulator::new(program.bytes);
+        attach(&mut emulator, "timer@F0");
+        emulator.run().unwrap();
+        assert_eq!(emulator.registers()[2], 0x02);
+        assert_eq!(emulator.registers()[4], 0x04);
+        assert_eq!(emulator.memory()[0xF0], 0x00);
+
+        // The timer's count goes into snapshots and comes back out
+        let snapshot: Snapshot = emulator.snapshot();
+        assert_eq!(
+            snapshot.devices,
+            vec![("timer@F0".to_string(), "06".to_string())]
+        );
+        let mut restored: Emulator = Emulator::new(Vec::new());
+        assert!(restored.restore(&snapshot).is_err());
+        attach(&mut restored, "timer@F0");
+        restored.restore(&snapshot).unwrap();
+        assert_eq!(restored.snapshot(), snapshot);
+    }
+
+    #[test]
+    fn test_device_arguments() {
+        assert_eq!(parse("timer@f0", None).unwrap().name, "timer@F0");
+        assert!(parse("timer", None).is_err());
+        assert!(parse("lamp@10", None).is_err());
+        assert!(parse("console@FE", None).is_err());
+
+        let mut emulator: Emulator = Emulator::new(Vec::new());
+        attach(&mut emulator, "timer@F0");
+        let spec: DeviceSpec = parse("timer@F0", None).unwrap();
+        assert_eq!(
+            emulator
+                .attach_device(&spec.name, spec.start, spec.end, spec.device)
+                .unwrap_err(),
+            "Error: Device timer@F0 at F0-F0 overlaps timer@F0 at F0-F0."
+        );
+    }
+
+    /// Collects console output where the test can see it.
+    #[derive(Clone, Default)]
+    struct SharedOutput(Rc<RefCell<Vec<u8>>>);
+
+    impl Write for SharedOutput {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.borrow_mut().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn run_with_console(source: &str, input: &str) -> (Emulator, String) {
+        let program: Program = Program::assemble_source("test.nha", source).unwrap();
+        let mut emulator: Emulator = Emulator::new(program.bytes);
+        let output: SharedOutput = SharedOutput::default();
+        let console: Console = Console::new(
+            Box::new(io::Cursor::new(input.as_bytes().to_vec())),
+            Box::new(output.clone()),
+        );
+        emulator
+            .attach_device("console@F0", 0xF0, 0xF2, Box::new(console))
+            .unwrap();
+        emulator.run().unwrap();
+        let text: String = String::from_utf8(output.0.borrow().clone()).unwrap();
+        (emulator, text)
+    }
+
+    #[test]
+    fn test_console_hello_world() {
+        let source: &str = "        MOV text -> R1
+        MOV 1 -> R2
+        MOV 0 -> R0
+loop:   MOV [R1] -> R3
+        JMPEQ done, R3
+        MOV R3 -> [F0]
+        ADDI R1, R2 -> R1
+        JMP loop
+done:   HALT
+text:   DATA 'Hello, world!'
+        DATA 0A
+        DATA 00
+";
+        let (_, output) = run_with_console(source, "");
+        assert_eq!(output, "Hello, world!\n");
+    }
+
+    #[test]
+    fn test_console_echoes_input_until_it_runs_out() {
+        // Poll the status port, echo each byte in upper case, and stop at the end
+        let source: &str = "        MOV 0 -> R0
+        MOV DF -> R5
+loop:   MOV [F1] -> R1
+        JMPEQ done, R1
+        MOV [F2] -> R2
+        AND R2, R5 -> R2
+        MOV R2 -> [F0]
+        JMP loop
+done:   HALT
+";
+        let (emulator, output) = run_with_console(source, "ok\nhi");
+        assert_eq!(output, "OK\nHI");
+        assert_eq!(emulator.memory()[0xF1], 0x00);
+        assert_eq!(emulator.snapshot().devices[0].1, "read 5");
+    }
+
+    fn console_reading(input: &str) -> Console {
+        Console::new(
+            Box::new(io::Cursor::new(input.as_bytes().to_vec())),
+            Box::new(io::sink()),
+        )
+    }
+
+    #[test]
+    fn test_console_state_keeps_unread_input() {
+        let mut console: Console = console_reading("ab\ncd");
+        assert_eq!(console.read(CONSOLE_INPUT), b'a');
+        assert_eq!(console.save_state(), "read 3 pending 62 0A");
+
+        // A fresh console over the same input skips what was read
+        let mut resumed: Console = console_reading("ab\ncd");
+        resumed.load_state("read 3 pending 7A").unwrap();
+        let input: Vec<u8> = (0..5).map(|_| resumed.read(CONSOLE_INPUT)).collect();
+        assert_eq!(input, b"zcd\0\0");
+        assert_eq!(resumed.read(CONSOLE_STATUS), 0);
+        assert_eq!(resumed.save_state(), "read 5");
+
+        assert!(console_reading("ab").load_state("read 3").is_err());
+        assert!(console_reading("ab").load_state("62 0A").is_err());
+    }
+
+    /// Input that arrives only when the test sends it, like a terminal.
+    struct Typed(mpsc::Receiver<Vec<u8>>);
+
+    impl Read for Typed {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match self.0.recv() {
+                Ok(line) => {
+                    buf[..line.len()].copy_from_slice(&line);
+                    Ok(line.len())
+                }
+                Err(_) => Ok(0),
+            }
+        }
+    }
+
+    #[test]
+    fn test_console_polls_background_input() {
+        let (keyboard, typed) = mpsc::channel::<Vec<u8>>();
+        let mut console: Console = Console::background(Typed(typed), Box::new(io::sink()));
+        // Nothing typed yet: the status port answers at once
+        assert_eq!(console.read(CONSOLE_STATUS), 0);
+        assert_eq!(console.read(CONSOLE_INPUT), 0);
+
+        keyboard.send(b"hi\n".to_vec()).unwrap();
+        drop(keyboard);
+        let start: std::time::Instant = std::time::Instant::now();
+        let mut input: Vec<u8> = Vec::new();
+        while input.len() < 3 {
+            assert!(
+                start.elapsed().as_secs() < 5,
+                "the typed line never arrived"
+            );
+            if console.read(CONSOLE_STATUS) == 1 {
+                input.push(console.read(CONSOLE_INPUT));
+            }
+        }
+        assert_eq!(input, b"hi\n");
+        assert_eq!(console.save_state(), "read 3");
+    }
+
+    #[test]
+    fn test_console_save_and_resume() {
+        // Echo input, pausing part-way through the second line
+        let source: &str = "        MOV 0 -> R0
+loop:   MOV [F1] -> R1
+        JMPEQ done, R1
+        MOV [F2] -> R2
+        MOV R2 -> [F0]
+        JMP loop
+done:   HALT
+";
+        let program: Program = Program::assemble_source("test.nha", source).unwrap();
+        let start = |output: &SharedOutput| -> Emulator {
+            let mut emulator: Emulator = Emulator::new(program.bytes.clone());
+            let console: Console = Console::new(
+                Box::new(io::Cursor::new(b"ab\ncd\n".to_vec())),
+                Box::new(output.clone()),
+            );
+            emulator
+                .attach_device("console@F0", 0xF0, 0xF2, Box::new(console))
+                .unwrap();
+            emulator
+        };
+
+        let before: SharedOutput = SharedOutput::default();
+        let mut paused: Emulator = start(&before);
+        paused.run_for(21).unwrap();
+        let snapshot: Snapshot = Snapshot::parse(&paused.snapshot().to_string()).unwrap();
+        assert_eq!(snapshot.devices[0].1, "read 6 pending 64 0A");
+
+        let after: SharedOutput = SharedOutput::default();
+        let mut resumed: Emulator = start(&after);
+        resumed.restore(&snapshot).unwrap();
+        resumed.run().unwrap();
+        let output: Vec<u8> = [before.0.borrow().clone(), after.0.borrow().clone()].concat();
+        assert_eq!(output, b"ab\ncd\n");
+    }
+}
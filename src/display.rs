@@ -0,0 +1,371 @@
+use std::io::{self, IsTerminal, Write};
+use std::time::{Duration, Instant};
+
+/// The display is memory 0x80–0xFF read as a 32×32 monochrome bitmap: four
+/// bytes per row, most significant bit leftmost.
+pub const DISPLAY_START: usize = 0x80;
+pub const WIDTH: usize = 32;
+pub const HEIGHT: usize = 32;
+
+pub fn pixel(memory: &[u8; 256], x: usize, y: usize) -> bool {
+    let byte: u8 = memory[DISPLAY_START + y * (WIDTH / 8) + x / 8];
+    byte & (0x80 >> (x % 8)) != 0
+}
+
+pub fn lit_pixels(memory: &[u8; 256]) -> usize {
+    memory[DISPLAY_START..]
+        .iter()
+        .map(|byte| byte.count_ones() as usize)
+        .sum()
+}
+
+/// One line per row, `#` for a lit pixel and `.` for an unlit one.
+pub fn render_ascii(memory: &[u8; 256]) -> String {
+    let mut text: String = String::with_capacity((WIDTH + 1) * HEIGHT);
+    for y in 0..HEIGHT {
+        for x in 0..WIDTH {
+            text.push(if pixel(memory, x, y) { '#' } else { '.' });
+        }
+        text.push('\n');
+    }
+    text
+}
+
+/// Two pixel rows per line using Unicode half blocks, inside a box so unlit
+/// pixels at the edges still show where the display ends.
+pub fn render_half_blocks(memory: &[u8; 256]) -> String {
+    let mut text: String = format!("┌{}┐\n", "─".repeat(WIDTH));
+    for y in (0..HEIGHT).step_by(2) {
+        text.push('│');
+        for x in 0..WIDTH {
+            text.push(match (pixel(memory, x, y), pixel(memory, x, y + 1)) {
+                (true, true) => '█',
+                (true, false) => '▀',
+                (false, true) => '▄',
+                (false, false) => ' ',
+            });
+        }
+        text.push_str("│\n");
+    }
+    text.push_str(&format!("└{}┘\n", "─".repeat(WIDTH)));
+    text
+}
+
+/// The display as a plain (P1) PBM image, with lit pixels black.
+pub fn to_pbm(memory: &[u8; 256]) -> String {
+    let mut text: String = format!("P1\n{} {}\n", WIDTH, HEIGHT);
+    for y in 0..HEIGHT {
+        let row: Vec<&str> = (0..WIDTH)
+            .map(|x| if pixel(memory, x, y) { "1" } else { "0" })
+            .collect();
+        text.push_str(&row.join(" "));
+        text.push('\n');
+    }
+    text
+}
+
+/// Reads ASCII art like `render_ascii` prints: one line per row, `#` for a
+/// lit pixel and `.` for an unlit one. Blank lines are ignored.
+pub fn parse_ascii_art(text: &str) -> Result<Vec<Vec<bool>>, String> {
+    text.lines()
+        .map(|line| line.trim_end())
+        .filter(|line| !line.is_empty())
+        .enumerate()
+        .map(|(row, line)| {
+            line.chars()
+                .map(|c| match c {
+                    '#' => Ok(true),
+                    '.' => Ok(false),
+                    _ => Err(format!(
+                        "Error: Unexpected '{}' in row {} of the image; use '#' and '.'.",
+                        c,
+                        row + 1
+                    )),
+                })
+                .collect()
+        })
+        .collect()
+}
+
+/// Reads an expected image: a PBM file, or ASCII art otherwise.
+pub fn load_image(path: &str) -> Result<Vec<Vec<bool>>, String> {
+    let data: Vec<u8> =
+        std::fs::read(path).map_err(|e| format!("Error reading file {}: {}", path, e))?;
+    let image = if data.starts_with(b"P1") || data.starts_with(b"P4") {
+        parse_pbm(&data)
+    } else {
+        parse_ascii_art(&String::from_utf8_lossy(&data))
+    };
+    image.map_err(|e| format!("{}: {}", path, e))
+}
+
+/// Compares the display with an expected 32×32 image. Returns `None` when
+/// they match, or a picture of the differences: `+` for a pixel that is lit
+/// but should not be, `-` for one that should be lit but is not.
+pub fn diff_image(memory: &[u8; 256], expected: &[Vec<bool>]) -> Result<Option<String>, String> {
+    if expected.len() != HEIGHT || expected.iter().any(|row| row.len() != WIDTH) {
+        return Err(format!(
+            "Error: The expected image must be {}x{} pixels.",
+            WIDTH, HEIGHT
+        ));
+    }
+
+    let (mut extra, mut missing) = (0, 0);
+    let mut picture: String = format!("   {}\n", "0123456789".repeat(4).split_at(WIDTH).0);
+    for (y, row) in expected.iter().enumerate() {
+        picture.push_str(&format!("{:2} ", y));
+        for (x, want) in row.iter().enumerate() {
+            picture.push(match (pixel(memory, x, y), *want) {
+                (true, true) => '#',
+                (false, false) => '.',
+                (true, false) => {
+                    extra += 1;
+                    '+'
+                }
+                (false, true) => {
+                    missing += 1;
+                    '-'
+                }
+            });
+        }
+        picture.push('\n');
+    }
+
+    if extra + missing == 0 {
+        return Ok(None);
+    }
+    Ok(Some(format!(
+        "{} pixel(s) differ: {} lit that should be unlit (+), {} unlit that should be lit (-).\n{}",
+        extra + missing,
+        extra,
+        missing,
+        picture
+    )))
+}
+
+/// How often `LiveView` redraws at most.
+const REFRESH_INTERVAL: Duration = Duration::from_millis(40);
+
+/// Redraws the display in place on the terminal while a program runs.
+#[derive(Default)]
+pub struct LiveView {
+    shown: Option<[u8; 128]>,
+    drawn_at: Option<Instant>,
+}
+
+impl LiveView {
+    /// Redraws if the display has changed, unless the last redraw was very
+    /// recent; a program drawing in a tight loop would otherwise spend all
+    /// its time on the terminal. When stdout is not a terminal, only the
+    /// final frame is drawn.
+    pub fn refresh(&mut self, memory: &[u8; 256]) -> io::Result<()> {
+        let recent: bool = self
+            .drawn_at
+            .is_some_and(|drawn_at| drawn_at.elapsed() < REFRESH_INTERVAL);
+        if recent || !io::stdout().is_terminal() {
+            return Ok(());
+        }
+        self.draw(memory)
+    }
+
+    /// Draws the final frame whenever the last redraw was.
+    pub fn finish(&mut self, memory: &[u8; 256]) -> io::Result<()> {
+        self.draw(memory)
+    }
+
+    fn draw(&mut self, memory: &[u8; 256]) -> io::Result<()> {
+        let frame: [u8; 128] = memory[DISPLAY_START..].try_into().unwrap();
+        if self.shown == Some(frame) {
+            return Ok(());
+        }
+        let mut stdout = io::stdout().lock();
+        if self.shown.is_some() {
+            // Move back up over the previous frame and its border
+            write!(stdout, "\x1b[{}A", HEIGHT / 2 + 2)?;
+        }
+        write!(stdout, "{}", render_half_blocks(memory))?;
+        stdout.flush()?;
+        self.shown = Some(frame);
+        self.drawn_at = Some(Instant::now());
+        Ok(())
+    }
+}
+
+/// Packs rows of pixels into bytes in display order: most significant bit
+/// leftmost, each row padded with zeros to a whole byte.
+pub fn pack_rows(rows: &[Vec<bool>]) -> Vec<u8> {
+    let mut bytes: Vec<u8> = Vec::new();
+    for row in rows {
+        for chunk in row.chunks(8) {
+            let mut byte: u8 = 0;
+            for (bit, lit) in chunk.iter().enumerate() {
+                if *lit {
+                    byte |= 0x80 >> bit;
+                }
+            }
+            bytes.push(byte);
+        }
+    }
+    bytes
+}
+
+/// Reads a plain (P1) or raw (P4) PBM image. Black pixels are lit.
+pub fn parse_pbm(data: &[u8]) -> Result<Vec<Vec<bool>>, String> {
+    let mut position: usize = 0;
+
+    // Header fields are separated by whitespace and may be followed by comments
+    let next_field = |position: &mut usize| -> Option<String> {
+        loop {
+            while *position < data.len() && data[*position].is_ascii_whitespace() {
+                *position += 1;
+            }
+            if data.get(*position) == Some(&b'#') {
+                while *position < data.len() && data[*position] != b'\n' {
+                    *position += 1;
+                }
+            } else {
+                break;
+            }
+        }
+        let start: usize = *position;
+        while *position < data.len() && !data[*position].is_ascii_whitespace() {
+            *position += 1;
+        }
+        (start < *position).then(|| String::from_utf8_lossy(&data[start..*position]).into_owned())
+    };
+
+    let magic: String = next_field(&mut position).unwrap_or_default();
+    let dimension = |position: &mut usize| -> Result<usize, String> {
+        next_field(position)
+            .and_then(|field| field.parse().ok())
+            .ok_or_else(|| "Error: Invalid PBM header.".to_string())
+    };
+    let width: usize = dimension(&mut position)?;
+    let height: usize = dimension(&mut position)?;
+    // Check the size before anything is allocated for it
+    let pixel_count: usize = width
+        .checked_mul(height)
+        .ok_or_else(|| "Error: Invalid PBM header.".to_string())?;
+    if width > WIDTH || height > HEIGHT {
+        return Err(format!(
+            "Error: PBM image is {}x{} pixels, larger than the {}x{} display.",
+            width, height, WIDTH, HEIGHT
+        ));
+    }
+
+    match magic.as_str() {
+        "P1" => {
+            let pixels: Vec<bool> = data[position..]
+                .iter()
+                .filter(|c| **c == b'0' || **c == b'1')
+                .map(|c| *c == b'1')
+                .collect();
+            if pixels.len() < pixel_count {
+                return Err("Error: PBM image has fewer pixels than its size says.".to_string());
+            }
+            Ok(pixels[..pixel_count]
+                .chunks(width.max(1))
+                .map(|row| row.to_vec())
+                .collect())
+        }
+        "P4" => {
+            // Exactly one whitespace byte separates the header from the data
+            let start: usize = position + 1;
+            let row_bytes: usize = width.div_ceil(8);
+            if data.len() < start + row_bytes * height {
+                return Err("Error: PBM image has fewer pixels than its size says.".to_string());
+            }
+            Ok((0..height)
+                .map(|y| {
+                    (0..width)
+                        .map(|x| data[start + y * row_bytes + x / 8] & (0x80 >> (x % 8)) != 0)
+                        .collect()
+                })
+                .collect())
+        }
+        _ => Err(format!("Error: Not a PBM image (magic '{}').", magic)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_most_significant_bit_is_leftmost() {
+        let mut memory: [u8; 256] = [0; 256];
+        memory[0x80] = 0x80;
+        memory[0xFF] = 0x01;
+
+        assert!(pixel(&memory, 0, 0));
+        assert!(pixel(&memory, 31, 31));
+        assert_eq!(lit_pixels(&memory), 2);
+
+        let rendered: String = render_ascii(&memory);
+        let rows: Vec<&str> = rendered.lines().collect();
+        assert_eq!(rows.len(), HEIGHT);
+        assert_eq!(&rows[0][..2], "#.");
+        assert_eq!(&rows[31][30..], ".#");
+    }
+
+    #[test]
+    fn test_half_blocks_and_pbm_export() {
+        let mut memory: [u8; 256] = [0; 256];
+        memory[0x80] = 0xC0; // Row 0: x = 0 and 1
+        memory[0x84] = 0xA0; // Row 1: x = 0 and 2
+        memory[0xFF] = 0x01; // Row 31: x = 31
+
+        let rendered: String = render_half_blocks(&memory);
+        let lines: Vec<&str> = rendered.lines().collect();
+        assert_eq!(lines.len(), HEIGHT / 2 + 2);
+        assert!(lines[1].starts_with("│█▀▄ "));
+        assert!(lines[16].ends_with("▄│"));
+
+        let rows: Vec<Vec<bool>> = parse_pbm(to_pbm(&memory).as_bytes()).unwrap();
+        assert_eq!(pack_rows(&rows), memory[DISPLAY_START..].to_vec());
+    }
+
+    #[test]
+    fn test_golden_image_diff() {
+        let mut memory: [u8; 256] = [0; 256];
+        memory[0x80] = 0x80;
+        let mut expected: Vec<Vec<bool>> = parse_ascii_art(&render_ascii(&memory)).unwrap();
+        assert_eq!(diff_image(&memory, &expected), Ok(None));
+
+        expected[0][0] = false;
+        expected[2][5] = true;
+        let diff: String = diff_image(&memory, &expected).unwrap().unwrap();
+        let lines: Vec<&str> = diff.lines().collect();
+        assert_eq!(
+            lines[0],
+            "2 pixel(s) differ: 1 lit that should be unlit (+), 1 unlit that should be lit (-)."
+        );
+        assert!(lines[2].starts_with(" 0 +...."));
+        assert!(lines[4].starts_with(" 2 .....-"));
+
+        assert!(diff_image(&memory, &expected[..31]).is_err());
+        assert!(parse_ascii_art("#.x").is_err());
+    }
+
+    #[test]
+    fn test_pbm_rows_pack_in_display_order() {
+        let plain: &[u8] = b"P1\n# a comment\n10 2\n1 0 0 0 0 0 0 0 0 1\n0000000001\n";
+        let rows: Vec<Vec<bool>> = parse_pbm(plain).unwrap();
+        assert_eq!(pack_rows(&rows), vec![0x80, 0x40, 0x00, 0x40]);
+
+        let raw: &[u8] = &[
+            b'P', b'4', b'\n', b'1', b'0', b' ', b'2', b'\n', 0x80, 0x40, 0x00, 0x40,
+        ];
+        assert_eq!(parse_pbm(raw).unwrap(), rows);
+    }
+
+    #[test]
+    fn test_pbm_size_is_checked_before_reading() {
+        let huge: &[u8] = b"P4\n18446744073709551615 18446744073709551615\n";
+        assert_eq!(parse_pbm(huge).unwrap_err(), "Error: Invalid PBM header.");
+        assert_eq!(
+            parse_pbm(b"P1\n33 1\n").unwrap_err(),
+            "Error: PBM image is 33x1 pixels, larger than the 32x32 display."
+        );
+    }
+}
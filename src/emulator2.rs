@@ -1,157 +1,601 @@
+mod bus;
 mod emulator_functions2;
-use std::thread;
 
+use crate::events::{self, Event};
+use crate::float8::{self, Precision};
+use crate::instruction::Instruction;
+use crate::snapshot::Snapshot;
+use bus::Bus;
+pub use bus::Device;
 use emulator_functions2::EmulatorFunctions;
+use std::fmt;
+use std::time::{Duration, Instant};
+
+/// How many instructions `run` executes before giving up on a program that
+/// never halts, unless `set_step_limit` says otherwise.
+pub const DEFAULT_STEP_LIMIT: usize = 1_000_000;
+
+/// Why the machine could not execute an instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapReason {
+    InvalidOpcode(u8),
+    /// A conditional jump (`F`) with a test code above 5.
+    InvalidJumpTest(u8),
+}
+
+/// An instruction the machine could not execute.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct EmulatorError {
+    pub pc: u8,
+    pub instruction: u16,
+    pub reason: TrapReason,
+}
+
+impl fmt::Display for EmulatorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let reason: String = match self.reason {
+            TrapReason::InvalidOpcode(opcode) => format!("invalid opcode {:X}", opcode),
+            TrapReason::InvalidJumpTest(test) => format!("invalid jump test {:X}", test),
+        };
+        write!(
+            f,
+            "Error: Trap at {:02X} in instruction {:04X}: {}.",
+            self.pc, self.instruction, reason
+        )
+    }
+}
+
+/// What the machine does when an instruction traps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TrapPolicy {
+    /// Stop, and return the error from `run`.
+    Halt,
+    /// Treat the instruction as a NOP.
+    Ignore,
+    /// Jump to a handler at this address, with the trapping instruction's address in RF.
+    Jump(u8),
+}
+
+impl TrapPolicy {
+    /// Parses `halt`, `ignore` or `jump:XX` (a hex handler address).
+    pub fn parse(text: &str) -> Result<TrapPolicy, String> {
+        match text.to_lowercase().as_str() {
+            "halt" => Ok(TrapPolicy::Halt),
+            "ignore" => Ok(TrapPolicy::Ignore),
+            other => other
+                .strip_prefix("jump:")
+                .and_then(|address| u8::from_str_radix(address, 16).ok())
+                .map(TrapPolicy::Jump)
+                .ok_or_else(|| {
+                    format!(
+                        "Error: Unknown trap policy '{}'. Expected halt, ignore or jump:XX.",
+                        text
+                    )
+                }),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RegisterWrite {
+    pub register: u8,
+    pub old: u8,
+    pub new: u8,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryWrite {
+    pub address: u8,
+    pub old: u8,
+    pub new: u8,
+}
+
+/// What one `step` did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct StepRecord {
+    /// How many instructions ran before this one.
+    pub step: usize,
+    pub pc: u8,
+    pub instruction: u16,
+    pub decoded: Option<Instruction>,
+    pub register_writes: Vec<RegisterWrite>,
+    /// Addresses loads read data from. Instruction fetches are not included.
+    pub memory_reads: Vec<u8>,
+    pub memory_writes: Vec<MemoryWrite>,
+    /// The address jumped to, if a jump was taken.
+    pub jump: Option<u8>,
+    /// A trap the policy recovered from. With `TrapPolicy::Halt`, `step`
+    /// returns the error instead.
+    pub trap: Option<EmulatorError>,
+}
+
+impl fmt::Display for StepRecord {
+    /// One line: address, instruction word, what it decodes to, then its effects.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text: String = match self.decoded {
+            Some(instruction) => instruction.to_string(),
+            None => String::from("?"),
+        };
+        write!(f, "{:02X}: {:04X}  {:<20}", self.pc, self.instruction, text)?;
+        for write in &self.register_writes {
+            write!(
+                f,
+                " R{:X}={:02X} (was {:02X})",
+                write.register, write.new, write.old
+            )?;
+        }
+        for write in &self.memory_writes {
+            write!(
+                f,
+                " [{:02X}]={:02X} (was {:02X})",
+                write.address, write.new, write.old
+            )?;
+        }
+        if let Some(address) = self.jump {
+            write!(f, " jump -> {:02X}", address)?;
+        }
+        if let Some(trap) = &self.trap {
+            write!(f, " {}", trap)?;
+        }
+        Ok(())
+    }
+}
+
+/// Why `run` returned without an error.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Stop {
+    Halted,
+    StepLimit,
+    Timeout,
+    /// The whole machine state repeated, so the instructions from `start` to
+    /// `end` (inclusive) will loop forever.
+    Loop {
+        start: u8,
+        end: u8,
+    },
+}
+
+impl fmt::Display for Stop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Stop::Halted => write!(f, "halted"),
+            Stop::StepLimit => write!(f, "reached the instruction limit"),
+            Stop::Timeout => write!(f, "timed out"),
+            Stop::Loop { start, end } => write!(
+                f,
+                "infinite loop between {:02X} and {:02X} (the machine state repeats)",
+                start, end
+            ),
+        }
+    }
+}
+
+/// Brent's cycle detection over the machine state: a snapshot, retaken at
+/// power-of-two intervals, is compared with the state after every step.
+#[derive(Default)]
+struct LoopDetector {
+    snapshot: Option<(u8, [u8; 16], [u8; 256])>,
+    window: usize,
+    since_snapshot: usize,
+    // Addresses executed since the snapshot: the loop body once the state repeats
+    executed: Vec<u8>,
+}
+
+impl LoopDetector {
+    fn check(&mut self, executed: u8, emulator: &Emulator) -> Option<Stop> {
+        let state: (u8, [u8; 16], [u8; 256]) = (
+            emulator.program_counter(),
+            emulator.register_values,
+            emulator.bus.memory,
+        );
+        self.executed.push(executed);
+        if self.snapshot.as_ref() == Some(&state) {
+            let start: u8 = *self.executed.iter().min()?;
+            let end: u8 = self.executed.iter().max()?.wrapping_add(1);
+            return Some(Stop::Loop { start, end });
+        }
+
+        self.since_snapshot += 1;
+        if self.since_snapshot >= self.window {
+            self.snapshot = Some(state);
+            self.window = (self.window * 2).max(1);
+            self.since_snapshot = 0;
+            self.executed.clear();
+        }
+        None
+    }
+}
 
 pub struct Emulator {
-    assembled_code: Vec<u8>,
     register_values: [u8; 16], // Assuming 16 registers, indexed from 0 to 15
-    memory: [u8; 256],         // Assuming a memory size of 256 bytes
+    bus: Bus,                  // 256 bytes of memory, and any devices mapped over it
     halted: bool,
     program_counter: usize, // To keep track of the current instruction
     cir: u16,               // Current instruction register
     ef: EmulatorFunctions,  // Instance of EmulatorFunctions for utility methods
     jump_instruction: bool, // Flag for jump instructions
+    steps: usize,           // Instructions executed so far
+    step_limit: Option<usize>,
+    timeout: Option<Duration>,
+    detect_loops: bool,
+    trap_policy: TrapPolicy,
+    record: StepRecord, // What the current step has done so far
 }
 
 impl Emulator {
     pub fn new(assembled_code: Vec<u8>) -> Self {
-        println!("Emulator is running...");
         let mut memory: [u8; 256] = [0; 256];
         for (i, byte) in assembled_code.iter().enumerate() {
             memory[i] = *byte; // Load assembled code into memory
         }
 
         Emulator {
-            assembled_code,
             register_values: [0; 16], // Initialize all registers to 0
-            memory,                   // Initialize all memory to 0
+            bus: Bus::new(memory),
             halted: false,
             program_counter: 0, // Start at the beginning of the assembled code
             cir: 0,             // Initialize the current instruction register
             ef: EmulatorFunctions::new(), // Create an instance of EmulatorFunctions
             jump_instruction: false, // Initialize jump instruction flag
+            steps: 0,
+            step_limit: Some(DEFAULT_STEP_LIMIT),
+            timeout: None,
+            detect_loops: true,
+            trap_policy: TrapPolicy::Halt,
+            record: StepRecord::default(),
         }
     }
 
-    pub fn run(&mut self) {
-        for (index, line) in self.assembled_code.iter().enumerate() {
-            println!("Executing line {}: {:02X}", index, line);
-            // Add your execution logic here
+    /// Runs until HALT, the step limit, the timeout, or a detected infinite
+    /// loop. With the `Halt` trap policy, an instruction that traps stops the
+    /// machine and is returned as the error.
+    pub fn run(&mut self) -> Result<Stop, EmulatorError> {
+        self.run_with(|_, _| {})
+    }
+
+    /// Like `run`, handing each step's record to `observer` as it executes.
+    pub fn run_with<F>(&mut self, mut observer: F) -> Result<Stop, EmulatorError>
+    where
+        F: FnMut(&Emulator, &StepRecord),
+    {
+        let started: Instant = Instant::now();
+        // Devices have state of their own, such as input still to come, so a
+        // repeated machine state no longer means the program is stuck
+        let mut detector: Option<LoopDetector> =
+            (self.detect_loops && !self.bus.has_devices()).then(LoopDetector::default);
+
+        loop {
+            if self.halted {
+                return Ok(Stop::Halted);
+            }
+            if !self.is_running() {
+                return Ok(Stop::StepLimit);
+            }
+            // Reading the clock every step would slow tight loops down
+            if let Some(timeout) = self.timeout {
+                if self.steps.is_multiple_of(1024) && started.elapsed() >= timeout {
+                    return Ok(Stop::Timeout);
+                }
+            }
+
+            let record: StepRecord = self.step()?;
+            observer(self, &record);
+            if let Some(detector) = &mut detector {
+                if let Some(stop) = detector.check(record.pc, self) {
+                    return Ok(stop);
+                }
+            }
         }
+    }
 
-        println!("List of register values: {:02X?}", self.register_values);
+    /// Steps until `condition` holds after a step, returning `true`, or until
+    /// the machine stops, returning `false`.
+    pub fn run_until<F>(&mut self, mut condition: F) -> Result<bool, EmulatorError>
+    where
+        F: FnMut(&Emulator, &StepRecord) -> bool,
+    {
+        while self.is_running() {
+            let record: StepRecord = self.step()?;
+            if condition(self, &record) {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
 
-        println!("List of memory values: \n{:02X?}", self.memory);
+    /// Executes up to `count` instructions, stopping early if the machine
+    /// stops, and returns their records.
+    pub fn run_for(&mut self, count: usize) -> Result<Vec<StepRecord>, EmulatorError> {
+        let mut records: Vec<StepRecord> = Vec::new();
+        while self.is_running() && records.len() < count {
+            records.push(self.step()?);
+        }
+        Ok(records)
+    }
 
-        while !self.halted && self.program_counter < self.assembled_code.len() {
-            println!(
-                "Program Counter: {}, Assembled Code Length: {}",
-                self.program_counter,
-                self.assembled_code.len()
-            );
+    pub fn set_trap_policy(&mut self, policy: TrapPolicy) {
+        self.trap_policy = policy;
+    }
 
-            self.fetch();
-            self.decode();
+    /// Caps how many instructions `run` executes in total; `None` runs until HALT.
+    pub fn set_step_limit(&mut self, limit: Option<usize>) {
+        self.step_limit = limit;
+    }
+
+    /// Limits how long `run` may take, checked every 1024 instructions.
+    pub fn set_timeout(&mut self, timeout: Option<Duration>) {
+        self.timeout = timeout;
+    }
+
+    /// Whether `run` stops as soon as the machine state repeats. On by default,
+    /// but never used while devices are attached.
+    pub fn set_loop_detection(&mut self, enabled: bool) {
+        self.detect_loops = enabled;
+    }
+
+    pub fn steps(&self) -> usize {
+        self.steps
+    }
+
+    pub fn is_running(&self) -> bool {
+        !self.halted && self.step_limit.is_none_or(|limit| self.steps < limit)
+    }
 
-            if self.jump_instruction {
-                println!("Jump instruction detected, skipping fetch-decode cycle");
-                self.jump_instruction = false; // Reset the jump instruction flag
-            } else {
-                self.program_counter += 2; // Move to the next instruction
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    /// The address of the next instruction to execute.
+    pub fn program_counter(&self) -> u8 {
+        self.program_counter as u8
+    }
+
+    /// Executes one instruction and records its effects.
+    pub fn step(&mut self) -> Result<StepRecord, EmulatorError> {
+        self.fetch();
+        self.record = StepRecord {
+            step: self.steps,
+            pc: self.program_counter as u8,
+            instruction: self.cir,
+            decoded: Instruction::decode(self.cir),
+            ..StepRecord::default()
+        };
+        events::emit(Event::InstructionExecuted {
+            pc: self.program_counter as u8,
+            instruction: self.cir,
+        });
+        let outcome: Result<(), TrapReason> = self.decode();
+        self.steps += 1;
+        self.bus.tick();
+
+        if let Err(reason) = outcome {
+            let error: EmulatorError = EmulatorError {
+                pc: self.program_counter as u8,
+                instruction: self.cir,
+                reason,
+            };
+            events::emit(Event::Trap {
+                pc: error.pc,
+                instruction: error.instruction,
+                message: error.to_string(),
+            });
+            match self.trap_policy {
+                TrapPolicy::Halt => {
+                    self.halted = true;
+                    self.record = StepRecord::default();
+                    return Err(error);
+                }
+                TrapPolicy::Ignore => {}
+                TrapPolicy::Jump(handler) => {
+                    self.write_register(0x0F, error.pc);
+                    self.jump_to(handler);
+                }
             }
+            self.record.trap = Some(error);
+        }
+
+        if self.jump_instruction {
+            self.jump_instruction = false; // Reset the jump instruction flag
+        } else {
+            // Move to the next instruction, wrapping from FE to 00
+            self.program_counter = (self.program_counter + 2) % self.bus.memory.len();
+        }
+        Ok(std::mem::take(&mut self.record))
+    }
 
-            println!("");
+    /// Reverses the step `record` describes, restoring the registers, memory
+    /// and PC from before it. Steps must be undone newest first.
+    ///
+    /// Records hold no device state, and a device's effects, such as printed
+    /// output, cannot be taken back, so nothing is undone while devices are
+    /// attached.
+    pub fn undo(&mut self, record: &StepRecord) -> Result<(), String> {
+        if self.bus.has_devices() {
+            return Err(
+                "Error: Cannot step back while devices are attached; their state is not recorded."
+                    .to_string(),
+            );
+        }
+        for write in record.memory_writes.iter().rev() {
+            self.bus.memory[write.address as usize] = write.old;
+        }
+        for write in record.register_writes.iter().rev() {
+            self.register_values[write.register as usize] = write.old;
+        }
+        self.program_counter = record.pc as usize;
+        self.cir = record.instruction;
+        self.steps = record.step;
+        self.halted = false;
+        Ok(())
+    }
 
-            println!("Current Register Values: {:02X?}", self.register_values);
-            // println!("Current Memory State: \n{:02X?}", self.memory);
-            // thread::sleep(std::time::Duration::from_millis(1000)); // Simulate a delay for each instruction
+    /// The machine state, for saving to disk.
+    pub fn snapshot(&self) -> Snapshot {
+        Snapshot {
+            pc: self.program_counter as u8,
+            cir: self.cir,
+            halted: self.halted,
+            steps: self.steps,
+            registers: self.register_values,
+            memory: self.bus.memory,
+            devices: self.bus.save_states(),
         }
+    }
+
+    /// Replaces the machine state with a snapshot's, keeping the run settings.
+    /// Devices named in the snapshot must already be attached.
+    pub fn restore(&mut self, snapshot: &Snapshot) -> Result<(), String> {
+        for (name, state) in &snapshot.devices {
+            self.bus.load_state(name, state)?;
+        }
+        self.program_counter = snapshot.pc as usize;
+        self.cir = snapshot.cir;
+        self.halted = snapshot.halted;
+        self.steps = snapshot.steps;
+        self.register_values = snapshot.registers;
+        self.bus.memory = snapshot.memory;
+        self.jump_instruction = false;
+        Ok(())
+    }
+
+    /// Maps a device over `start..=end`, so loads and stores there reach it.
+    /// `name` identifies the device in snapshots.
+    pub fn attach_device(
+        &mut self,
+        name: &str,
+        start: u8,
+        end: u8,
+        device: Box<dyn Device>,
+    ) -> Result<(), String> {
+        self.bus.attach(name, start, end, device)
+    }
+
+    /// Changes a register from outside the program, as a debugger does.
+    pub fn set_register(&mut self, register: u8, value: u8) {
+        self.write_register(register, value);
+    }
+
+    /// Changes a memory cell from outside the program, bypassing any device
+    /// mapped there.
+    pub fn set_memory(&mut self, address: u8, value: u8) {
+        self.bus.memory[address as usize] = value;
+    }
+
+    pub fn registers(&self) -> &[u8; 16] {
+        &self.register_values
+    }
+
+    pub fn memory(&self) -> &[u8; 256] {
+        &self.bus.memory
+    }
 
-        println!("Emulator has halted.");
-        println!("Final register values: {:02X?}", self.register_values);
-        println!("Final memory state: \n{:02X?}", self.memory);
+    fn write_register(&mut self, register: u8, value: u8) {
+        let old: u8 = self.register_values[register as usize];
+        self.register_values[register as usize] = value;
+        self.record.register_writes.push(RegisterWrite {
+            register,
+            old,
+            new: value,
+        });
+        events::emit(Event::RegisterWrite {
+            register,
+            old,
+            new: value,
+        });
     }
 
+    fn read_memory(&mut self, address: u8) -> u8 {
+        self.record.memory_reads.push(address);
+        self.bus.read(address)
+    }
+
+    fn write_memory(&mut self, address: u8, value: u8) {
+        // A store to a device leaves the memory underneath, and so `old`, as it was
+        let old: u8 = self.bus.memory[address as usize];
+        self.bus.write(address, value);
+        self.record.memory_writes.push(MemoryWrite {
+            address,
+            old,
+            new: value,
+        });
+        events::emit(Event::MemoryWrite {
+            address,
+            old,
+            new: value,
+        });
+    }
+
+    fn jump_to(&mut self, address: u8) {
+        events::emit(Event::JumpTaken {
+            from: self.program_counter as u8,
+            to: address,
+        });
+        self.record.jump = Some(address);
+        self.jump_instruction = true; // Set the jump instruction flag
+        self.program_counter = address as usize;
+    }
+
+    /// Reads the instruction at the PC from memory, so stores into code take
+    /// effect. An instruction at FF takes its second byte from 00.
     fn fetch(&mut self) {
-        // println!("Fetching");
-        let high: u16 = self.assembled_code[self.program_counter] as u16;
-        let low: u16 = self.assembled_code[self.program_counter + 1] as u16;
+        let memory: &[u8; 256] = &self.bus.memory;
+        let high: u16 = memory[self.program_counter] as u16;
+        let low: u16 = memory[(self.program_counter + 1) % memory.len()] as u16;
         self.cir = (high << 8) | low;
     }
-    fn decode(&mut self) {
-        // let ef: ef = emulator_functions::EmulatorFunctions {};
+    fn decode(&mut self) -> Result<(), TrapReason> {
         let nibble: u8 = self.ef.get_nibble(self.cir, 0); // Get the first 4 bits
-        println!(
-            "Decoded instruction: {:04X}, Nibble: {:02X}",
-            self.cir, nibble
-        );
-
-        println!("Executing instruction with nibble: {:04X}", nibble);
 
         match nibble {
-            0x00 => self.nop(),                      // NOP WORKS
-            0x01 => self.load_from_memory_direct(),  // Working
-            0x02 => self.load_value_into_register(), // LOAD VALUE TO REGISTER WORKS
-            0x03 => self.store_to_memory(),          // Working
-            0x04 => self.move_register_value(),      // Working
-            0x05 | 0x06 | 0x07 | 0x08 | 0x09 | 0x0A => self.register_instruction(nibble), // Working
-            0x0B => self.jump_equal(),               // Working
-            0x0C => self.halt(),                     // HALT WORKS
-            0x0D => self.load_from_memory(),         // Working
-            0x0E => self.store_in_memory(),          // Working
-            0x0F => self.jump_unconditional_or_with_test(), // Working
-            _ => panic!(
-                "Error: Invalid instruction nibble '{:01X}' in instruction {:04X}",
-                nibble, self.cir
-            ),
+            0x00 => self.nop(),                                    // NOP WORKS
+            0x01 => self.load_from_memory_direct(),                // Working
+            0x02 => self.load_value_into_register(),               // LOAD VALUE TO REGISTER WORKS
+            0x03 => self.store_to_memory(),                        // Working
+            0x04 => self.move_register_value(),                    // Working
+            0x05..=0x0A => self.register_instruction(nibble),      // Working
+            0x0B => self.jump_equal(),                             // Working
+            0x0C => self.halt(),                                   // HALT WORKS
+            0x0D => self.load_from_memory(),                       // Working
+            0x0E => self.store_in_memory(),                        // Working
+            0x0F => return self.jump_unconditional_or_with_test(), // Working
+            _ => return Err(TrapReason::InvalidOpcode(nibble)),
         }
+        Ok(())
     }
 
-    fn nop(&self) {
-        println!("No Operation (NOP)");
-    }
+    fn nop(&self) {}
 
     fn load_from_memory_direct(&mut self) {
-        println!("Loading from memory directly");
         let register_address: u8 = self.ef.get_nibble(self.cir, 1);
         let memory_address: u8 = self.ef.get_byte(self.cir, 1);
-        let memory_address_value: u8 = self.memory[memory_address as usize];
+        let memory_address_value: u8 = self.read_memory(memory_address);
 
-        self.register_values[register_address as usize] = memory_address_value;
+        self.write_register(register_address, memory_address_value);
     }
 
     fn load_value_into_register(&mut self) {
-        println!("Loading value into register");
         let register_address: u8 = self.ef.get_nibble(self.cir, 1);
         let value: u8 = self.ef.get_byte(self.cir, 1); // Get the first byte (high nibble)
 
-        self.register_values[register_address as usize] = value;
-        println!(
-            "Loaded value {:02X} into register {}",
-            value, register_address
-        );
+        self.write_register(register_address, value);
     }
 
     fn store_to_memory(&mut self) {
-        println!("Storing value to memory - Getting");
         let register_address: u8 = self.ef.get_nibble(self.cir, 1);
         let memory_address: u8 = self.ef.get_byte(self.cir, 1);
         let register_value: u8 = self.register_values[register_address as usize];
-        self.memory[memory_address as usize] = register_value;
+        self.write_memory(memory_address, register_value);
     }
 
     fn move_register_value(&mut self) {
-        println!("Moving register value");
         let register_r: u8 = self.ef.get_nibble(self.cir, 2);
         let register_s: u8 = self.ef.get_nibble(self.cir, 3);
         let register_r_value: u8 = self.register_values[register_r as usize];
 
-        self.register_values[register_s as usize] = register_r_value;
+        self.write_register(register_s, register_r_value);
     }
 
     fn register_instruction(&mut self, nibble: u8) {
-        println!("Register operation with nibble: {:02X}", nibble);
         let reg_a: u8 = self.ef.get_nibble(self.cir, 2);
         let reg_b: u8 = self.ef.get_nibble(self.cir, 3);
         let reg_a_value: u8 = self.register_values[reg_a as usize];
@@ -160,123 +604,82 @@ impl Emulator {
 
         match nibble {
             0x05 => {
-                println!("Adding integer values: {} + {}", reg_a_value, reg_b_value);
-                self.register_values[storage_register as usize] =
-                    reg_a_value.wrapping_add(reg_b_value);
+                self.write_register(storage_register, reg_a_value.wrapping_add(reg_b_value));
             }
             0x06 => {
-                println!(
-                    "Adding floating point values: {} + {}",
-                    reg_a_value, reg_b_value
-                );
+                let (sum, precision) = float8::add(reg_a_value, reg_b_value);
+                if precision != Precision::Exact {
+                    events::emit(Event::FloatPrecision {
+                        pc: self.program_counter as u8,
+                        precision,
+                    });
+                }
+                self.write_register(storage_register, sum);
             }
             0x07 => {
-                println!("OR operation: {} | {}", reg_a_value, reg_b_value);
-                self.register_values[storage_register as usize] = reg_a_value | reg_b_value;
+                self.write_register(storage_register, reg_a_value | reg_b_value);
             }
             0x08 => {
-                println!("AND operation: {} & {}", reg_a_value, reg_b_value);
-                self.register_values[storage_register as usize] = reg_a_value & reg_b_value;
+                self.write_register(storage_register, reg_a_value & reg_b_value);
             }
             0x09 => {
-                println!("XOR operation: {} ^ {}", reg_a_value, reg_b_value);
-                self.register_values[storage_register as usize] = reg_a_value ^ reg_b_value;
+                self.write_register(storage_register, reg_a_value ^ reg_b_value);
             }
             0x0A => {
                 let target_reg: u8 = storage_register;
                 let rot_amount: u8 = self.ef.get_nibble(self.cir, 3); // ensures 0–7
                 let data: u8 = self.register_values[target_reg as usize];
                 let rotated: u8 = data.rotate_right(rot_amount.into());
-                self.register_values[target_reg as usize] = rotated;
-                println!(
-                    "Rotating register {} by {} bits: {:02X} -> {:02X}",
-                    target_reg, rot_amount, data, rotated
-                );
+                self.write_register(target_reg, rotated);
             }
-            _ => panic!(
-                "Error: Invalid register operation nibble '{:01X}' in instruction {:04X}",
-                nibble, self.cir
-            ),
+            // decode only sends opcodes 5 to A here
+            _ => unreachable!("register_instruction called for opcode {:X}", nibble),
         }
     }
 
     fn jump_equal(&mut self) {
-        println!("Jump if equal operation");
         let register_r_address: u8 = self.ef.get_nibble(self.cir, 1);
         let memory_location: u8 = self.ef.get_byte(self.cir, 1);
 
-        println!(
-            "Jumping to memory address: {:02X} if register {} is equal to 0",
-            memory_location, register_r_address
-        );
-
-        if register_r_address == 0 {
-            println!(
-                "{:05}    Jumping to memory address: {:02X}",
-                1, memory_location
-            );
-            self.jump_instruction = true; // Set the jump instruction flag
-
-            self.program_counter = memory_location as usize;
-        } else if register_r_address > 0 && register_r_address < 16 {
-            println!(
-                "{:05}     Jumping to memory address: {:04X} if register {} is equal to 0",
-                1, memory_location, register_r_address
-            );
-            let register_0_value: u8 = self.register_values[0];
-            let register_r_value: u8 = self.register_values[register_r_address as usize];
-            println!(
-                "Register 0 value: {:02X}, Register {} value: {:02X}",
-                register_0_value, register_r_address, register_r_value
-            );
-            if register_r_value == register_0_value {
-                println!(
-                    "Jump condition met, jumping to memory address: {:02X}",
-                    memory_location
-                );
-                self.jump_instruction = true; // Set the jump instruction flag
-
-                self.program_counter = memory_location as usize;
-            }
-        } else {
-            panic!("Error: Invalid register address for jump operation");
+        let register_0_value: u8 = self.register_values[0];
+        let register_r_value: u8 = self.register_values[register_r_address as usize];
+        // With R0 as the register, the test always passes: an unconditional jump
+        if register_r_value == register_0_value {
+            self.jump_to(memory_location);
         }
-
-        return;
     }
 
     fn halt(&mut self) {
         self.halted = true;
-        println!("Halting the emulator");
+        events::emit(Event::Halted {
+            pc: self.program_counter as u8,
+        });
     }
 
     fn load_from_memory(&mut self) {
-        println!("Loading from memory");
         let register_saving_address: u8 = self.ef.get_nibble(self.cir, 2);
         let memory_address_in_register: u8 = self.ef.get_nibble(self.cir, 3);
         let memory_address: u8 = self.register_values[memory_address_in_register as usize];
 
-        let memory_value: u8 = self.memory[memory_address as usize];
-        self.register_values[register_saving_address as usize] = memory_value;
+        let memory_value: u8 = self.read_memory(memory_address);
+        self.write_register(register_saving_address, memory_value);
     }
 
     fn store_in_memory(&mut self) {
-        println!("Storing in memory");
         let register_address: u8 = self.ef.get_nibble(self.cir, 2);
         let register_value: u8 = self.register_values[register_address as usize];
         let memory_address_in_registry: u8 = self.ef.get_nibble(self.cir, 3);
         let memory_address: u8 = self.register_values[memory_address_in_registry as usize];
-        self.memory[memory_address as usize] = register_value;
+        self.write_memory(memory_address, register_value);
     }
 
-    fn jump_unconditional_or_with_test(&mut self) {
+    fn jump_unconditional_or_with_test(&mut self) -> Result<(), TrapReason> {
         // JMPEQ - Working
         // JMPNE - Working
         // JMPGE - Working
         // JMPGT - Working
         // JMPLE - Working
         // JMPLT - Working
-        println!("Jump unconditional or with test operation");
         let register_address: u8 = self.ef.get_nibble(self.cir, 1);
         let register_value: u8 = self.register_values[register_address as usize];
         let which_test: u8 = self.ef.get_nibble(self.cir, 2);
@@ -284,24 +687,201 @@ impl Emulator {
         let memory_address: u8 = self.register_values[memory_address_stored_in_register as usize];
         let register_value_at_0: u8 = self.register_values[0];
 
-        println!("Register value at 0: {:02X}", register_value_at_0);
-        println!(
-            "Register value at {}: {:02X}",
-            register_address, register_value
+        let do_the_jump: bool = self
+            .ef
+            .jump_with_test(which_test, register_value_at_0, register_value)
+            .ok_or(TrapReason::InvalidJumpTest(which_test))?;
+
+        if do_the_jump {
+            self.jump_to(memory_address);
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::devices::Timer;
+
+    #[test]
+    fn test_stores_into_code_take_effect() {
+        // MOV C0 -> R1; MOV R1 -> [08]; MOV 1 -> R2; NOP; MOV 5 -> R2 (becomes HALT)
+        let mut emulator: Emulator = Emulator::new(vec![
+            0x21, 0xC0, 0x31, 0x08, 0x22, 0x01, 0x0F, 0xFF, 0x22, 0x05,
+        ]);
+        emulator.run().unwrap();
+        assert!(emulator.is_halted());
+        assert_eq!(emulator.registers()[2], 0x01);
+    }
+
+    #[test]
+    fn test_code_above_the_image_runs() {
+        // MOV C0 -> R1; MOV R1 -> [F0]; JMP F0
+        let mut emulator: Emulator = Emulator::new(vec![0x21, 0xC0, 0x31, 0xF0, 0xB0, 0xF0]);
+        emulator.run().unwrap();
+        assert!(emulator.is_halted());
+        assert_eq!(emulator.steps(), 4);
+    }
+
+    #[test]
+    fn test_program_counter_wraps_around() {
+        let mut image: Vec<u8> = vec![0x0F; 256];
+        image[..8].copy_from_slice(&[0x20, 0x07, 0xB1, 0x06, 0xB0, 0xFE, 0xC0, 0x00]);
+        image[0xFE..].copy_from_slice(&[0x21, 0x07]);
+        let mut emulator: Emulator = Emulator::new(image);
+        emulator.run().unwrap();
+        assert!(emulator.is_halted());
+        assert_eq!(emulator.registers()[1], 0x07);
+        assert_eq!(emulator.steps(), 7);
+    }
+
+    #[test]
+    fn test_step_limit_stops_a_program_without_halt() {
+        let mut emulator: Emulator = Emulator::new(Vec::new());
+        emulator.set_step_limit(Some(300));
+        // Memory full of NOPs repeats its state, which would be caught first
+        emulator.set_loop_detection(false);
+        assert_eq!(emulator.run().unwrap(), Stop::StepLimit);
+        assert!(!emulator.is_halted());
+        assert!(!emulator.is_running());
+        assert_eq!(emulator.steps(), 300);
+    }
+
+    #[test]
+    fn test_trap_policies() {
+        // NOP; JMP with the nonexistent test 6; HALT; MOV 9 -> R2; HALT
+        let image: Vec<u8> = vec![0x0F, 0xFF, 0xF1, 0x62, 0xC0, 0x00, 0x22, 0x09, 0xC0, 0x00];
+
+        let mut emulator: Emulator = Emulator::new(image.clone());
+        let error: EmulatorError = emulator.run().unwrap_err();
+        assert_eq!(
+            error,
+            EmulatorError {
+                pc: 0x02,
+                instruction: 0xF162,
+                reason: TrapReason::InvalidJumpTest(6),
+            }
+        );
+        assert_eq!(
+            error.to_string(),
+            "Error: Trap at 02 in instruction F162: invalid jump test 6."
         );
+        assert!(!emulator.is_running());
+
+        let mut emulator: Emulator = Emulator::new(image.clone());
+        emulator.set_trap_policy(TrapPolicy::Ignore);
+        emulator.run().unwrap();
+        assert_eq!(emulator.steps(), 3);
+        assert_eq!(emulator.registers()[2], 0x00);
+
+        let mut emulator: Emulator = Emulator::new(image);
+        emulator.set_trap_policy(TrapPolicy::parse("jump:06").unwrap());
+        emulator.run().unwrap();
+        assert_eq!(emulator.registers()[2], 0x09);
+        assert_eq!(emulator.registers()[0xF], 0x02);
+    }
 
-        let do_the_jump: bool =
-            self.ef
-                .jump_with_test(which_test, register_value_at_0, register_value);
+    #[test]
+    fn test_step_records_and_incremental_runs() {
+        // MOV 80 -> R1; MOV R1 -> [80]; JMP 06; HALT
+        let mut emulator: Emulator =
+            Emulator::new(vec![0x21, 0x80, 0x31, 0x80, 0xB0, 0x06, 0xC0, 0x00]);
+
+        let records: Vec<StepRecord> = emulator.run_for(2).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(
+            records[0].register_writes,
+            vec![RegisterWrite {
+                register: 1,
+                old: 0,
+                new: 0x80
+            }]
+        );
+        assert_eq!(
+            records[1].memory_writes,
+            vec![MemoryWrite {
+                address: 0x80,
+                old: 0,
+                new: 0x80
+            }]
+        );
+        assert_eq!(
+            records[1].decoded,
+            Some(Instruction::StoreDirect {
+                register: 1,
+                address: 0x80
+            })
+        );
 
-        if do_the_jump {
-            self.jump_instruction = true; // Set the jump instruction flag
-            self.program_counter = memory_address as usize;
-        } else {
-            println!(
-                "Jump condition not met, not jumping to memory address: {:02X}",
-                memory_address
-            );
+        let record: StepRecord = emulator.step().unwrap();
+        assert_eq!(
+            (record.pc, record.instruction, record.jump),
+            (0x04, 0xB006, Some(0x06))
+        );
+        assert_eq!(emulator.program_counter(), 0x06);
+
+        assert!(!emulator
+            .run_until(|_, record| record.jump.is_some())
+            .unwrap());
+        assert!(emulator.is_halted());
+    }
+
+    #[test]
+    fn test_undo() {
+        // MOV 80 -> R1; MOV R1 -> [80]; HALT
+        let program: Vec<u8> = vec![0x21, 0x80, 0x31, 0x80, 0xC0, 0x00];
+        let mut emulator: Emulator = Emulator::new(program.clone());
+        let records: Vec<StepRecord> = emulator.run_for(3).unwrap();
+        for record in records.iter().rev() {
+            emulator.undo(record).unwrap();
         }
+        assert_eq!(emulator.program_counter(), 0x00);
+        assert_eq!(emulator.registers()[1], 0x00);
+        assert_eq!(emulator.memory()[0x80], 0x00);
+
+        // A device's state is not recorded, so stepping back is refused
+        let mut emulator: Emulator = Emulator::new(program);
+        emulator
+            .attach_device("timer@F0", 0xF0, 0xF0, Box::<Timer>::default())
+            .unwrap();
+        let record: StepRecord = emulator.step().unwrap();
+        let before: Snapshot = emulator.snapshot();
+        assert!(emulator.undo(&record).is_err());
+        assert_eq!(emulator.snapshot(), before);
+    }
+
+    #[test]
+    fn test_infinite_loop_is_detected() {
+        // MOV 1 -> R1; then R2 += R1 until it wraps to 0: ADDI R1, R2 -> R2; JMPEQ 08, R2; JMP 02; HALT
+        let image: Vec<u8> = vec![0x21, 0x01, 0x52, 0x12, 0xB2, 0x08, 0xB0, 0x02, 0xC0, 0x00];
+        let mut emulator: Emulator = Emulator::new(image);
+        assert_eq!(emulator.run().unwrap(), Stop::Halted);
+
+        // MOV 1 -> R1; ADDI R1, R1 -> R1; JMP 04; JMP 04
+        let image: Vec<u8> = vec![0x21, 0x01, 0x51, 0x11, 0xB0, 0x06, 0xB0, 0x04];
+        let mut emulator: Emulator = Emulator::new(image);
+        let stop: Stop = emulator.run().unwrap();
+        assert_eq!(
+            stop,
+            Stop::Loop {
+                start: 0x04,
+                end: 0x07
+            }
+        );
+        assert!(emulator.steps() < 20);
+        assert_eq!(
+            stop.to_string(),
+            "infinite loop between 04 and 07 (the machine state repeats)"
+        );
+    }
+
+    #[test]
+    fn test_timeout() {
+        let mut emulator: Emulator = Emulator::new(Vec::new());
+        emulator.set_loop_detection(false);
+        emulator.set_timeout(Some(Duration::ZERO));
+        assert_eq!(emulator.run().unwrap(), Stop::Timeout);
+        assert_eq!(emulator.steps(), 0);
     }
 }
@@ -0,0 +1,122 @@
+/// A peripheral that answers loads and stores in a range of addresses.
+pub trait Device {
+    /// A load from the device; `offset` counts from the start of its range.
+    fn read(&mut self, offset: u8) -> u8;
+
+    /// A store to the device.
+    fn write(&mut self, offset: u8, value: u8);
+
+    /// Called after every instruction, for devices that change over time.
+    fn tick(&mut self) {}
+
+    /// The device's state on one line, for snapshots.
+    fn save_state(&self) -> String {
+        String::new()
+    }
+
+    fn load_state(&mut self, _state: &str) -> Result<(), String> {
+        Ok(())
+    }
+}
+
+struct Mapping {
+    name: String,
+    start: u8,
+    end: u8,
+    device: Box<dyn Device>,
+}
+
+/// Memory with devices mapped over parts of it. Loads and stores in a mapped
+/// range reach the device instead of the memory underneath.
+pub struct Bus {
+    pub memory: [u8; 256],
+    mappings: Vec<Mapping>,
+}
+
+impl Bus {
+    pub fn new(memory: [u8; 256]) -> Self {
+        Bus {
+            memory,
+            mappings: Vec::new(),
+        }
+    }
+
+    /// Maps `device` over `start..=end`, which must not overlap another device.
+    pub fn attach(
+        &mut self,
+        name: &str,
+        start: u8,
+        end: u8,
+        device: Box<dyn Device>,
+    ) -> Result<(), String> {
+        if let Some(other) = self
+            .mappings
+            .iter()
+            .find(|other| start <= other.end && other.start <= end)
+        {
+            return Err(format!(
+                "Error: Device {} at {:02X}-{:02X} overlaps {} at {:02X}-{:02X}.",
+                name, start, end, other.name, other.start, other.end
+            ));
+        }
+        self.mappings.push(Mapping {
+            name: name.to_string(),
+            start,
+            end,
+            device,
+        });
+        Ok(())
+    }
+
+    pub fn has_devices(&self) -> bool {
+        !self.mappings.is_empty()
+    }
+
+    fn mapping_at(&mut self, address: u8) -> Option<&mut Mapping> {
+        self.mappings
+            .iter_mut()
+            .find(|mapping| (mapping.start..=mapping.end).contains(&address))
+    }
+
+    pub fn read(&mut self, address: u8) -> u8 {
+        match self.mapping_at(address) {
+            Some(mapping) => mapping.device.read(address - mapping.start),
+            None => self.memory[address as usize],
+        }
+    }
+
+    pub fn write(&mut self, address: u8, value: u8) {
+        match self.mapping_at(address) {
+            Some(mapping) => mapping.device.write(address - mapping.start, value),
+            None => self.memory[address as usize] = value,
+        }
+    }
+
+    pub fn tick(&mut self) {
+        for mapping in &mut self.mappings {
+            mapping.device.tick();
+        }
+    }
+
+    /// Each device's name and saved state.
+    pub fn save_states(&self) -> Vec<(String, String)> {
+        self.mappings
+            .iter()
+            .map(|mapping| (mapping.name.clone(), mapping.device.save_state()))
+            .collect()
+    }
+
+    pub fn load_state(&mut self, name: &str, state: &str) -> Result<(), String> {
+        let mapping: &mut Mapping = self
+            .mappings
+            .iter_mut()
+            .find(|mapping| mapping.name == name)
+            .ok_or_else(|| {
+                format!(
+                    "Error: The snapshot has state for device {}, which is not attached.",
+                    name
+                )
+            })?;
+        mapping.device.load_state(state)
+    }
+}
@@ -19,28 +19,28 @@ impl EmulatorFunctions {
         }
 
         if index == 0 {
-            return (cir >> 8) as u8; // Extract the high byte
+            (cir >> 8) as u8 // Extract the high byte
         } else {
-            return (cir & 0xFF) as u8; // Extract the low byte
+            (cir & 0xFF) as u8 // Extract the low byte
         }
     }
 
+    /// The outcome of conditional jump test `jump_command`, or `None` if the
+    /// machine has no such test.
     pub fn jump_with_test(
         &self,
         jump_command: u8,
         register_value_at_0: u8,
         register_value_at_r: u8,
-    ) -> bool {
+    ) -> Option<bool> {
         match jump_command {
-            0 => register_value_at_r == register_value_at_0, // EQ
-            1 => register_value_at_r != register_value_at_0, // NE
-            2 => register_value_at_r >= register_value_at_0, // LT
-            3 => register_value_at_r <= register_value_at_0, // GT
-            4 => register_value_at_r > register_value_at_0,  // LE
-            5 => register_value_at_r < register_value_at_0,  // GE
-            _ => {
-                panic!("Error: Invalid jump command");
-            }
+            0 => Some(register_value_at_r == register_value_at_0), // EQ
+            1 => Some(register_value_at_r != register_value_at_0), // NE
+            2 => Some(register_value_at_r >= register_value_at_0), // LT
+            3 => Some(register_value_at_r <= register_value_at_0), // GT
+            4 => Some(register_value_at_r > register_value_at_0),  // LE
+            5 => Some(register_value_at_r < register_value_at_0),  // GE
+            _ => None,
         }
     }
 }
@@ -0,0 +1,219 @@
+use once_cell::sync::Lazy;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+
+use crate::float8::Precision;
+use crate::json;
+use crate::source_map::SourceMap;
+
+/// Something that happened while assembling or running a program.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Event {
+    /// An assembler phase finished; `detail` describes its output.
+    AssemblerPhase {
+        phase: &'static str,
+        detail: String,
+    },
+    /// The instruction at `pc` was fetched and is about to execute.
+    InstructionExecuted {
+        pc: u8,
+        instruction: u16,
+    },
+    RegisterWrite {
+        register: u8,
+        old: u8,
+        new: u8,
+    },
+    MemoryWrite {
+        address: u8,
+        old: u8,
+        new: u8,
+    },
+    JumpTaken {
+        from: u8,
+        to: u8,
+    },
+    Halted {
+        pc: u8,
+    },
+    /// The instruction at `pc` could not be executed.
+    Trap {
+        pc: u8,
+        instruction: u16,
+        message: String,
+    },
+    /// A floating-point result was truncated or overflowed.
+    FloatPrecision {
+        pc: u8,
+        precision: Precision,
+    },
+}
+
+/// Observer for assembler and emulator events.
+pub trait EventSink: Send {
+    fn event(&mut self, event: &Event);
+}
+
+/// Discards every event. This is the default sink.
+pub struct QuietSink;
+
+impl EventSink for QuietSink {
+    fn event(&mut self, _event: &Event) {}
+}
+
+/// Prints one readable line per event to stdout, showing where each
+/// executed instruction came from when a source map is available.
+#[derive(Default)]
+pub struct HumanSink {
+    pub source_map: Option<SourceMap>,
+}
+
+impl EventSink for HumanSink {
+    fn event(&mut self, event: &Event) {
+        match event {
+            Event::AssemblerPhase { phase, detail } => println!("[assembler:{}] {}", phase, detail),
+            Event::InstructionExecuted { pc, instruction } => {
+                match self.source_map.as_ref().and_then(|map| map.lookup(*pc)) {
+                    Some(location) => println!("{:02X}: {:04X}  {}", pc, instruction, location),
+                    None => println!("{:02X}: {:04X}", pc, instruction),
+                }
+            }
+            Event::RegisterWrite { register, old, new } => {
+                println!("      R{:X} <- {:02X} (was {:02X})", register, new, old)
+            }
+            Event::MemoryWrite { address, old, new } => {
+                println!("      [{:02X}] <- {:02X} (was {:02X})", address, new, old)
+            }
+            Event::JumpTaken { from, to } => println!("      jump {:02X} -> {:02X}", from, to),
+            Event::Halted { pc } => println!("Halted at {:02X}", pc),
+            Event::Trap { message, .. } => println!("      {}", message),
+            Event::FloatPrecision { pc, precision } => {
+                println!("      ADDF at {:02X}: {}", pc, precision)
+            }
+        }
+    }
+}
+
+/// Prints one JSON object per event to stdout.
+pub struct JsonSink;
+
+impl EventSink for JsonSink {
+    fn event(&mut self, event: &Event) {
+        println!("{}", event_to_json(event));
+    }
+}
+
+pub fn event_to_json(event: &Event) -> String {
+    match event {
+        Event::AssemblerPhase { phase, detail } => format!(
+            "{{\"event\":\"assembler_phase\",\"phase\":\"{}\",\"detail\":\"{}\"}}",
+            phase,
+            json::escape(detail)
+        ),
+        Event::InstructionExecuted { pc, instruction } => format!(
+            "{{\"event\":\"instruction\",\"pc\":{},\"instruction\":{}}}",
+            pc, instruction
+        ),
+        Event::RegisterWrite { register, old, new } => format!(
+            "{{\"event\":\"register_write\",\"register\":{},\"old\":{},\"new\":{}}}",
+            register, old, new
+        ),
+        Event::MemoryWrite { address, old, new } => format!(
+            "{{\"event\":\"memory_write\",\"address\":{},\"old\":{},\"new\":{}}}",
+            address, old, new
+        ),
+        Event::JumpTaken { from, to } => {
+            format!("{{\"event\":\"jump\",\"from\":{},\"to\":{}}}", from, to)
+        }
+        Event::Halted { pc } => format!("{{\"event\":\"halted\",\"pc\":{}}}", pc),
+        Event::Trap {
+            pc,
+            instruction,
+            message,
+        } => format!(
+            "{{\"event\":\"trap\",\"pc\":{},\"instruction\":{},\"message\":\"{}\"}}",
+            pc,
+            instruction,
+            json::escape(message)
+        ),
+        Event::FloatPrecision { pc, precision } => format!(
+            "{{\"event\":\"float_precision\",\"pc\":{},\"precision\":\"{}\"}}",
+            pc, precision
+        ),
+    }
+}
+
+static EVENT_SINK: Lazy<Mutex<Box<dyn EventSink>>> = Lazy::new(|| Mutex::new(Box::new(QuietSink)));
+
+// Lets `emit` skip the lock entirely while the quiet sink is installed.
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// Replaces the global sink. Passing `None` restores the quiet default.
+pub fn set_sink(sink: Option<Box<dyn EventSink>>) {
+    let mut current = EVENT_SINK.lock().unwrap();
+    ENABLED.store(sink.is_some(), Ordering::Relaxed);
+    *current = sink.unwrap_or_else(|| Box::new(QuietSink));
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+pub fn emit(event: Event) {
+    if enabled() {
+        EVENT_SINK.lock().unwrap().event(&event);
+    }
+}
+
+/// Emits an assembler phase event, only formatting `detail` when a sink is listening.
+pub fn phase<F: FnOnce() -> String>(phase: &'static str, detail: F) {
+    if enabled() {
+        emit(Event::AssemblerPhase {
+            phase,
+            detail: detail(),
+        });
+    }
+}
+
+/// Builds a sink from a `--log` value.
+pub fn sink_from_name(name: &str) -> Result<Option<Box<dyn EventSink>>, String> {
+    match name {
+        "quiet" => Ok(None),
+        "human" => Ok(Some(Box::new(HumanSink::default()))),
+        "json" => Ok(Some(Box::new(JsonSink))),
+        _ => Err(format!(
+            "Error: Unknown log format '{}'. Expected quiet, human or json.",
+            name
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_register_write_json() {
+        let event = Event::RegisterWrite {
+            register: 3,
+            old: 0,
+            new: 0x1F,
+        };
+        assert_eq!(
+            event_to_json(&event),
+            "{\"event\":\"register_write\",\"register\":3,\"old\":0,\"new\":31}"
+        );
+    }
+
+    #[test]
+    fn test_phase_detail_is_escaped() {
+        let event = Event::AssemblerPhase {
+            phase: "read",
+            detail: "[\"MOV 1 -> R0\"]".to_string(),
+        };
+        assert_eq!(
+            event_to_json(&event),
+            "{\"event\":\"assembler_phase\",\"phase\":\"read\",\"detail\":\"[\\\"MOV 1 -> R0\\\"]\"}"
+        );
+    }
+}
@@ -0,0 +1,148 @@
+use std::fmt;
+
+// The textbook 8-bit floating-point format: a sign bit, a 3-bit exponent in
+// excess-4 and a 4-bit mantissa read as a binary fraction, so `s eee mmmm`
+// is (-1)^s × 0.mmmm × 2^(eee - 4). Normalised values have the top mantissa
+// bit set; the largest magnitude is 7.5 and the smallest is 1/32.
+const EXPONENT_BIAS: i32 = 4;
+const LARGEST: u8 = 0x7F;
+
+/// How far an encoded value is from the one asked for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Precision {
+    Exact,
+    /// Mantissa bits were dropped, rounding towards zero.
+    Truncated,
+    /// The value was too large and was clamped to ±7.5.
+    Overflow,
+}
+
+impl fmt::Display for Precision {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Precision::Exact => write!(f, "exact"),
+            Precision::Truncated => write!(f, "truncated"),
+            Precision::Overflow => write!(f, "overflow"),
+        }
+    }
+}
+
+pub fn decode(bits: u8) -> f64 {
+    let sign: f64 = if bits & 0x80 != 0 { -1.0 } else { 1.0 };
+    let exponent: i32 = ((bits >> 4) & 0x07) as i32 - EXPONENT_BIAS;
+    let mantissa: f64 = (bits & 0x0F) as f64 / 16.0;
+    sign * mantissa * 2f64.powi(exponent)
+}
+
+/// The normalised encoding of `value`, truncating extra mantissa bits.
+pub fn encode(value: f64) -> (u8, Precision) {
+    let sign: u8 = if value < 0.0 { 0x80 } else { 0x00 };
+    let magnitude: f64 = value.abs();
+    if magnitude == 0.0 {
+        return (0x00, Precision::Exact);
+    }
+
+    // Find the exponent that puts the magnitude in [0.5, 1)
+    let mut exponent: i32 = 0;
+    let mut fraction: f64 = magnitude;
+    while fraction >= 1.0 {
+        fraction /= 2.0;
+        exponent += 1;
+    }
+    while fraction < 0.5 {
+        fraction *= 2.0;
+        exponent -= 1;
+    }
+
+    if exponent > 7 - EXPONENT_BIAS {
+        return (sign | LARGEST, Precision::Overflow);
+    }
+    if exponent < -EXPONENT_BIAS {
+        return (0x00, Precision::Truncated);
+    }
+
+    let scaled: f64 = fraction * 16.0;
+    let mantissa: u8 = scaled.floor() as u8;
+    let precision: Precision = if scaled == scaled.floor() {
+        Precision::Exact
+    } else {
+        Precision::Truncated
+    };
+    let biased: u8 = (exponent + EXPONENT_BIAS) as u8;
+    (sign | (biased << 4) | mantissa, precision)
+}
+
+/// ADDF: adds two encoded values and encodes the sum.
+pub fn add(a: u8, b: u8) -> (u8, Precision) {
+    // Every encoding is a multiple of 1/256 (0.0001 × 2^-4), so the f64 sum is exact
+    encode(decode(a) + decode(b))
+}
+
+/// A float DATA literal such as `2.5` or `-0.375`, which must be exactly
+/// representable.
+pub fn parse_literal(text: &str) -> Result<u8, String> {
+    let value: f64 = text
+        .parse()
+        .map_err(|_| format!("Error: Invalid floating-point value '{}'", text))?;
+    match encode(value) {
+        (bits, Precision::Exact) => Ok(bits),
+        (bits, Precision::Truncated) => Err(format!(
+            "Error: {} cannot be stored exactly in 8-bit floating point; the nearest value towards zero is {}.",
+            text,
+            decode(bits)
+        )),
+        (_, Precision::Overflow) => Err(format!(
+            "Error: {} is out of range for 8-bit floating point (largest magnitude is 7.5).",
+            text
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_textbook_encodings() {
+        // 2 5/8 needs five mantissa bits, so the last one is lost
+        assert_eq!(encode(2.625), (0x6A, Precision::Truncated));
+        assert_eq!(encode(-2.0), (0xE8, Precision::Exact));
+        assert_eq!(encode(0.375), (0x3C, Precision::Exact));
+        assert_eq!(decode(0x6A), 2.5);
+        assert_eq!(decode(0x3C), 0.375);
+        assert_eq!(encode(8.0), (0x7F, Precision::Overflow));
+        assert_eq!(encode(-0.01), (0x00, Precision::Truncated));
+    }
+
+    #[test]
+    fn test_add_normalises_and_reports_loss() {
+        assert_eq!(add(0xE8, 0x78), (0x68, Precision::Exact)); // -2 + 4
+        assert_eq!(add(0x68, 0x31), (0x68, Precision::Truncated)); // 2 + 1/32
+        assert_eq!(add(0x7F, 0x7F), (0x7F, Precision::Overflow)); // 7.5 + 7.5
+        assert_eq!(add(0x48, 0xC8), (0x00, Precision::Exact)); // 0.5 - 0.5
+    }
+
+    #[test]
+    fn test_literals_must_be_exact() {
+        assert_eq!(parse_literal("-0.375"), Ok(0xBC));
+        assert!(parse_literal("0.1")
+            .unwrap_err()
+            .contains("cannot be stored exactly"));
+        assert!(parse_literal("9.0").unwrap_err().contains("out of range"));
+    }
+
+    #[test]
+    fn test_addf_with_float_data() {
+        let source: &str = "        MOV [a] -> R1
+        MOV [b] -> R2
+        ADDF R1, R2 -> R3
+        HALT
+a:      DATA -1.5
+b:      DATA 0.25
+";
+        let program = crate::program::Program::assemble_source("test.nha", source).unwrap();
+        let mut emulator = crate::emulator2::Emulator::new(program.bytes);
+        emulator.run().unwrap();
+        assert_eq!(decode(emulator.registers()[3]), -1.25);
+    }
+}
@@ -0,0 +1,185 @@
+use std::collections::HashSet;
+
+use crate::assembler2::{grab_memory_parts, tokenize};
+use crate::assembler_cleaner::{split_comment, split_label};
+
+// House style, as in test1.nha: a one-space margin, then label, mnemonic and
+// operand columns, with trailing comments lined up after the operands. DATA
+// operands are short, so their comments sit closer.
+const LABEL_WIDTH: usize = 13;
+const MNEMONIC_WIDTH: usize = 8;
+const OPERAND_WIDTH: usize = 16;
+const DATA_OPERAND_WIDTH: usize = 12;
+
+/// Rewrites a `.nha` source file into the canonical layout.
+pub fn format_source(source: &str) -> String {
+    let labels: HashSet<String> = source
+        .lines()
+        .filter_map(|line| split_label(split_comment(line).0).0)
+        .map(|label| label.to_string())
+        .collect();
+
+    let mut formatted: String = String::new();
+    let mut previous_blank: bool = true;
+
+    for line in source.lines() {
+        let line: String = format_line(line, &labels);
+        // Collapse runs of blank lines and drop any at the start of the file
+        if line.is_empty() {
+            if previous_blank {
+                continue;
+            }
+            previous_blank = true;
+        } else {
+            previous_blank = false;
+        }
+        formatted.push_str(&line);
+        formatted.push('\n');
+    }
+
+    while formatted.ends_with("\n\n") {
+        formatted.pop();
+    }
+    // A file that ends without a line break keeps it that way
+    if !source.ends_with('\n') {
+        formatted.pop();
+    }
+    formatted
+}
+
+fn format_line(line: &str, labels: &HashSet<String>) -> String {
+    let (code, comment) = split_comment(line);
+    let (label, statement) = split_label(code);
+
+    if label.is_none() && statement.is_empty() {
+        return match comment {
+            Some(comment) => format!(" {}", comment.trim_end()),
+            None => String::new(),
+        };
+    }
+
+    let mut formatted: String = match label {
+        Some(label) => pad(&format!(" {}: ", label), LABEL_WIDTH),
+        None => " ".repeat(LABEL_WIDTH),
+    };
+
+    let mut operand_width: usize = OPERAND_WIDTH;
+    if !statement.is_empty() {
+        let (mnemonic, operands) = match statement.split_once(char::is_whitespace) {
+            Some((mnemonic, operands)) => (mnemonic, operands.trim()),
+            None => (statement, ""),
+        };
+        let mnemonic: String = mnemonic.to_uppercase();
+        if mnemonic == "DATA" {
+            operand_width = DATA_OPERAND_WIDTH;
+        }
+        let operands: String = if operands.contains('\'') || operands.contains('"') {
+            // Quoted DATA keeps its spelling exactly
+            operands.to_string()
+        } else {
+            join_operands(operands, labels)
+        };
+
+        if operands.is_empty() {
+            formatted.push_str(&mnemonic);
+        } else {
+            formatted.push_str(&pad(&format!("{} ", mnemonic), MNEMONIC_WIDTH));
+            formatted.push_str(&operands);
+        }
+    }
+
+    if let Some(comment) = comment {
+        let comment_column: usize = LABEL_WIDTH + MNEMONIC_WIDTH + operand_width;
+        let width: usize = formatted.chars().count();
+        if width + 2 <= comment_column {
+            formatted = pad(&formatted, comment_column);
+        } else {
+            formatted.push_str("  ");
+        }
+        formatted.push_str(comment.trim_end());
+    }
+
+    formatted.trim_end().to_string()
+}
+
+fn join_operands(operands: &str, labels: &HashSet<String>) -> String {
+    let mut joined: String = String::new();
+
+    for token in tokenize(operands) {
+        for part in grab_memory_parts(&token) {
+            match part.as_str() {
+                "," => joined.push(','),
+                "->" => joined.push_str(" ->"),
+                "]" => joined.push(']'),
+                _ => {
+                    if !joined.is_empty() && !joined.ends_with('[') {
+                        joined.push(' ');
+                    }
+                    joined.push_str(&normalise_case(&part, labels));
+                }
+            }
+        }
+    }
+    joined
+}
+
+/// Registers and hex numbers are written in upper case; labels keep their spelling.
+fn normalise_case(token: &str, labels: &HashSet<String>) -> String {
+    if labels.contains(token) {
+        return token.to_string();
+    }
+    let is_register: bool = token.len() == 2
+        && token.starts_with(['R', 'r'])
+        && token.chars().nth(1).is_some_and(|c| c.is_ascii_hexdigit());
+    if is_register || token.chars().all(|c| c.is_ascii_hexdigit()) {
+        token.to_uppercase()
+    } else {
+        token.to_string()
+    }
+}
+
+fn pad(text: &str, width: usize) -> String {
+    format!("{:<width$}", text, width = width)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_format_normalises_layout_and_case() {
+        let source = "loop: addi r1,r2->r1 // step\n\n\n  mov r3->[ r1 ]\nend:halt\n";
+        let expected = concat!(
+            " loop:       ADDI    R1, R2 -> R1    // step\n",
+            "\n",
+            "             MOV     R3 -> [R1]\n",
+            " end:        HALT\n",
+        );
+        assert_eq!(format_source(source), expected);
+    }
+
+    #[test]
+    fn test_format_keeps_label_spelling_and_quoted_data() {
+        let source = "   jmpeq   fa,ra\nfa:  data  'Hi, there'   //greeting\n";
+        let expected = concat!(
+            "             JMPEQ   fa, RA\n",
+            " fa:         DATA    'Hi, there'  //greeting\n",
+        );
+        assert_eq!(format_source(source), expected);
+    }
+
+    #[test]
+    fn test_format_is_idempotent() {
+        let source = std::fs::read_to_string("./test1.nha").unwrap();
+        let once = format_source(&source);
+        assert_eq!(format_source(&once), once);
+    }
+
+    #[test]
+    fn test_house_style_files_are_already_formatted() {
+        for path in ["./test1.nha", "./test1 Good.nha", "./test1 copy.nha"] {
+            let source = std::fs::read_to_string(path).unwrap();
+            assert_eq!(format_source(&source), source, "{}", path);
+        }
+    }
+}
@@ -0,0 +1,339 @@
+use std::fmt;
+
+/// Two-operand register operations, opcodes 5 to 9.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithmeticOp {
+    AddInteger,
+    AddFloat,
+    Or,
+    And,
+    Xor,
+}
+
+impl ArithmeticOp {
+    pub fn mnemonic(self) -> &'static str {
+        match self {
+            ArithmeticOp::AddInteger => "ADDI",
+            ArithmeticOp::AddFloat => "ADDF",
+            ArithmeticOp::Or => "OR",
+            ArithmeticOp::And => "AND",
+            ArithmeticOp::Xor => "XOR",
+        }
+    }
+}
+
+/// A decoded instruction word. Field names follow the emulator's reading of
+/// each nibble, so `register` is always the register the instruction writes or
+/// tests, and `address_register` holds an address.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Instruction {
+    Nop,
+    LoadDirect {
+        register: u8,
+        address: u8,
+    },
+    LoadImmediate {
+        register: u8,
+        value: u8,
+    },
+    StoreDirect {
+        register: u8,
+        address: u8,
+    },
+    Move {
+        from: u8,
+        to: u8,
+    },
+    Arithmetic {
+        op: ArithmeticOp,
+        dest: u8,
+        left: u8,
+        right: u8,
+    },
+    Rotate {
+        register: u8,
+        amount: u8,
+    },
+    /// `BRXY`: jump to XY if R equals R0; R0 itself makes it unconditional.
+    JumpEqual {
+        register: u8,
+        address: u8,
+    },
+    Halt,
+    LoadIndirect {
+        register: u8,
+        address_register: u8,
+    },
+    StoreIndirect {
+        register: u8,
+        address_register: u8,
+    },
+    /// `FRTS`: jump to the address in S if R passes test T against R0.
+    JumpRegister {
+        register: u8,
+        test: u8,
+        address_register: u8,
+    },
+}
+
+pub const JUMP_TESTS: [&str; 6] = ["JMPEQ", "JMPNE", "JMPGE", "JMPLE", "JMPGT", "JMPLT"];
+
+fn nibble(word: u16, index: usize) -> u8 {
+    ((word >> (12 - index * 4)) & 0x0F) as u8
+}
+
+impl Instruction {
+    /// Decodes an instruction word, or returns `None` if no instruction has that encoding.
+    pub fn decode(word: u16) -> Option<Instruction> {
+        let low_byte: u8 = (word & 0xFF) as u8;
+        let instruction: Instruction = match nibble(word, 0) {
+            0x0 => Instruction::Nop,
+            0x1 => Instruction::LoadDirect {
+                register: nibble(word, 1),
+                address: low_byte,
+            },
+            0x2 => Instruction::LoadImmediate {
+                register: nibble(word, 1),
+                value: low_byte,
+            },
+            0x3 => Instruction::StoreDirect {
+                register: nibble(word, 1),
+                address: low_byte,
+            },
+            0x4 => Instruction::Move {
+                from: nibble(word, 2),
+                to: nibble(word, 3),
+            },
+            opcode @ 0x5..=0x9 => Instruction::Arithmetic {
+                op: match opcode {
+                    0x5 => ArithmeticOp::AddInteger,
+                    0x6 => ArithmeticOp::AddFloat,
+                    0x7 => ArithmeticOp::Or,
+                    0x8 => ArithmeticOp::And,
+                    _ => ArithmeticOp::Xor,
+                },
+                dest: nibble(word, 1),
+                left: nibble(word, 2),
+                right: nibble(word, 3),
+            },
+            0xA => Instruction::Rotate {
+                register: nibble(word, 1),
+                amount: nibble(word, 3),
+            },
+            0xB => Instruction::JumpEqual {
+                register: nibble(word, 1),
+                address: low_byte,
+            },
+            0xC => Instruction::Halt,
+            0xD => Instruction::LoadIndirect {
+                register: nibble(word, 2),
+                address_register: nibble(word, 3),
+            },
+            0xE => Instruction::StoreIndirect {
+                register: nibble(word, 2),
+                address_register: nibble(word, 3),
+            },
+            _ => {
+                let test: u8 = nibble(word, 2);
+                if test as usize >= JUMP_TESTS.len() {
+                    return None;
+                }
+                Instruction::JumpRegister {
+                    register: nibble(word, 1),
+                    test,
+                    address_register: nibble(word, 3),
+                }
+            }
+        };
+        Some(instruction)
+    }
+
+    /// The register this instruction writes, if any.
+    pub fn written_register(self) -> Option<u8> {
+        match self {
+            Instruction::LoadDirect { register, .. }
+            | Instruction::LoadImmediate { register, .. }
+            | Instruction::Rotate { register, .. }
+            | Instruction::LoadIndirect { register, .. } => Some(register),
+            Instruction::Move { to, .. } => Some(to),
+            Instruction::Arithmetic { dest, .. } => Some(dest),
+            _ => None,
+        }
+    }
+
+    /// A short description of what the instruction does, e.g. `R1 ← R1 + R2`.
+    pub fn describe(self) -> String {
+        match self {
+            Instruction::Nop => "No operation".to_string(),
+            Instruction::LoadDirect { register, address } => {
+                format!("R{:X} ← memory[{:02X}]", register, address)
+            }
+            Instruction::LoadImmediate { register, value } => {
+                format!("R{:X} ← {:02X}", register, value)
+            }
+            Instruction::StoreDirect { register, address } => {
+                format!("memory[{:02X}] ← R{:X}", address, register)
+            }
+            Instruction::Move { from, to } => format!("R{:X} ← R{:X}", to, from),
+            Instruction::Arithmetic {
+                op,
+                dest,
+                left,
+                right,
+            } => {
+                let (operation, note): (&str, &str) = match op {
+                    ArithmeticOp::AddInteger => ("+", " (two's complement)"),
+                    ArithmeticOp::AddFloat => ("+", " (floating point)"),
+                    ArithmeticOp::Or => ("OR", ""),
+                    ArithmeticOp::And => ("AND", ""),
+                    ArithmeticOp::Xor => ("XOR", ""),
+                };
+                format!(
+                    "R{:X} ← R{:X} {} R{:X}{}",
+                    dest, left, operation, right, note
+                )
+            }
+            Instruction::Rotate { register, amount } => {
+                format!(
+                    "R{:X} ← R{:X} rotated right by {} bits",
+                    register, register, amount
+                )
+            }
+            Instruction::JumpEqual {
+                register: 0,
+                address,
+            } => format!("PC ← {:02X}", address),
+            Instruction::JumpEqual { register, address } => {
+                format!("PC ← {:02X} if R{:X} = R0", address, register)
+            }
+            Instruction::Halt => "Stop execution".to_string(),
+            Instruction::LoadIndirect {
+                register,
+                address_register,
+            } => format!("R{:X} ← memory[R{:X}]", register, address_register),
+            Instruction::StoreIndirect {
+                register,
+                address_register,
+            } => format!("memory[R{:X}] ← R{:X}", address_register, register),
+            Instruction::JumpRegister {
+                address_register, ..
+            } if self.is_unconditional_jump() => format!("PC ← R{:X}", address_register),
+            Instruction::JumpRegister {
+                register,
+                test,
+                address_register,
+            } => {
+                let comparison: &str = ["=", "≠", "≥", "≤", ">", "<"][test as usize];
+                format!(
+                    "PC ← R{:X} if R{:X} {} R0 (unsigned)",
+                    address_register, register, comparison
+                )
+            }
+        }
+    }
+
+    /// True for jumps that are always taken.
+    pub fn is_unconditional_jump(self) -> bool {
+        match self {
+            Instruction::JumpEqual { register, .. } => register == 0,
+            Instruction::JumpRegister { register, test, .. } => register == 0 && test == 0,
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for Instruction {
+    /// Writes the instruction in the syntax the assembler accepts.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match *self {
+            Instruction::Nop => write!(f, "NOP"),
+            Instruction::LoadDirect { register, address } => {
+                write!(f, "MOV [{:02X}] -> R{:X}", address, register)
+            }
+            Instruction::LoadImmediate { register, value } => {
+                write!(f, "MOV {:02X} -> R{:X}", value, register)
+            }
+            Instruction::StoreDirect { register, address } => {
+                write!(f, "MOV R{:X} -> [{:02X}]", register, address)
+            }
+            Instruction::Move { from, to } => write!(f, "MOV R{:X} -> R{:X}", from, to),
+            Instruction::Arithmetic {
+                op,
+                dest,
+                left,
+                right,
+            } => write!(
+                f,
+                "{} R{:X}, R{:X} -> R{:X}",
+                op.mnemonic(),
+                left,
+                right,
+                dest
+            ),
+            Instruction::Rotate { register, amount } => {
+                write!(f, "ROT R{:X}, {:X}", register, amount)
+            }
+            Instruction::JumpEqual {
+                register: 0,
+                address,
+            } => write!(f, "JMP {:02X}", address),
+            Instruction::JumpEqual { register, address } => {
+                write!(f, "JMPEQ {:02X}, R{:X}", address, register)
+            }
+            Instruction::Halt => write!(f, "HALT"),
+            Instruction::LoadIndirect {
+                register,
+                address_register,
+            } => write!(f, "MOV [R{:X}] -> R{:X}", address_register, register),
+            Instruction::StoreIndirect {
+                register,
+                address_register,
+            } => write!(f, "MOV R{:X} -> [R{:X}]", register, address_register),
+            Instruction::JumpRegister {
+                register: 0,
+                test: 0,
+                address_register,
+            } => write!(f, "JMP R{:X}", address_register),
+            Instruction::JumpRegister {
+                register,
+                test,
+                address_register,
+            } => write!(
+                f,
+                "{} R{:X}, R{:X}",
+                JUMP_TESTS[test as usize], address_register, register
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::assembler2::assembler;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_display_round_trips_through_the_assembler() {
+        let words: [u16; 14] = [
+            0x0FFF, 0x1A37, 0x2B0F, 0x3C42, 0x4012, 0x5C13, 0x6624, 0xA304, 0xB04A, 0xB320, 0xC000,
+            0xD042, 0xE035, 0xF413,
+        ];
+        for word in words {
+            let text: String = Instruction::decode(word).unwrap().to_string();
+            let bytes: Vec<u8> = assembler(vec![text.clone()], HashMap::new()).unwrap();
+            let reassembled: u16 = (bytes[0] as u16) << 8 | bytes[1] as u16;
+            assert_eq!(
+                Instruction::decode(reassembled),
+                Instruction::decode(word),
+                "{}",
+                text
+            );
+        }
+    }
+
+    #[test]
+    fn test_invalid_jump_test() {
+        assert_eq!(Instruction::decode(0xF160), None);
+    }
+}
@@ -0,0 +1,339 @@
+use std::fmt;
+
+/// A minimal JSON value, enough for the language server protocol.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    /// Keys keep their insertion order so output is stable.
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Builds an object from key/value pairs.
+    pub fn object<K: Into<String>>(pairs: Vec<(K, Json)>) -> Json {
+        Json::Object(pairs.into_iter().map(|(k, v)| (k.into(), v)).collect())
+    }
+
+    pub fn get(&self, key: &str) -> Option<&Json> {
+        match self {
+            Json::Object(pairs) => pairs.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// Follows a path of object keys, e.g. `["params", "textDocument", "uri"]`.
+    pub fn path(&self, keys: &[&str]) -> Option<&Json> {
+        keys.iter().try_fold(self, |value, key| value.get(key))
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Json::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_u64(&self) -> Option<u64> {
+        match self {
+            Json::Number(n) if *n >= 0.0 && n.fract() == 0.0 => Some(*n as u64),
+            _ => None,
+        }
+    }
+
+    pub fn as_bool(&self) -> Option<bool> {
+        match self {
+            Json::Bool(b) => Some(*b),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> Option<&Vec<Json>> {
+        match self {
+            Json::Array(items) => Some(items),
+            _ => None,
+        }
+    }
+
+    pub fn parse(text: &str) -> Result<Json, String> {
+        let mut parser = Parser {
+            chars: text.chars().collect(),
+            position: 0,
+        };
+        let value: Json = parser.value()?;
+        parser.skip_whitespace();
+        if parser.position != parser.chars.len() {
+            return Err(format!(
+                "Error: Unexpected trailing characters at {}",
+                parser.position
+            ));
+        }
+        Ok(value)
+    }
+}
+
+impl From<&str> for Json {
+    fn from(s: &str) -> Json {
+        Json::String(s.to_string())
+    }
+}
+
+impl From<String> for Json {
+    fn from(s: String) -> Json {
+        Json::String(s)
+    }
+}
+
+impl From<usize> for Json {
+    fn from(n: usize) -> Json {
+        Json::Number(n as f64)
+    }
+}
+
+impl From<bool> for Json {
+    fn from(b: bool) -> Json {
+        Json::Bool(b)
+    }
+}
+
+pub fn escape(text: &str) -> String {
+    let mut escaped: String = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '"' => escaped.push_str("\\\""),
+            '\\' => escaped.push_str("\\\\"),
+            '\n' => escaped.push_str("\\n"),
+            '\r' => escaped.push_str("\\r"),
+            '\t' => escaped.push_str("\\t"),
+            c if (c as u32) < 0x20 => escaped.push_str(&format!("\\u{:04x}", c as u32)),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(b) => write!(f, "{}", b),
+            Json::Number(n) if n.fract() == 0.0 && n.abs() < 1e15 => write!(f, "{}", *n as i64),
+            Json::Number(n) => write!(f, "{}", n),
+            Json::String(s) => write!(f, "\"{}\"", escape(s)),
+            Json::Array(items) => {
+                write!(f, "[")?;
+                for (index, item) in items.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", item)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(pairs) => {
+                write!(f, "{{")?;
+                for (index, (key, value)) in pairs.iter().enumerate() {
+                    if index > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "\"{}\":{}", escape(key), value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+}
+
+impl Parser {
+    fn skip_whitespace(&mut self) {
+        while self
+            .chars
+            .get(self.position)
+            .is_some_and(|c| c.is_whitespace())
+        {
+            self.position += 1;
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_whitespace();
+        if self.chars.get(self.position) == Some(&expected) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(format!(
+                "Error: Expected '{}' at {}",
+                expected, self.position
+            ))
+        }
+    }
+
+    fn literal(&mut self, word: &str, value: Json) -> Result<Json, String> {
+        let end: usize = self.position + word.len();
+        if end <= self.chars.len()
+            && self.chars[self.position..end]
+                .iter()
+                .copied()
+                .eq(word.chars())
+        {
+            self.position = end;
+            Ok(value)
+        } else {
+            Err(format!("Error: Invalid literal at {}", self.position))
+        }
+    }
+
+    fn value(&mut self) -> Result<Json, String> {
+        self.skip_whitespace();
+        match self.chars.get(self.position) {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(Json::String(self.string()?)),
+            Some('t') => self.literal("true", Json::Bool(true)),
+            Some('f') => self.literal("false", Json::Bool(false)),
+            Some('n') => self.literal("null", Json::Null),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            _ => Err(format!("Error: Unexpected input at {}", self.position)),
+        }
+    }
+
+    fn object(&mut self) -> Result<Json, String> {
+        self.expect('{')?;
+        let mut pairs: Vec<(String, Json)> = Vec::new();
+        self.skip_whitespace();
+        if self.chars.get(self.position) == Some(&'}') {
+            self.position += 1;
+            return Ok(Json::Object(pairs));
+        }
+        loop {
+            self.skip_whitespace();
+            let key: String = self.string()?;
+            self.expect(':')?;
+            pairs.push((key, self.value()?));
+            self.skip_whitespace();
+            match self.chars.get(self.position) {
+                Some(',') => self.position += 1,
+                Some('}') => {
+                    self.position += 1;
+                    return Ok(Json::Object(pairs));
+                }
+                _ => return Err(format!("Error: Expected ',' or '}}' at {}", self.position)),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Json, String> {
+        self.expect('[')?;
+        let mut items: Vec<Json> = Vec::new();
+        self.skip_whitespace();
+        if self.chars.get(self.position) == Some(&']') {
+            self.position += 1;
+            return Ok(Json::Array(items));
+        }
+        loop {
+            items.push(self.value()?);
+            self.skip_whitespace();
+            match self.chars.get(self.position) {
+                Some(',') => self.position += 1,
+                Some(']') => {
+                    self.position += 1;
+                    return Ok(Json::Array(items));
+                }
+                _ => return Err(format!("Error: Expected ',' or ']' at {}", self.position)),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        if self.chars.get(self.position) != Some(&'"') {
+            return Err(format!("Error: Expected string at {}", self.position));
+        }
+        self.position += 1;
+        let mut text: String = String::new();
+        loop {
+            let c: char = *self
+                .chars
+                .get(self.position)
+                .ok_or("Error: Unterminated string")?;
+            self.position += 1;
+            match c {
+                '"' => return Ok(text),
+                '\\' => {
+                    let escaped: char = *self
+                        .chars
+                        .get(self.position)
+                        .ok_or("Error: Unterminated string")?;
+                    self.position += 1;
+                    match escaped {
+                        'n' => text.push('\n'),
+                        'r' => text.push('\r'),
+                        't' => text.push('\t'),
+                        'b' => text.push('\u{8}'),
+                        'f' => text.push('\u{c}'),
+                        'u' => {
+                            let hex: String = self
+                                .chars
+                                .get(self.position..self.position + 4)
+                                .ok_or("Error: Invalid unicode escape")?
+                                .iter()
+                                .collect();
+                            self.position += 4;
+                            let code: u32 = u32::from_str_radix(&hex, 16)
+                                .map_err(|_| "Error: Invalid unicode escape".to_string())?;
+                            text.push(char::from_u32(code).unwrap_or('\u{FFFD}'));
+                        }
+                        other => text.push(other),
+                    }
+                }
+                c => text.push(c),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Json, String> {
+        let start: usize = self.position;
+        while self
+            .chars
+            .get(self.position)
+            .is_some_and(|c| c.is_ascii_digit() || "+-.eE".contains(*c))
+        {
+            self.position += 1;
+        }
+        let text: String = self.chars[start..self.position].iter().collect();
+        text.parse::<f64>()
+            .map(Json::Number)
+            .map_err(|_| format!("Error: Invalid number '{}'", text))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_print_round_trip() {
+        let text =
+            r#"{"id":1,"params":{"uri":"file:///a.nha","ok":true,"list":[null,-2.5,"x\"y"]}}"#;
+        let value = Json::parse(text).unwrap();
+        assert_eq!(
+            value.path(&["params", "uri"]).unwrap().as_str(),
+            Some("file:///a.nha")
+        );
+        assert_eq!(value.get("id").unwrap().as_u64(), Some(1));
+        assert_eq!(value.to_string(), text);
+    }
+
+    #[test]
+    fn test_unicode_escape() {
+        let value = Json::parse(r#""caf\u00e9\n""#).unwrap();
+        assert_eq!(value.as_str(), Some("café\n"));
+    }
+}
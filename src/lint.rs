@@ -0,0 +1,328 @@
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
+
+use crate::assembler2::{grab_memory_parts, tokenize};
+use crate::assembler_cleaner::{split_comment, split_label};
+use crate::instruction::Instruction;
+use crate::program::Program;
+use crate::source_map::SourceLocation;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Warning {
+    pub location: Option<SourceLocation>,
+    pub message: String,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(
+                f,
+                "{}:{}:{}: warning: {}",
+                location.file, location.line, location.column, self.message
+            ),
+            None => write!(f, "warning: {}", self.message),
+        }
+    }
+}
+
+/// Where control can go after an instruction.
+struct Successors {
+    fall_through: Option<usize>,
+    jump: Option<usize>,
+}
+
+impl Successors {
+    fn targets(&self) -> impl Iterator<Item = usize> {
+        self.fall_through.into_iter().chain(self.jump)
+    }
+}
+
+fn successors(address: usize, instruction: Instruction) -> Successors {
+    let next: Option<usize> = match instruction {
+        Instruction::Halt => None,
+        _ if instruction.is_unconditional_jump() => None,
+        _ => Some(address + 2),
+    };
+    let jump: Option<usize> = match instruction {
+        Instruction::JumpEqual { address, .. } => Some(address as usize),
+        _ => None,
+    };
+    Successors {
+        fall_through: next,
+        jump,
+    }
+}
+
+fn compares_with_r0(instruction: Instruction) -> bool {
+    matches!(
+        instruction,
+        Instruction::JumpEqual { .. } | Instruction::JumpRegister { .. }
+    ) && !instruction.is_unconditional_jump()
+}
+
+/// Checks an assembled program for common mistakes. `source` is the text it
+/// was assembled from, used to find label references.
+pub fn lint(program: &Program, path: &str, source: &str) -> Vec<Warning> {
+    let code_length: usize = program.code_length;
+    let mut instructions: BTreeMap<usize, Instruction> = BTreeMap::new();
+    for address in (0..code_length).step_by(2) {
+        let word: u16 = (program.bytes[address] as u16) << 8 | program.bytes[address + 1] as u16;
+        if let Some(instruction) = Instruction::decode(word) {
+            instructions.insert(address, instruction);
+        }
+    }
+
+    // Addresses paired with messages; a BTreeMap keeps the report in program order
+    let mut findings: BTreeMap<usize, Vec<String>> = BTreeMap::new();
+    let mut report = |address: usize, message: String| {
+        findings.entry(address).or_default().push(message);
+    };
+
+    // Walk every reachable instruction, tracking whether R0 may have been set on the way
+    let mut r0_set: BTreeMap<usize, bool> = BTreeMap::new();
+    let mut worklist: VecDeque<usize> = VecDeque::new();
+    if instructions.contains_key(&0) {
+        r0_set.insert(0, false);
+        worklist.push_back(0);
+    }
+
+    while let Some(address) = worklist.pop_front() {
+        let instruction: Instruction = instructions[&address];
+        let set_after: bool = r0_set[&address] || instruction.written_register() == Some(0);
+        let next: Successors = successors(address, instruction);
+
+        for target in next.targets() {
+            if !instructions.contains_key(&target) {
+                continue;
+            }
+            let known: Option<bool> = r0_set.get(&target).copied();
+            if known.is_none() || (set_after && known == Some(false)) {
+                r0_set.insert(target, set_after || known.unwrap_or(false));
+                worklist.push_back(target);
+            }
+        }
+    }
+
+    let reachable: Vec<usize> = r0_set.keys().copied().collect();
+    let data_end: usize = program.bytes.len();
+
+    for &address in &reachable {
+        let instruction: Instruction = instructions[&address];
+        let next: Successors = successors(address, instruction);
+
+        if let Some(target) = next.fall_through.filter(|target| *target >= code_length) {
+            let message: &str = if target < data_end {
+                "execution falls through into DATA"
+            } else {
+                "execution runs past the end of the program"
+            };
+            report(address, message.to_string());
+        }
+        if let Some(target) = next.jump.filter(|target| *target >= code_length) {
+            let message: String = if target < data_end {
+                format!("{} jumps into DATA at {:02X}", instruction, target)
+            } else {
+                format!(
+                    "{} jumps to {:02X}, past the end of the program",
+                    instruction, target
+                )
+            };
+            report(address, message);
+        }
+
+        if compares_with_r0(instruction) && !r0_set[&address] {
+            report(
+                address,
+                format!("{} compares against R0, but R0 is never set", instruction),
+            );
+        }
+
+        if let Instruction::StoreDirect { address: store, .. } = instruction {
+            if (store as usize) < code_length {
+                report(
+                    address,
+                    format!("{} overwrites the running code", instruction),
+                );
+            }
+        }
+    }
+
+    // Work back from every HALT to find the instructions that can still reach
+    // one. A jump to an address in a register might go anywhere, so it counts.
+    let mut predecessors: BTreeMap<usize, Vec<usize>> = BTreeMap::new();
+    for &address in &reachable {
+        for target in successors(address, instructions[&address]).targets() {
+            predecessors.entry(target).or_default().push(address);
+        }
+    }
+    let mut can_halt: HashSet<usize> = HashSet::new();
+    let mut worklist: VecDeque<usize> = reachable
+        .iter()
+        .copied()
+        .filter(|address| {
+            matches!(
+                instructions[address],
+                Instruction::Halt | Instruction::JumpRegister { .. }
+            )
+        })
+        .collect();
+    while let Some(address) = worklist.pop_front() {
+        if can_halt.insert(address) {
+            worklist.extend(predecessors.get(&address).into_iter().flatten());
+        }
+    }
+
+    // Report each stuck region once, where control first enters it
+    for &address in &reachable {
+        if can_halt.contains(&address) {
+            continue;
+        }
+        if address == 0 {
+            report(
+                0,
+                "no path from the start of the program reaches HALT".to_string(),
+            );
+        } else if predecessors[&address]
+            .iter()
+            .any(|from| can_halt.contains(from))
+        {
+            report(
+                address,
+                format!("no path from {} reaches HALT", instructions[&address]),
+            );
+        }
+    }
+
+    let mut warnings: Vec<Warning> = Vec::new();
+    for (address, messages) in findings {
+        for message in messages {
+            warnings.push(Warning {
+                location: program.source_map.lookup(address as u8).cloned(),
+                message,
+            });
+        }
+    }
+    warnings.extend(unused_labels(program, path, source));
+    warnings
+}
+
+fn unused_labels(program: &Program, path: &str, source: &str) -> Vec<Warning> {
+    let mut referenced: HashSet<String> = HashSet::new();
+    for line in source.lines() {
+        let (_, statement) = split_label(split_comment(line).0);
+        for token in tokenize(statement) {
+            referenced.extend(grab_memory_parts(&token));
+        }
+    }
+
+    let mut warnings: Vec<Warning> = Vec::new();
+    for (index, line) in source.lines().enumerate() {
+        let (code, _) = split_comment(line);
+        if let (Some(label), _) = split_label(code) {
+            if program.labels.contains_key(label) && !referenced.contains(label) {
+                warnings.push(Warning {
+                    location: Some(SourceLocation {
+                        file: path.to_string(),
+                        line: index + 1,
+                        column: line[..line.find(label).unwrap_or(0)].chars().count() + 1,
+                        label: Some(label.to_string()),
+                    }),
+                    message: format!("label '{}' is never used", label),
+                });
+            }
+        }
+    }
+    warnings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source_map::SourceMap;
+    use std::collections::HashMap;
+
+    fn program(code: &[u16], data: &[u8], labels: &[(&str, u8)]) -> Program {
+        let mut bytes: Vec<u8> = code.iter().flat_map(|word| word.to_be_bytes()).collect();
+        let code_length: usize = bytes.len();
+        bytes.extend_from_slice(data);
+        Program {
+            bytes,
+            labels: labels
+                .iter()
+                .map(|(name, address)| (name.to_string(), *address))
+                .collect::<HashMap<String, u8>>(),
+            code_length,
+            source_map: SourceMap::new(),
+            optimizations: Vec::new(),
+        }
+    }
+
+    fn messages(program: &Program, source: &str) -> Vec<String> {
+        lint(program, "test.nha", source)
+            .into_iter()
+            .map(|warning| warning.message)
+            .collect()
+    }
+
+    #[test]
+    fn test_chessboard_is_clean() {
+        let source: String = std::fs::read_to_string("./test1.nha").unwrap();
+        let program: Program = Program::assemble("./test1.nha").unwrap();
+        assert_eq!(lint(&program, "./test1.nha", &source), vec![]);
+    }
+
+    #[test]
+    fn test_falls_through_into_data() {
+        // MOV 1 -> R1, then DATA
+        let program = program(&[0x2101], &[0x07], &[]);
+        assert_eq!(
+            messages(&program, ""),
+            vec![
+                "execution falls through into DATA",
+                "no path from the start of the program reaches HALT"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_jump_into_data_and_unset_r0() {
+        // JMPEQ 04, R1 / HALT / DATA
+        let program = program(&[0xB104, 0xC000], &[0x00], &[]);
+        assert_eq!(
+            messages(&program, ""),
+            vec![
+                "JMPEQ 04, R1 jumps into DATA at 04",
+                "JMPEQ 04, R1 compares against R0, but R0 is never set"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_loop_with_no_exit_beside_a_halt() {
+        // MOV 1 -> R0 / JMPEQ 06, R1 / HALT / JMP 06
+        let stuck = program(&[0x2001, 0xB106, 0xC000, 0xB006], &[], &[]);
+        assert_eq!(
+            messages(&stuck, ""),
+            vec!["no path from JMP 06 reaches HALT"]
+        );
+
+        // A jump through a register might reach the HALT, so is not reported
+        // MOV 06 -> R1 / JMP R1 / JMP 04 / HALT
+        let indirect = program(&[0x2106, 0xF001, 0xB004, 0xC000], &[], &[]);
+        assert_eq!(messages(&indirect, ""), Vec::<String>::new());
+    }
+
+    #[test]
+    fn test_store_over_code_and_unused_label() {
+        // MOV R1 -> [00] / HALT
+        let program = program(&[0x3100, 0xC000], &[], &[("end", 0x02)]);
+        assert_eq!(
+            messages(&program, "MOV R1 -> [00]\nend: HALT\n"),
+            vec![
+                "MOV R1 -> [00] overwrites the running code",
+                "label 'end' is never used"
+            ]
+        );
+    }
+}
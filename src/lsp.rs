@@ -0,0 +1,659 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+use crate::assembler2::MNEMONICS;
+use crate::assembler_cleaner::{split_comment, CONTROL_DIRECTIVES, DATA_DIRECTIVES};
+use crate::instruction::Instruction;
+use crate::json::Json;
+use crate::lint;
+use crate::program::{AssemblyError, Program};
+
+// LSP constants used below
+const SEVERITY_ERROR: usize = 1;
+const SEVERITY_WARNING: usize = 2;
+const COMPLETION_VARIABLE: usize = 6;
+const COMPLETION_KEYWORD: usize = 14;
+const COMPLETION_REFERENCE: usize = 18;
+const SYMBOL_FUNCTION: usize = 12;
+const SYMBOL_CONSTANT: usize = 14;
+
+/// A word in a line of source, with 0-based character columns.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Word {
+    start: usize,
+    end: usize,
+    text: String,
+}
+
+/// Words in the code part of a line; comments are ignored.
+fn words(line: &str) -> Vec<Word> {
+    let (code, _) = split_comment(line);
+    let mut found: Vec<Word> = Vec::new();
+    let mut current: Option<Word> = None;
+
+    for (column, c) in code.chars().enumerate() {
+        if c.is_ascii_alphanumeric() || c == '_' || c == '.' {
+            match current.as_mut() {
+                Some(word) => {
+                    word.end = column + 1;
+                    word.text.push(c);
+                }
+                None => {
+                    current = Some(Word {
+                        start: column,
+                        end: column + 1,
+                        text: c.to_string(),
+                    })
+                }
+            }
+        } else if let Some(word) = current.take() {
+            found.push(word);
+        }
+    }
+    found.extend(current);
+    found
+}
+
+/// A label defined by `name:` at the start of a line.
+struct LabelDefinition {
+    line: usize,
+    word: Word,
+}
+
+fn label_definitions(text: &str) -> Vec<LabelDefinition> {
+    let mut definitions: Vec<LabelDefinition> = Vec::new();
+    for (line_index, line) in text.lines().enumerate() {
+        let (code, _) = split_comment(line);
+        if let Some((label, _)) = code.split_once(':') {
+            if let Some(word) = words(label).into_iter().next() {
+                definitions.push(LabelDefinition {
+                    line: line_index,
+                    word,
+                });
+            }
+        }
+    }
+    definitions
+}
+
+fn word_at(text: &str, line: usize, character: usize) -> Option<Word> {
+    let line: &str = text.lines().nth(line)?;
+    words(line)
+        .into_iter()
+        .find(|word| word.start <= character && character <= word.end)
+}
+
+fn range(line: usize, start: usize, end: usize) -> Json {
+    Json::object(vec![
+        (
+            "start",
+            Json::object(vec![("line", line.into()), ("character", start.into())]),
+        ),
+        (
+            "end",
+            Json::object(vec![("line", line.into()), ("character", end.into())]),
+        ),
+    ])
+}
+
+fn location(uri: &str, line: usize, word: &Word) -> Json {
+    Json::object(vec![
+        ("uri", uri.into()),
+        ("range", range(line, word.start, word.end)),
+    ])
+}
+
+fn uri_to_path(uri: &str) -> String {
+    let path: &str = uri.strip_prefix("file://").unwrap_or(uri);
+    path.replace("%20", " ")
+}
+
+fn response(id: &Json, result: Json) -> Json {
+    Json::object(vec![
+        ("jsonrpc", "2.0".into()),
+        ("id", id.clone()),
+        ("result", result),
+    ])
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    Json::object(vec![
+        ("jsonrpc", "2.0".into()),
+        ("method", method.into()),
+        ("params", params),
+    ])
+}
+
+/// Language server state: the open documents, by URI.
+#[derive(Default)]
+pub struct Server {
+    documents: HashMap<String, String>,
+    shutdown_requested: bool,
+    pub exited: bool,
+}
+
+impl Server {
+    /// Handles one incoming message and returns the messages to send back.
+    pub fn handle(&mut self, message: &Json) -> Vec<Json> {
+        let method: &str = message.get("method").and_then(Json::as_str).unwrap_or("");
+        let params: Json = message.get("params").cloned().unwrap_or(Json::Null);
+        let uri: String = params
+            .path(&["textDocument", "uri"])
+            .and_then(Json::as_str)
+            .unwrap_or("")
+            .to_string();
+
+        let id: &Json = match message.get("id") {
+            Some(id) => id,
+            None => return self.handle_notification(method, &params, &uri),
+        };
+
+        let result: Json = match method {
+            "initialize" => capabilities(),
+            "shutdown" => {
+                self.shutdown_requested = true;
+                Json::Null
+            }
+            "textDocument/hover" => self.hover(&uri, &params),
+            "textDocument/definition" => self.definition(&uri, &params),
+            "textDocument/references" => self.references(&uri, &params),
+            "textDocument/completion" => self.completion(&uri, &params),
+            "textDocument/documentSymbol" => self.document_symbols(&uri),
+            _ => {
+                return vec![Json::object(vec![
+                    ("jsonrpc", "2.0".into()),
+                    ("id", id.clone()),
+                    (
+                        "error",
+                        Json::object(vec![
+                            ("code", Json::Number(-32601.0)),
+                            ("message", format!("Unknown method '{}'", method).into()),
+                        ]),
+                    ),
+                ])]
+            }
+        };
+        vec![response(id, result)]
+    }
+
+    fn handle_notification(&mut self, method: &str, params: &Json, uri: &str) -> Vec<Json> {
+        match method {
+            "textDocument/didOpen" => {
+                let text: &str = params
+                    .path(&["textDocument", "text"])
+                    .and_then(Json::as_str)
+                    .unwrap_or("");
+                self.documents.insert(uri.to_string(), text.to_string());
+                vec![self.diagnostics(uri)]
+            }
+            "textDocument/didChange" => {
+                // Full document sync: the last change holds the whole text
+                let text: Option<&str> = params
+                    .get("contentChanges")
+                    .and_then(Json::as_array)
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change.get("text"))
+                    .and_then(Json::as_str);
+                if let Some(text) = text {
+                    self.documents.insert(uri.to_string(), text.to_string());
+                }
+                vec![self.diagnostics(uri)]
+            }
+            "textDocument/didClose" => {
+                self.documents.remove(uri);
+                vec![notification(
+                    "textDocument/publishDiagnostics",
+                    Json::object(vec![
+                        ("uri", uri.into()),
+                        ("diagnostics", Json::Array(vec![])),
+                    ]),
+                )]
+            }
+            "exit" => {
+                self.exited = true;
+                vec![]
+            }
+            _ => vec![],
+        }
+    }
+
+    fn text(&self, uri: &str) -> &str {
+        self.documents
+            .get(uri)
+            .map(|text| text.as_str())
+            .unwrap_or("")
+    }
+
+    fn assemble(&self, uri: &str) -> Result<Program, AssemblyError> {
+        Program::assemble_source(&uri_to_path(uri), self.text(uri))
+    }
+
+    fn diagnostics(&self, uri: &str) -> Json {
+        let text: &str = self.text(uri);
+        let line_length = |line: usize| -> usize {
+            text.lines()
+                .nth(line)
+                .map_or(0, |line| line.chars().count())
+        };
+        let diagnostic = |line: usize, column: usize, severity: usize, message: &str| -> Json {
+            Json::object(vec![
+                ("range", range(line, column, line_length(line).max(column))),
+                ("severity", severity.into()),
+                ("source", "brookshear".into()),
+                ("message", message.into()),
+            ])
+        };
+
+        let mut diagnostics: Vec<Json> = Vec::new();
+        match self.assemble(uri) {
+            Ok(program) => {
+                for warning in lint::lint(&program, &uri_to_path(uri), text) {
+                    let (line, column) = warning
+                        .location
+                        .as_ref()
+                        .map_or((0, 0), |l| (l.line - 1, l.column - 1));
+                    diagnostics.push(diagnostic(line, column, SEVERITY_WARNING, &warning.message));
+                }
+            }
+            Err(error) => {
+                let (line, column) = error
+                    .location
+                    .as_ref()
+                    .map_or((0, 0), |l| (l.line - 1, l.column - 1));
+                diagnostics.push(diagnostic(line, column, SEVERITY_ERROR, &error.message));
+            }
+        }
+
+        notification(
+            "textDocument/publishDiagnostics",
+            Json::object(vec![
+                ("uri", uri.into()),
+                ("diagnostics", Json::Array(diagnostics)),
+            ]),
+        )
+    }
+
+    fn position(params: &Json) -> (usize, usize) {
+        let line: u64 = params
+            .path(&["position", "line"])
+            .and_then(Json::as_u64)
+            .unwrap_or(0);
+        let character: u64 = params
+            .path(&["position", "character"])
+            .and_then(Json::as_u64)
+            .unwrap_or(0);
+        (line as usize, character as usize)
+    }
+
+    fn hover(&self, uri: &str, params: &Json) -> Json {
+        let (line, character) = Server::position(params);
+        let program: Program = match self.assemble(uri) {
+            Ok(program) => program,
+            Err(_) => return Json::Null,
+        };
+
+        let contents: String = match word_at(self.text(uri), line, character) {
+            Some(word) if program.labels.contains_key(&word.text) => {
+                let address: u8 = program.labels[&word.text];
+                format!("**{}** — address `{:02X}`", word.text, address)
+            }
+            _ => {
+                let addresses: Vec<u8> = program.source_map.addresses_for_line(line + 1);
+                let first: usize = match addresses.first() {
+                    Some(first) => *first as usize,
+                    None => return Json::Null,
+                };
+                let bytes: String = addresses
+                    .iter()
+                    .map(|address| format!("{:02X}", program.bytes[*address as usize]))
+                    .collect::<Vec<String>>()
+                    .join(" ");
+
+                if first < program.code_length {
+                    let word: u16 =
+                        (program.bytes[first] as u16) << 8 | program.bytes[first + 1] as u16;
+                    match Instruction::decode(word) {
+                        Some(instruction) => format!(
+                            "`{:02X}`: `{}`  `{}`\n\n{}",
+                            first,
+                            bytes,
+                            instruction,
+                            instruction.describe()
+                        ),
+                        None => format!("`{:02X}`: `{}`", first, bytes),
+                    }
+                } else {
+                    format!("`{:02X}`: `{}` (DATA)", first, bytes)
+                }
+            }
+        };
+
+        Json::object(vec![(
+            "contents",
+            Json::object(vec![
+                ("kind", "markdown".into()),
+                ("value", contents.into()),
+            ]),
+        )])
+    }
+
+    fn definition(&self, uri: &str, params: &Json) -> Json {
+        let (line, character) = Server::position(params);
+        let word: Word = match word_at(self.text(uri), line, character) {
+            Some(word) => word,
+            None => return Json::Null,
+        };
+        label_definitions(self.text(uri))
+            .into_iter()
+            .find(|definition| definition.word.text == word.text)
+            .map_or(Json::Null, |definition| {
+                location(uri, definition.line, &definition.word)
+            })
+    }
+
+    fn references(&self, uri: &str, params: &Json) -> Json {
+        let (line, character) = Server::position(params);
+        let text: &str = self.text(uri);
+        let include_declaration: bool = params
+            .path(&["context", "includeDeclaration"])
+            .and_then(Json::as_bool)
+            .unwrap_or(true);
+
+        let name: String = match word_at(text, line, character) {
+            Some(word) => word.text,
+            None => return Json::Array(vec![]),
+        };
+        let definitions: Vec<LabelDefinition> = label_definitions(text);
+        if !definitions.iter().any(|d| d.word.text == name) {
+            return Json::Array(vec![]);
+        }
+
+        let mut locations: Vec<Json> = Vec::new();
+        for (line_index, source_line) in text.lines().enumerate() {
+            for word in words(source_line) {
+                let is_definition: bool = definitions
+                    .iter()
+                    .any(|d| d.line == line_index && d.word == word);
+                if word.text == name && (include_declaration || !is_definition) {
+                    locations.push(location(uri, line_index, &word));
+                }
+            }
+        }
+        Json::Array(locations)
+    }
+
+    fn completion(&self, uri: &str, params: &Json) -> Json {
+        let (line, character) = Server::position(params);
+        let source_line: &str = self.text(uri).lines().nth(line).unwrap_or("");
+        let before: String = source_line.chars().take(character).collect();
+        let statement: &str = before.split_once(':').map_or(before.as_str(), |(_, s)| s);
+
+        let item = |label: String, kind: usize| -> Json {
+            Json::object(vec![("label", label.into()), ("kind", kind.into())])
+        };
+
+        // Offer mnemonics until one has been typed, then operands
+        let mut items: Vec<Json> = Vec::new();
+        if statement.split_whitespace().count() <= 1 && !statement.ends_with(' ') {
+            let keywords = MNEMONICS
+                .iter()
+                .chain(["DATA"].iter())
+                .chain(DATA_DIRECTIVES.iter())
+                .chain(CONTROL_DIRECTIVES.iter());
+            for mnemonic in keywords {
+                items.push(item(mnemonic.to_string(), COMPLETION_KEYWORD));
+            }
+        } else {
+            for register in 0..16 {
+                items.push(item(format!("R{:X}", register), COMPLETION_VARIABLE));
+            }
+            for definition in label_definitions(self.text(uri)) {
+                items.push(item(definition.word.text, COMPLETION_REFERENCE));
+            }
+        }
+        Json::Array(items)
+    }
+
+    fn document_symbols(&self, uri: &str) -> Json {
+        let program: Option<Program> = self.assemble(uri).ok();
+        let mut symbols: Vec<Json> = Vec::new();
+
+        for definition in label_definitions(self.text(uri)) {
+            let address: Option<u8> = program
+                .as_ref()
+                .and_then(|p| p.labels.get(&definition.word.text).copied());
+            let is_data: bool = match (&program, address) {
+                (Some(program), Some(address)) => address as usize >= program.code_length,
+                _ => false,
+            };
+            let line_range: Json =
+                range(definition.line, definition.word.start, definition.word.end);
+            symbols.push(Json::object(vec![
+                ("name", definition.word.text.clone().into()),
+                (
+                    "detail",
+                    address
+                        .map_or(String::new(), |a| format!("{:02X}", a))
+                        .into(),
+                ),
+                (
+                    "kind",
+                    if is_data {
+                        SYMBOL_CONSTANT
+                    } else {
+                        SYMBOL_FUNCTION
+                    }
+                    .into(),
+                ),
+                ("range", line_range.clone()),
+                ("selectionRange", line_range),
+            ]));
+        }
+        Json::Array(symbols)
+    }
+}
+
+fn capabilities() -> Json {
+    Json::object(vec![
+        (
+            "capabilities",
+            Json::object(vec![
+                ("textDocumentSync", 1usize.into()),
+                ("hoverProvider", true.into()),
+                ("definitionProvider", true.into()),
+                ("referencesProvider", true.into()),
+                ("documentSymbolProvider", true.into()),
+                (
+                    "completionProvider",
+                    Json::object(vec![(
+                        "triggerCharacters",
+                        Json::Array(vec![" ".into(), ",".into(), "[".into()]),
+                    )]),
+                ),
+            ]),
+        ),
+        (
+            "serverInfo",
+            Json::object(vec![("name", "brookshear-lsp".into())]),
+        ),
+    ])
+}
+
+/// Reads one `Content-Length` framed message, or `None` at end of input.
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<String>> {
+    let mut length: Option<usize> = None;
+    loop {
+        let mut header: String = String::new();
+        if input.read_line(&mut header)? == 0 {
+            return Ok(None);
+        }
+        let header: &str = header.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some(value) = header.strip_prefix("Content-Length:") {
+            length = value.trim().parse().ok();
+        }
+    }
+
+    let mut body: Vec<u8> = vec![0; length.unwrap_or(0)];
+    input.read_exact(&mut body)?;
+    Ok(Some(String::from_utf8_lossy(&body).into_owned()))
+}
+
+fn write_message(output: &mut impl Write, message: &Json) -> io::Result<()> {
+    let body: String = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    output.flush()
+}
+
+/// Runs the language server over stdin and stdout until the client sends `exit`.
+/// Returns whether the client asked for a shutdown first, as the protocol expects.
+pub fn serve() -> io::Result<bool> {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let mut output = io::stdout();
+    let mut server: Server = Server::default();
+
+    while let Some(body) = read_message(&mut input)? {
+        let message: Json = match Json::parse(&body) {
+            Ok(message) => message,
+            Err(e) => {
+                eprintln!("Ignoring malformed message: {}", e);
+                continue;
+            }
+        };
+        for reply in server.handle(&message) {
+            write_message(&mut output, &reply)?;
+        }
+        if server.exited {
+            break;
+        }
+    }
+    Ok(server.shutdown_requested)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const URI: &str = "file:///tmp/test.nha";
+    const SOURCE: &str =
+        "        MOV 1 -> R0\nloop:   JMPEQ done, R0\n        JMP loop\ndone:   HALT\n";
+
+    fn request(server: &mut Server, method: &str, line: usize, character: usize) -> Json {
+        let message = Json::object(vec![
+            ("id", 1usize.into()),
+            ("method", method.into()),
+            (
+                "params",
+                Json::object(vec![
+                    ("textDocument", Json::object(vec![("uri", URI.into())])),
+                    (
+                        "position",
+                        Json::object(vec![("line", line.into()), ("character", character.into())]),
+                    ),
+                ]),
+            ),
+        ]);
+        server
+            .handle(&message)
+            .remove(0)
+            .get("result")
+            .unwrap()
+            .clone()
+    }
+
+    fn open(text: &str) -> (Server, Json) {
+        let mut server = Server::default();
+        let message = Json::object(vec![
+            ("method", "textDocument/didOpen".into()),
+            (
+                "params",
+                Json::object(vec![(
+                    "textDocument",
+                    Json::object(vec![("uri", URI.into()), ("text", text.into())]),
+                )]),
+            ),
+        ]);
+        let published = server.handle(&message).remove(0);
+        (server, published)
+    }
+
+    #[test]
+    fn test_error_diagnostic_points_at_line() {
+        let (_, published) = open("        MOV 1 -> R0\n        BOGUS R1\n        HALT\n");
+        let diagnostics = published.path(&["params", "diagnostics"]).unwrap();
+        let first = &diagnostics.as_array().unwrap()[0];
+        assert_eq!(
+            first.path(&["range", "start", "line"]).unwrap().as_u64(),
+            Some(1)
+        );
+        assert_eq!(first.get("severity").unwrap().as_u64(), Some(1));
+    }
+
+    #[test]
+    fn test_hover_shows_encoding() {
+        let (mut server, _) = open(SOURCE);
+        let hover = request(&mut server, "textDocument/hover", 0, 9);
+        assert_eq!(
+            hover.path(&["contents", "value"]).unwrap().as_str(),
+            Some("`00`: `20 01`  `MOV 01 -> R0`\n\nR0 ← 01")
+        );
+    }
+
+    #[test]
+    fn test_definition_and_references() {
+        let (mut server, _) = open(SOURCE);
+        let definition = request(&mut server, "textDocument/definition", 1, 16);
+        assert_eq!(
+            definition
+                .path(&["range", "start", "line"])
+                .unwrap()
+                .as_u64(),
+            Some(3)
+        );
+
+        let references = request(&mut server, "textDocument/references", 2, 13);
+        let lines: Vec<u64> = references
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|r| {
+                r.path(&["range", "start", "line"])
+                    .unwrap()
+                    .as_u64()
+                    .unwrap()
+            })
+            .collect();
+        assert_eq!(lines, vec![1, 2]);
+    }
+
+    #[test]
+    fn test_completion_offers_operands_after_mnemonic() {
+        let (mut server, _) = open(SOURCE);
+        let items = request(&mut server, "textDocument/completion", 2, 12);
+        let labels: Vec<&str> = items
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|item| item.get("label").unwrap().as_str().unwrap())
+            .collect();
+        assert!(labels.contains(&"RF"));
+        assert!(labels.contains(&"loop"));
+        assert!(!labels.contains(&"HALT"));
+    }
+
+    #[test]
+    fn test_document_symbols() {
+        let (mut server, _) = open(SOURCE);
+        let symbols = request(&mut server, "textDocument/documentSymbol", 0, 0);
+        let names: Vec<&str> = symbols
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|symbol| symbol.get("name").unwrap().as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["loop", "done"]);
+    }
+}
@@ -1,33 +1,582 @@
-use std::collections::HashMap;
 mod assembler2;
 mod assembler_cleaner;
+mod compiler;
+mod debugger;
+mod devices;
+mod display;
+// The first emulator, kept as it was alongside emulator2, which the commands use
+#[allow(dead_code, clippy::needless_return, clippy::manual_range_patterns)]
 mod emulator;
 mod emulator2;
+mod events;
+mod float8;
+mod formatter;
+mod instruction;
+mod json;
+mod lint;
+mod lsp;
+mod optimizer;
+mod program;
+mod snapshot;
+mod source_map;
+mod trace;
+mod watch;
+
+use display::LiveView;
+use emulator2::{EmulatorError, Stop, TrapPolicy};
+use events::HumanSink;
+use program::Program;
+use snapshot::Snapshot;
+use std::collections::HashMap;
+use std::time::Duration;
+use trace::{AddressRange, TraceFormat, Tracer};
+
+const DEFAULT_SOURCE: &str = "./test1.nha";
 
 fn main() {
-    let (cleaned_lines, label_addresses, data_entries): (
-        Vec<String>,
-        HashMap<String, u8>,
-        Vec<u8>,
-    ) = assembler_cleaner::assember_cleaning();
+    let args: Vec<String> = std::env::args().skip(1).collect();
+
+    match args.first().map(|s| s.as_str()) {
+        Some("run") => run_command(&args[1..]),
+        Some("fmt") => fmt_command(&args[1..]),
+        Some("lint") => lint_command(&args[1..]),
+        Some("lsp") => lsp_command(),
+        Some("compile") => compile_command(&args[1..]),
+        Some("watch") => watch_command(&args[1..]),
+        Some("step") => step_command(&args[1..]),
+        Some("debug") => debug_command(&args[1..]),
+        _ => run_command(&args),
+    }
+}
+
+/// `run [FILE] [--log quiet|human|json] [--optimize] [--on-trap halt|ignore|jump:XX]
+/// [--limit N|none] [--timeout SECONDS] [--no-loop-check] [--load SNAPSHOT]
+/// [--save SNAPSHOT] [--trace text|csv|jsonl] [--trace-out FILE] [--trace-only RANGE]
+/// [--display] [--display-out FILE] [--expect-display FILE] [--device KIND@ADDR]
+/// [--input FILE]`:
+/// assemble a file and run it to completion. `--optimize`
+/// runs the peephole pass and reports what it removed; `--on-trap` picks what
+/// an invalid instruction does. A program that never halts stops at the
+/// instruction limit, the timeout, or as soon as its state repeats. `--load`
+/// starts from a saved machine state instead of a source file, and `--save`
+/// writes the state the machine stopped in. `--trace` writes a record of every
+/// instruction executed, or only those in the `--trace-only` ranges.
+/// `--display` shows the 32×32 display live as the program draws, and
+/// `--display-out` saves its final frame as a PBM image. `--expect-display`
+/// fails the run unless the final frame matches a PBM or ASCII-art image.
+/// `--device` maps a device, such as `timer@F0` or `console@F0`, into memory;
+/// `--input` gives the console scripted input instead of stdin.
+fn run_command(args: &[String]) {
+    let mut source_path: Option<String> = None;
+    let mut log_format: String = String::from("quiet");
+    let mut optimize: bool = false;
+    let mut trap_policy: String = String::from("halt");
+    let mut step_limit: Option<usize> = Some(emulator2::DEFAULT_STEP_LIMIT);
+    let mut timeout: Option<Duration> = None;
+    let mut detect_loops: bool = true;
+    let mut load_path: Option<String> = None;
+    let mut save_path: Option<String> = None;
+    let mut trace_format: Option<String> = None;
+    let mut trace_path: Option<String> = None;
+    let mut trace_ranges: Vec<String> = Vec::new();
+    let mut live_view: Option<LiveView> = None;
+    let mut display_path: Option<String> = None;
+    let mut expected_path: Option<String> = None;
+    let mut device_specs: Vec<String> = Vec::new();
+    let mut input_path: Option<String> = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--log" {
+            log_format = args.next().cloned().unwrap_or_default();
+        } else if arg == "--optimize" {
+            optimize = true;
+        } else if arg == "--on-trap" {
+            trap_policy = args.next().cloned().unwrap_or_default();
+        } else if arg == "--limit" {
+            let limit: String = args.next().cloned().unwrap_or_default();
+            step_limit = match limit.as_str() {
+                "none" => None,
+                _ => match limit.parse() {
+                    Ok(limit) => Some(limit),
+                    Err(_) => {
+                        eprintln!("Error: Invalid instruction limit '{}'.", limit);
+                        std::process::exit(2);
+                    }
+                },
+            };
+        } else if arg == "--timeout" {
+            let seconds: String = args.next().cloned().unwrap_or_default();
+            match seconds.parse::<f64>().ok().filter(|s| *s >= 0.0) {
+                Some(seconds) => timeout = Some(Duration::from_secs_f64(seconds)),
+                None => {
+                    eprintln!("Error: Invalid timeout '{}'.", seconds);
+                    std::process::exit(2);
+                }
+            }
+        } else if arg == "--no-loop-check" {
+            detect_loops = false;
+        } else if arg == "--load" {
+            load_path = args.next().cloned();
+        } else if arg == "--save" {
+            save_path = args.next().cloned();
+        } else if arg == "--trace" {
+            trace_format = args.next().cloned();
+        } else if arg == "--trace-out" {
+            trace_path = args.next().cloned();
+        } else if arg == "--trace-only" {
+            trace_ranges.extend(args.next().cloned());
+        } else if arg == "--display" {
+            live_view = Some(LiveView::default());
+        } else if arg == "--display-out" {
+            display_path = args.next().cloned();
+        } else if arg == "--expect-display" {
+            expected_path = args.next().cloned();
+        } else if arg == "--device" {
+            device_specs.extend(args.next().cloned());
+        } else if arg == "--input" {
+            input_path = args.next().cloned();
+        } else {
+            source_path = Some(arg.clone());
+        }
+    }
+
+    match events::sink_from_name(&log_format) {
+        Ok(sink) => events::set_sink(sink),
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+    let trap_policy: TrapPolicy = match TrapPolicy::parse(&trap_policy) {
+        Ok(policy) => policy,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let (bytes, labels, snapshot) = match &load_path {
+        Some(load_path) => {
+            if source_path.is_some() {
+                eprintln!(
+                    "Error: --load starts from a snapshot, so it cannot be given a source file."
+                );
+                std::process::exit(2);
+            }
+            let snapshot: Snapshot = match Snapshot::load(load_path) {
+                Ok(snapshot) => snapshot,
+                Err(e) => {
+                    eprintln!("{}", e);
+                    std::process::exit(1);
+                }
+            };
+            (Vec::new(), HashMap::new(), Some(snapshot))
+        }
+        None => {
+            let source_path: String = source_path.unwrap_or_else(|| String::from(DEFAULT_SOURCE));
+            let program: Program = assemble_for_run(&source_path, optimize);
+            if log_format == "human" {
+                // Now that the program is assembled, the trace can show source locations
+                events::set_sink(Some(Box::new(HumanSink {
+                    source_map: Some(program.source_map.clone()),
+                })));
+            }
+            (program.bytes, program.labels, None)
+        }
+    };
+    let mut emulator = emulator2::Emulator::new(bytes);
+    for text in &device_specs {
+        let attached: Result<(), String> = devices::parse(text, input_path.as_deref())
+            .and_then(|spec| emulator.attach_device(&spec.name, spec.start, spec.end, spec.device));
+        if let Err(e) = attached {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    }
+    if input_path.is_some() && !device_specs.iter().any(|text| text.starts_with("console@")) {
+        eprintln!("Error: --input needs a console device, such as --device console@F0.");
+        std::process::exit(2);
+    }
+    // Devices come first, so the snapshot can restore their state too
+    if let Some(snapshot) = &snapshot {
+        if let Err(e) = emulator.restore(snapshot) {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    }
+    let mut tracer: Option<Tracer> = trace_format
+        .map(|format| start_trace(&format, trace_path.as_deref(), &trace_ranges, &labels));
 
-    for line in &cleaned_lines {
-        println!("{}", line);
+    emulator.set_trap_policy(trap_policy);
+    // A resumed run gets the full limit on top of what it had already executed
+    emulator.set_step_limit(step_limit.map(|limit| emulator.steps() + limit));
+    emulator.set_timeout(timeout);
+    emulator.set_loop_detection(detect_loops);
+    let mut trace_error: Option<std::io::Error> = None;
+    let mut output_error: Option<std::io::Error> = None;
+    let outcome: Result<Stop, EmulatorError> = emulator.run_with(|emulator, record| {
+        if let Some(tracer) = &mut tracer {
+            if let Err(e) = tracer.record(record) {
+                trace_error.get_or_insert(e);
+            }
+        }
+        if let Some(live_view) = &mut live_view {
+            let drew: bool = record
+                .memory_writes
+                .iter()
+                .any(|write| write.address as usize >= display::DISPLAY_START);
+            if drew {
+                if let Err(e) = live_view.refresh(emulator.memory()) {
+                    output_error.get_or_insert(e);
+                }
+            }
+        }
+    });
+    if let Some(live_view) = &mut live_view {
+        if let Err(e) = live_view.finish(emulator.memory()) {
+            output_error.get_or_insert(e);
+        }
     }
+    if let Some(e) = output_error {
+        eprintln!("Error writing to the terminal: {}", e);
+        std::process::exit(2);
+    }
+    if let Some(e) = trace_error.or_else(|| tracer.as_mut()?.flush().err()) {
+        eprintln!("Error writing the trace: {}", e);
+        std::process::exit(2);
+    }
+    match &outcome {
+        Ok(Stop::Halted) => {}
+        Ok(stop) => println!("Stopped after {} instructions: {}.", emulator.steps(), stop),
+        Err(error) => eprintln!("{}", error),
+    }
+    println!("Final register values: {:02X?}", emulator.registers());
+    if let Some(display_path) = &display_path {
+        match std::fs::write(display_path, display::to_pbm(emulator.memory())) {
+            Ok(()) => println!("Saved the display to {}", display_path),
+            Err(e) => {
+                eprintln!("Error writing file {}: {}", display_path, e);
+                std::process::exit(2);
+            }
+        }
+    }
+    if let Some(save_path) = &save_path {
+        match emulator.snapshot().save(save_path) {
+            Ok(()) => println!("Saved the machine state to {}", save_path),
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+    if outcome.is_err() {
+        std::process::exit(1);
+    }
+    if let Some(expected_path) = &expected_path {
+        let checked = display::load_image(expected_path)
+            .and_then(|expected| display::diff_image(emulator.memory(), &expected));
+        match checked {
+            Ok(None) => println!("The display matches {}", expected_path),
+            Ok(Some(diff)) => {
+                println!("The display does not match {}", expected_path);
+                print!("{}", diff);
+                std::process::exit(1);
+            }
+            Err(e) => {
+                eprintln!("{}", e);
+                std::process::exit(2);
+            }
+        }
+    }
+}
 
-    for (label, address) in &label_addresses {
-        println!("Label: {}, Address: {:02X}", label, address);
+/// Sets up `run --trace`, exiting if the format, ranges or file are invalid.
+fn start_trace(
+    format: &str,
+    path: Option<&str>,
+    ranges: &[String],
+    labels: &HashMap<String, u8>,
+) -> Tracer {
+    let exit = |e: String| -> ! {
+        eprintln!("{}", e);
+        std::process::exit(2);
+    };
+    let format: TraceFormat = TraceFormat::parse(format).unwrap_or_else(|e| exit(e));
+    let ranges: Vec<AddressRange> = ranges
+        .iter()
+        .map(|range| trace::parse_range(range, labels))
+        .collect::<Result<_, _>>()
+        .unwrap_or_else(|e| exit(e));
+    let output: Box<dyn std::io::Write> = match path {
+        Some(path) => match std::fs::File::create(path) {
+            Ok(file) => Box::new(std::io::BufWriter::new(file)),
+            Err(e) => exit(format!("Error writing file {}: {}", path, e)),
+        },
+        None => Box::new(std::io::stdout()),
+    };
+    Tracer::new(format, ranges, output)
+        .unwrap_or_else(|e| exit(format!("Error writing the trace: {}", e)))
+}
+
+/// Assembles the program for `run`, exiting on errors. With `optimize`, the
+/// peephole pass runs and its changes are reported.
+fn assemble_for_run(source_path: &str, optimize: bool) -> Program {
+    let assembled = if optimize {
+        std::fs::read_to_string(source_path)
+            .map_err(|e| format!("Error reading file {}: {}", source_path, e))
+            .and_then(|source| {
+                Program::assemble_optimized(source_path, &source).map_err(|e| e.to_string())
+            })
+    } else {
+        Program::assemble(source_path).map_err(|e| e.to_string())
+    };
+    let program: Program = match assembled {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if optimize {
+        for optimization in &program.optimizations {
+            println!("{}", optimization);
+        }
+        println!(
+            "Optimizer removed {} instruction(s), saving {} bytes.",
+            program.optimizations.len(),
+            program.optimizations.len() * 2
+        );
     }
 
-    println!("Label addresses: {:?}", label_addresses);
+    program
+}
+
+/// `fmt [--check] [FILE...]`: rewrite files in the canonical layout. With
+/// `--check`, only report files that would change and exit non-zero if any would.
+fn fmt_command(args: &[String]) {
+    let check: bool = args.iter().any(|arg| arg == "--check");
+    let mut paths: Vec<&String> = args.iter().filter(|arg| *arg != "--check").collect();
+    let default_path: String = String::from(DEFAULT_SOURCE);
+    if paths.is_empty() {
+        paths.push(&default_path);
+    }
 
-    let mut assembled_code: Vec<u8> =
-        assembler2::assembler(cleaned_lines, label_addresses).unwrap();
+    let mut unformatted: usize = 0;
+    for path in paths {
+        let source: String = match std::fs::read_to_string(path) {
+            Ok(source) => source,
+            Err(e) => {
+                eprintln!("Error reading file {}: {}", path, e);
+                std::process::exit(2);
+            }
+        };
 
-    assembled_code.extend(data_entries);
+        let formatted: String = formatter::format_source(&source);
+        if formatted == source {
+            continue;
+        }
 
-    println!("Assembled code: {:02X?}", assembled_code);
+        unformatted += 1;
+        if check {
+            println!("Would reformat: {}", path);
+        } else if let Err(e) = std::fs::write(path, formatted) {
+            eprintln!("Error writing file {}: {}", path, e);
+            std::process::exit(2);
+        } else {
+            println!("Reformatted: {}", path);
+        }
+    }
 
-    emulator2::Emulator::new(assembled_code).run();
-    println!("Emulator finished running.");
+    if check && unformatted > 0 {
+        std::process::exit(1);
+    }
+}
+
+/// `lint [FILE]`: report likely mistakes, exiting non-zero if there are any.
+fn lint_command(args: &[String]) {
+    let source_path: String = args
+        .first()
+        .cloned()
+        .unwrap_or_else(|| String::from(DEFAULT_SOURCE));
+
+    let source: String = match std::fs::read_to_string(&source_path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Error reading file {}: {}", source_path, e);
+            std::process::exit(2);
+        }
+    };
+    let program: Program = match Program::assemble(&source_path) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let warnings: Vec<lint::Warning> = lint::lint(&program, &source_path, &source);
+    for warning in &warnings {
+        println!("{}", warning);
+    }
+    if !warnings.is_empty() {
+        println!("{} warning(s)", warnings.len());
+        std::process::exit(1);
+    }
+}
+
+/// `watch [FILE]`: reassemble and rerun the program every time it is saved.
+fn watch_command(args: &[String]) {
+    let source_path: String = args
+        .first()
+        .cloned()
+        .unwrap_or_else(|| String::from(DEFAULT_SOURCE));
+    watch::watch(&source_path);
+}
+
+/// `step [FILE] [COUNT] [--until XX]`: execute COUNT instructions (default 1),
+/// or keep going until the PC reaches XX, printing what each one did.
+fn step_command(args: &[String]) {
+    let mut source_path: String = String::from(DEFAULT_SOURCE);
+    let mut count: usize = 1;
+    let mut until: Option<u8> = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "--until" {
+            let address: String = args.next().cloned().unwrap_or_default();
+            match u8::from_str_radix(&address, 16) {
+                Ok(address) => until = Some(address),
+                Err(_) => {
+                    eprintln!("Error: Invalid address '{}' for --until.", address);
+                    std::process::exit(2);
+                }
+            }
+        } else if let Ok(number) = arg.parse() {
+            count = number;
+        } else {
+            source_path = arg.clone();
+        }
+    }
+
+    let program: Program = match Program::assemble(&source_path) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+
+    let mut emulator = emulator2::Emulator::new(program.bytes);
+    let outcome: Result<(), EmulatorError> = match until {
+        Some(address) => emulator
+            .run_until(|emulator, record| {
+                println!("{}", record);
+                emulator.program_counter() == address
+            })
+            .map(|_| ()),
+        None => emulator.run_for(count).map(|records| {
+            for record in records {
+                println!("{}", record);
+            }
+        }),
+    };
+    if let Err(error) = outcome {
+        eprintln!("{}", error);
+    }
+    println!("Registers: {:02X?}", emulator.registers());
+    if emulator.is_halted() {
+        println!("Halted.");
+    } else {
+        println!("Next instruction at {:02X}", emulator.program_counter());
+    }
+}
+
+/// `debug [FILE]`: assemble a file and explore it in an interactive debugger.
+fn debug_command(args: &[String]) {
+    let source_path: String = args
+        .first()
+        .cloned()
+        .unwrap_or_else(|| String::from(DEFAULT_SOURCE));
+    let program: Program = match Program::assemble(&source_path) {
+        Ok(program) => program,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    if let Err(e) = debugger::repl(program) {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// `compile FILE [-o OUT]`: compile a structured-language program to `.nha`
+/// assembly, written next to it unless `-o` names another file.
+fn compile_command(args: &[String]) {
+    let mut source_path: Option<String> = None;
+    let mut output_path: Option<String> = None;
+    let mut args = args.iter();
+
+    while let Some(arg) = args.next() {
+        if arg == "-o" {
+            output_path = args.next().cloned();
+        } else {
+            source_path = Some(arg.clone());
+        }
+    }
+
+    let source_path: String = match source_path {
+        Some(path) => path,
+        None => {
+            eprintln!("Error: compile needs a source file.");
+            std::process::exit(2);
+        }
+    };
+    let output_path: String = match compiler::output_path(&source_path, output_path.as_deref()) {
+        Ok(path) => path,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
+
+    let source: String = match std::fs::read_to_string(&source_path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("Error reading file {}: {}", source_path, e);
+            std::process::exit(2);
+        }
+    };
+    let assembly: String = match compiler::compile(&source, &source_path) {
+        Ok(assembly) => assembly,
+        Err(e) => {
+            eprintln!("{}: {}", source_path, e);
+            std::process::exit(1);
+        }
+    };
+
+    // The output should always assemble; check before writing it
+    if let Err(e) = Program::assemble_source(&output_path, &assembly) {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    }
+    if let Err(e) = std::fs::write(&output_path, assembly) {
+        eprintln!("Error writing file {}: {}", output_path, e);
+        std::process::exit(2);
+    }
+    println!("Compiled {} -> {}", source_path, output_path);
+}
+
+/// `lsp`: run a language server for `.nha` files over stdin and stdout.
+fn lsp_command() {
+    match lsp::serve() {
+        Ok(true) => {}
+        Ok(false) => std::process::exit(1),
+        Err(e) => {
+            eprintln!("Error: Language server stopped: {}", e);
+            std::process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,265 @@
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+
+use crate::assembler2::assemble_line;
+use crate::instruction::Instruction;
+use crate::source_map::SourceLocation;
+
+/// An instruction the peephole pass removed.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Optimization {
+    pub location: SourceLocation,
+    pub statement: String,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for Optimization {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}:{}: removed `{}` ({})",
+            self.location.file,
+            self.location.line,
+            self.location.column,
+            self.statement,
+            self.reason
+        )
+    }
+}
+
+fn decode_line(line: &str) -> Option<Instruction> {
+    let bytes: [u8; 2] = assemble_line(line).ok()?;
+    Instruction::decode(u16::from_be_bytes(bytes))
+}
+
+fn label_in(line: &str, labels: &HashMap<String, u8>) -> Option<String> {
+    line.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_'))
+        .find(|word| labels.contains_key(*word))
+        .map(|word| word.to_string())
+}
+
+/// Picks out instructions that can go without changing what the program does.
+/// `symbolic` and `resolved` are the same instruction lines before and after
+/// label substitution; the result holds line indices and the reason for each.
+///
+/// Removing instructions moves everything after them, so a program that reaches
+/// code or DATA through a literal address instead of a label is left alone.
+/// That includes a literal loaded into a register when the program loads or
+/// stores through a register, since any such value might be a pointer, and any
+/// program that jumps to an address held in a register.
+pub fn removals(
+    symbolic: &[String],
+    resolved: &[String],
+    labels: &HashMap<String, u8>,
+    data_length: usize,
+) -> Vec<(usize, &'static str)> {
+    let instructions: Vec<Instruction> = match resolved.iter().map(|l| decode_line(l)).collect() {
+        Some(instructions) => instructions,
+        // Leave the assembler to report the error
+        None => return Vec::new(),
+    };
+    let code_length: usize = instructions.len() * 2;
+
+    if instructions
+        .iter()
+        .any(|instruction| matches!(instruction, Instruction::JumpRegister { .. }))
+    {
+        return Vec::new();
+    }
+    let indirect: bool = instructions.iter().any(|instruction| {
+        matches!(
+            instruction,
+            Instruction::LoadIndirect { .. } | Instruction::StoreIndirect { .. }
+        )
+    });
+
+    for (instruction, line) in instructions.iter().zip(symbolic) {
+        let address: Option<u8> = match *instruction {
+            Instruction::LoadDirect { address, .. }
+            | Instruction::StoreDirect { address, .. }
+            | Instruction::JumpEqual { address, .. } => Some(address),
+            Instruction::LoadImmediate { value, .. } if indirect => Some(value),
+            _ => None,
+        };
+        if let Some(address) = address {
+            if (address as usize) < code_length + data_length && label_in(line, labels).is_none() {
+                return Vec::new();
+            }
+        }
+    }
+
+    // Anything a label names may be reached from elsewhere
+    let targets: HashSet<usize> = labels
+        .values()
+        .map(|address| *address as usize)
+        .filter(|address| *address < code_length)
+        .map(|address| address / 2)
+        .collect();
+
+    // The constant each register is known to hold, by label name or value
+    let mut known: [Option<String>; 16] = Default::default();
+    let mut removed: Vec<(usize, &'static str)> = Vec::new();
+
+    for (index, instruction) in instructions.iter().enumerate() {
+        if targets.contains(&index) {
+            known = Default::default();
+        }
+
+        let constant: Option<(u8, String)> = match *instruction {
+            Instruction::LoadImmediate { register, value } => Some((
+                register,
+                label_in(&symbolic[index], labels).unwrap_or_else(|| format!("{:02X}", value)),
+            )),
+            _ => None,
+        };
+
+        let reason: Option<&'static str> = match *instruction {
+            Instruction::Nop => Some("no operation"),
+            Instruction::Move { from, to } if from == to => Some("move to itself"),
+            Instruction::JumpEqual { address, .. } if address as usize == index * 2 + 2 => {
+                Some("jump to the next instruction")
+            }
+            _ => match &constant {
+                Some((register, key)) if known[*register as usize].as_ref() == Some(key) => {
+                    Some("register already holds this constant")
+                }
+                _ => None,
+            },
+        };
+        if let Some(reason) = reason {
+            removed.push((index, reason));
+            continue;
+        }
+
+        if let Some(register) = instruction.written_register() {
+            known[register as usize] = None;
+        }
+        if let Some((register, key)) = constant {
+            known[register as usize] = Some(key);
+        }
+        if matches!(
+            instruction,
+            Instruction::JumpEqual { .. } | Instruction::JumpRegister { .. } | Instruction::Halt
+        ) {
+            known = Default::default();
+        }
+    }
+
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::program::Program;
+
+    const SOURCE: &str = "        MOV 1 -> R1
+        NOP
+        MOV R1 -> R1
+        MOV 1 -> R1
+        JMP next
+next:   MOV 2 -> R2
+loop:   MOV 2 -> R2
+        ADDI R1, R2 -> R2
+        JMPEQ loop, R2
+        MOV R2 -> [value]
+        HALT
+value:  DATA 00
+";
+
+    #[test]
+    fn test_removes_waste_and_relocates_labels() {
+        let program = Program::assemble_optimized("test.nha", SOURCE).unwrap();
+        let reasons: Vec<(usize, &str)> = program
+            .optimizations
+            .iter()
+            .map(|o| (o.location.line, o.reason))
+            .collect();
+        assert_eq!(
+            reasons,
+            vec![
+                (2, "no operation"),
+                (3, "move to itself"),
+                (4, "register already holds this constant"),
+                (5, "jump to the next instruction"),
+            ]
+        );
+
+        // The reload at `loop` stays, because `loop` is a jump target
+        assert_eq!(program.labels["next"], 0x02);
+        assert_eq!(program.labels["loop"], 0x04);
+        assert_eq!(program.labels["value"], 0x0E);
+        assert_eq!(
+            program.bytes,
+            vec![
+                0x21, 0x01, 0x22, 0x02, 0x22, 0x02, 0x52, 0x12, 0xB2, 0x04, 0x32, 0x0E, 0xC0, 0x00,
+                0x00
+            ]
+        );
+        assert_eq!(program.source_map.lookup(0x04).unwrap().line, 7);
+    }
+
+    #[test]
+    fn test_literal_addresses_disable_removal() {
+        let source: &str = "        NOP\n        JMP 04\n        HALT\n";
+        let program = Program::assemble_optimized("test.nha", source).unwrap();
+        assert!(program.optimizations.is_empty());
+        assert_eq!(program.bytes.len(), 6);
+    }
+
+    #[test]
+    fn test_literal_pointers_disable_removal() {
+        let source: &str = "        NOP
+        MOV 08 -> R1
+        MOV [R1] -> R2
+        HALT
+        DATA 42
+";
+        let program = Program::assemble_optimized("test.nha", source).unwrap();
+        assert!(program.optimizations.is_empty());
+        let mut emulator = crate::emulator2::Emulator::new(program.bytes);
+        emulator.run().unwrap();
+        assert_eq!(emulator.registers()[2], 0x42);
+
+        // A jump to an address in a register may land anywhere
+        let source: &str = "        NOP
+        MOV end -> R1
+        JMP R1
+end:    HALT
+";
+        let program = Program::assemble_optimized("test.nha", source).unwrap();
+        assert!(program.optimizations.is_empty());
+    }
+
+    #[test]
+    fn test_alignment_follows_the_optimized_code() {
+        let source: &str = "        MOV R1 -> R1
+        MOV [t] -> R3
+        HALT
+        .align 4
+t:      DATA 42
+";
+        let program = Program::assemble_optimized("test.nha", source).unwrap();
+        assert_eq!(program.optimizations.len(), 1);
+        assert_eq!(program.labels["t"], 0x04);
+        assert_eq!(program.bytes, vec![0x13, 0x04, 0xC0, 0x00, 0x42]);
+    }
+
+    #[test]
+    fn test_full_screen_bitmap_stays_on_the_display() {
+        // Only the top-left pixel is lit
+        let mut rows: Vec<String> = vec![".".repeat(32); 32];
+        rows[0].replace_range(0..1, "#");
+        let source: String = format!(
+            "        MOV R1 -> R1\n        HALT\nscreen: .bitmap {}\n",
+            rows.join("/")
+        );
+        let program = Program::assemble_optimized("test.nha", &source).unwrap();
+        assert_eq!(program.optimizations.len(), 1);
+        assert_eq!(program.labels["screen"], 0x80);
+
+        let mut emulator = crate::emulator2::Emulator::new(program.bytes);
+        emulator.run().unwrap();
+        assert!(crate::display::pixel(emulator.memory(), 0, 0));
+        assert_eq!(crate::display::lit_pixels(emulator.memory()), 1);
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::assembler2;
+use crate::assembler_cleaner;
+use crate::optimizer::Optimization;
+use crate::source_map::{SourceLocation, SourceMap};
+
+/// A program that could not be assembled, with the statement at fault when it is known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AssemblyError {
+    pub location: Option<SourceLocation>,
+    pub message: String,
+}
+
+impl fmt::Display for AssemblyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.location {
+            Some(location) => write!(
+                f,
+                "{}:{}:{}: {}",
+                location.file, location.line, location.column, self.message
+            ),
+            None => write!(f, "{}", self.message),
+        }
+    }
+}
+
+/// An assembled program, ready to load into the emulator.
+pub struct Program {
+    pub bytes: Vec<u8>,
+    pub labels: HashMap<String, u8>,
+    /// Number of bytes of instructions; DATA follows from here.
+    pub code_length: usize,
+    pub source_map: SourceMap,
+    /// What the peephole pass removed, if it ran.
+    pub optimizations: Vec<Optimization>,
+}
+
+impl Program {
+    pub fn assemble(path: &str) -> Result<Program, AssemblyError> {
+        Program::from_cleaned(assembler_cleaner::assember_cleaning(path)?)
+    }
+
+    /// Assembles source text that is not (or not yet) saved to `path`.
+    pub fn assemble_source(path: &str, contents: &str) -> Result<Program, AssemblyError> {
+        Program::from_cleaned(assembler_cleaner::clean_source(path, contents, false)?)
+    }
+
+    /// Like `assemble_source`, with the peephole optimizer run over the instructions.
+    pub fn assemble_optimized(path: &str, contents: &str) -> Result<Program, AssemblyError> {
+        Program::from_cleaned(assembler_cleaner::clean_source(path, contents, true)?)
+    }
+
+    fn from_cleaned(cleaned: assembler_cleaner::CleanedSource) -> Result<Program, AssemblyError> {
+        let (cleaned_lines, label_addresses, data_entries, source_map, optimizations) = cleaned;
+
+        let mut bytes: Vec<u8> = assembler2::assembler(cleaned_lines, label_addresses.clone())
+            .map_err(|e| AssemblyError {
+                location: source_map.lookup((e.index * 2) as u8).cloned(),
+                message: e.message,
+            })?;
+        let code_length: usize = bytes.len();
+        bytes.extend(data_entries);
+
+        if bytes.len() > 256 {
+            return Err(AssemblyError {
+                location: None,
+                message: format!(
+                    "Error: Program is {} bytes long, but memory only holds 256.",
+                    bytes.len()
+                ),
+            });
+        }
+
+        Ok(Program {
+            bytes,
+            labels: label_addresses,
+            code_length,
+            source_map,
+            optimizations,
+        })
+    }
+}
@@ -0,0 +1,236 @@
+use std::fmt;
+
+/// The first line of every snapshot file, with the format version.
+const HEADER: &str = "brookshear-snapshot 1";
+
+/// The whole state of a machine, saved to resume it later or written by hand
+/// to start a program from a prepared state.
+///
+/// The file format is line-based text with hex numbers. After the header
+/// line, each line is a key and its value; `#` starts a comment:
+///
+/// ```text
+/// brookshear-snapshot 1
+/// pc 08
+/// cir 2080
+/// halted no
+/// steps 4
+/// registers 00 80 00 00 00 00 00 00 00 00 00 00 00 00 00 00
+/// memory 00: 20 00 21 80 22 FF 23 01 ...
+/// device timer@F0 2A
+/// ```
+///
+/// `registers` lists R0 to RF and each `memory` line holds up to 16 bytes
+/// starting at its address. A `device` line gives an attached device's name
+/// and whatever state it saves. Keys that are left out default to zero, so a
+/// prepared state only needs the lines it cares about.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub pc: u8,
+    pub cir: u16,
+    pub halted: bool,
+    /// Instructions executed so far, in decimal.
+    pub steps: usize,
+    pub registers: [u8; 16],
+    pub memory: [u8; 256],
+    /// Each device's name and saved state.
+    pub devices: Vec<(String, String)>,
+}
+
+impl Default for Snapshot {
+    fn default() -> Self {
+        Snapshot {
+            pc: 0,
+            cir: 0,
+            halted: false,
+            steps: 0,
+            registers: [0; 16],
+            memory: [0; 256],
+            devices: Vec::new(),
+        }
+    }
+}
+
+fn hex_bytes(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .map(|byte| format!("{:02X}", byte))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+impl fmt::Display for Snapshot {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "{}", HEADER)?;
+        writeln!(f, "pc {:02X}", self.pc)?;
+        writeln!(f, "cir {:04X}", self.cir)?;
+        writeln!(f, "halted {}", if self.halted { "yes" } else { "no" })?;
+        writeln!(f, "steps {}", self.steps)?;
+        writeln!(f, "registers {}", hex_bytes(&self.registers))?;
+        for (row, bytes) in self.memory.chunks(16).enumerate() {
+            writeln!(f, "memory {:02X}: {}", row * 16, hex_bytes(bytes))?;
+        }
+        for (name, state) in &self.devices {
+            writeln!(f, "device {} {}", name, state)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parses whitespace-separated hex bytes, as many as `into` holds at most.
+fn parse_bytes(text: &str, into: &mut [u8], line: usize) -> Result<(), String> {
+    let values: Vec<&str> = text.split_whitespace().collect();
+    if values.len() > into.len() {
+        return Err(format!(
+            "Error: Line {} of the snapshot has more than {} values.",
+            line,
+            into.len()
+        ));
+    }
+    for (slot, value) in into.iter_mut().zip(values) {
+        *slot = u8::from_str_radix(value, 16).map_err(|_| {
+            format!(
+                "Error: Invalid hex byte '{}' on line {} of the snapshot.",
+                value, line
+            )
+        })?;
+    }
+    Ok(())
+}
+
+impl Snapshot {
+    pub fn parse(text: &str) -> Result<Snapshot, String> {
+        let mut snapshot: Snapshot = Snapshot::default();
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(index, line)| (index + 1, line.split('#').next().unwrap_or("").trim()))
+            .filter(|(_, line)| !line.is_empty());
+
+        match lines.next() {
+            Some((_, HEADER)) => {}
+            Some((_, line)) if line.starts_with("brookshear-snapshot") => {
+                return Err(format!(
+                    "Error: Unsupported snapshot version '{}'; expected '{}'.",
+                    line, HEADER
+                ))
+            }
+            _ => return Err(format!("Error: A snapshot must start with '{}'.", HEADER)),
+        }
+
+        for (line, text) in lines {
+            let (key, value) = text.split_once(char::is_whitespace).unwrap_or((text, ""));
+            let value: &str = value.trim();
+            let invalid = || {
+                format!(
+                    "Error: Invalid {} '{}' on line {} of the snapshot.",
+                    key, value, line
+                )
+            };
+            match key {
+                "pc" => snapshot.pc = u8::from_str_radix(value, 16).map_err(|_| invalid())?,
+                "cir" => snapshot.cir = u16::from_str_radix(value, 16).map_err(|_| invalid())?,
+                "halted" => {
+                    snapshot.halted = match value {
+                        "yes" => true,
+                        "no" => false,
+                        _ => return Err(invalid()),
+                    }
+                }
+                "steps" => snapshot.steps = value.parse().map_err(|_| invalid())?,
+                "registers" => parse_bytes(value, &mut snapshot.registers, line)?,
+                "memory" => {
+                    let (address, bytes) = value.split_once(':').ok_or_else(invalid)?;
+                    let address: usize =
+                        u8::from_str_radix(address.trim(), 16).map_err(|_| invalid())? as usize;
+                    let end: usize = (address + 16).min(256);
+                    parse_bytes(bytes, &mut snapshot.memory[address..end], line)?;
+                }
+                "device" => {
+                    let (name, state) =
+                        value.split_once(char::is_whitespace).unwrap_or((value, ""));
+                    if name.is_empty() {
+                        return Err(invalid());
+                    }
+                    snapshot
+                        .devices
+                        .push((name.to_string(), state.trim().to_string()));
+                }
+                _ => {
+                    return Err(format!(
+                        "Error: Unknown key '{}' on line {} of the snapshot.",
+                        key, line
+                    ))
+                }
+            }
+        }
+        Ok(snapshot)
+    }
+
+    pub fn load(path: &str) -> Result<Snapshot, String> {
+        let text: String = std::fs::read_to_string(path)
+            .map_err(|e| format!("Error reading file {}: {}", path, e))?;
+        Snapshot::parse(&text).map_err(|e| format!("{}: {}", path, e))
+    }
+
+    pub fn save(&self, path: &str) -> Result<(), String> {
+        std::fs::write(path, self.to_string())
+            .map_err(|e| format!("Error writing file {}: {}", path, e))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator2::Emulator;
+    use crate::program::Program;
+
+    #[test]
+    fn test_save_and_resume() {
+        let source: String = std::fs::read_to_string("./test1.nha").unwrap();
+        let program: Program = Program::assemble_source("test1.nha", &source).unwrap();
+
+        let mut finished: Emulator = Emulator::new(program.bytes.clone());
+        finished.run().unwrap();
+
+        // Stop part-way, round-trip through the text format, and carry on
+        let mut paused: Emulator = Emulator::new(program.bytes);
+        paused.run_for(50).unwrap();
+        let text: String = paused.snapshot().to_string();
+        let snapshot: Snapshot = Snapshot::parse(&text).unwrap();
+        assert_eq!(snapshot, paused.snapshot());
+        assert_eq!(snapshot.steps, 50);
+
+        let mut resumed: Emulator = Emulator::new(Vec::new());
+        resumed.restore(&snapshot).unwrap();
+        resumed.run().unwrap();
+        assert_eq!(resumed.snapshot(), finished.snapshot());
+    }
+
+    #[test]
+    fn test_prepared_state() {
+        let text: &str = "brookshear-snapshot 1
+# add two numbers that are already in registers
+registers 00 05 07
+memory 00: 53 12 C0 00
+";
+        let mut emulator: Emulator = Emulator::new(Vec::new());
+        emulator.restore(&Snapshot::parse(text).unwrap()).unwrap();
+        emulator.run().unwrap();
+        assert_eq!(emulator.registers()[3], 0x0C);
+        assert!(emulator.snapshot().halted);
+
+        assert_eq!(
+            Snapshot::parse("pc 10").unwrap_err(),
+            "Error: A snapshot must start with 'brookshear-snapshot 1'."
+        );
+        assert_eq!(
+            Snapshot::parse("brookshear-snapshot 1\npc 100").unwrap_err(),
+            "Error: Invalid pc '100' on line 2 of the snapshot."
+        );
+        assert_eq!(
+            Snapshot::parse("brookshear-snapshot 1\nflags 1").unwrap_err(),
+            "Error: Unknown key 'flags' on line 2 of the snapshot."
+        );
+    }
+}
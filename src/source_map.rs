@@ -0,0 +1,75 @@
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Where a byte of the assembled program came from. Lines and columns are 1-based.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLocation {
+    pub file: String,
+    pub line: usize,
+    pub column: usize,
+    pub label: Option<String>,
+}
+
+impl fmt::Display for SourceLocation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)?;
+        if let Some(label) = &self.label {
+            write!(f, " ({})", label)?;
+        }
+        Ok(())
+    }
+}
+
+/// Maps every assembled byte address back to the statement that produced it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SourceMap {
+    entries: BTreeMap<u8, SourceLocation>,
+}
+
+impl SourceMap {
+    pub fn new() -> Self {
+        SourceMap::default()
+    }
+
+    pub fn insert(&mut self, address: u8, location: SourceLocation) {
+        self.entries.insert(address, location);
+    }
+
+    pub fn lookup(&self, address: u8) -> Option<&SourceLocation> {
+        self.entries.get(&address)
+    }
+
+    /// Every address whose byte came from `line`, in ascending order.
+    pub fn addresses_for_line(&self, line: usize) -> Vec<u8> {
+        self.entries
+            .iter()
+            .filter(|(_, location)| location.line == line)
+            .map(|(address, _)| *address)
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::program::Program;
+
+    #[test]
+    fn test_chessboard_source_map() {
+        let program = Program::assemble("./test1.nha").unwrap();
+
+        let first = program.source_map.lookup(0x00).unwrap();
+        assert_eq!((first.line, first.column), (17, 14));
+        assert_eq!(first.label, None);
+
+        // Both bytes of an instruction point at the same statement
+        let start_loop = program.source_map.lookup(0x08).unwrap();
+        assert_eq!(start_loop.line, 22);
+        assert_eq!(start_loop.label.as_deref(), Some("startloop"));
+        assert_eq!(program.source_map.lookup(0x09), Some(start_loop));
+
+        let data = program.source_map.lookup(0x23).unwrap();
+        assert_eq!(data.line, 39);
+        assert_eq!(data.label.as_deref(), Some("endmem"));
+        assert_eq!(program.source_map.lookup(0x26), None);
+    }
+}
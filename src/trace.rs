@@ -0,0 +1,238 @@
+use std::collections::HashMap;
+use std::io::{self, Write};
+
+use crate::emulator2::StepRecord;
+use crate::json::Json;
+
+/// How `run --trace` writes one record per executed instruction.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TraceFormat {
+    /// The same line `step` prints, prefixed with the step number.
+    Text,
+    /// `step,pc,instruction,mnemonic,registers,memory` with hex values.
+    Csv,
+    /// One JSON object per line, with numbers as in `--log json`.
+    JsonLines,
+}
+
+impl TraceFormat {
+    pub fn parse(name: &str) -> Result<TraceFormat, String> {
+        match name {
+            "text" => Ok(TraceFormat::Text),
+            "csv" => Ok(TraceFormat::Csv),
+            "jsonl" => Ok(TraceFormat::JsonLines),
+            _ => Err(format!(
+                "Error: Unknown trace format '{}'. Expected text, csv or jsonl.",
+                name
+            )),
+        }
+    }
+}
+
+/// An inclusive range of instruction addresses to trace.
+pub type AddressRange = (u8, u8);
+
+/// Parses `ADDR`, `START-END` or a label, where addresses are hex or labels.
+/// A label on its own covers the code up to the next label.
+pub fn parse_range(text: &str, labels: &HashMap<String, u8>) -> Result<AddressRange, String> {
+    let address = |text: &str| -> Result<u8, String> {
+        match labels.get(text) {
+            Some(address) => Ok(*address),
+            None => u8::from_str_radix(text, 16).map_err(|_| {
+                format!(
+                    "Error: Unknown label or address '{}' in the trace filter.",
+                    text
+                )
+            }),
+        }
+    };
+
+    if let Some((start, end)) = text.split_once('-') {
+        let (start, end) = (address(start.trim())?, address(end.trim())?);
+        if start > end {
+            return Err(format!(
+                "Error: The trace filter '{}' ends before it starts.",
+                text
+            ));
+        }
+        return Ok((start, end));
+    }
+    let start: u8 = address(text)?;
+    if !labels.contains_key(text) {
+        return Ok((start, start));
+    }
+    let end: u8 = labels
+        .values()
+        .filter(|address| **address > start)
+        .min()
+        .map_or(0xFF, |next| next - 1);
+    Ok((start, end))
+}
+
+/// The register and memory changes, e.g. `R1=84 RA=84` and `[90]=FF`.
+fn changes(record: &StepRecord) -> (String, String) {
+    let registers: Vec<String> = record
+        .register_writes
+        .iter()
+        .map(|write| format!("R{:X}={:02X}", write.register, write.new))
+        .collect();
+    let memory: Vec<String> = record
+        .memory_writes
+        .iter()
+        .map(|write| format!("[{:02X}]={:02X}", write.address, write.new))
+        .collect();
+    (registers.join(" "), memory.join(" "))
+}
+
+fn mnemonic(record: &StepRecord) -> String {
+    record
+        .decoded
+        .map(|instruction| instruction.to_string())
+        .unwrap_or_else(|| "?".to_string())
+}
+
+/// Formats one record, without a line ending.
+pub fn format_record(record: &StepRecord, format: TraceFormat) -> String {
+    match format {
+        TraceFormat::Text => format!("{:>8} {}", record.step, record)
+            .trim_end()
+            .to_string(),
+        TraceFormat::Csv => {
+            let (registers, memory) = changes(record);
+            // Only the mnemonic can contain a comma, as in `ADDI R1, R2 -> R3`
+            format!(
+                "{},{:02X},{:04X},\"{}\",{},{}",
+                record.step,
+                record.pc,
+                record.instruction,
+                mnemonic(record),
+                registers,
+                memory
+            )
+        }
+        TraceFormat::JsonLines => {
+            let registers: Vec<Json> = record
+                .register_writes
+                .iter()
+                .map(|write| {
+                    Json::object(vec![
+                        ("register", (write.register as usize).into()),
+                        ("old", (write.old as usize).into()),
+                        ("new", (write.new as usize).into()),
+                    ])
+                })
+                .collect();
+            let memory: Vec<Json> = record
+                .memory_writes
+                .iter()
+                .map(|write| {
+                    Json::object(vec![
+                        ("address", (write.address as usize).into()),
+                        ("old", (write.old as usize).into()),
+                        ("new", (write.new as usize).into()),
+                    ])
+                })
+                .collect();
+            Json::object(vec![
+                ("step", record.step.into()),
+                ("pc", (record.pc as usize).into()),
+                ("instruction", (record.instruction as usize).into()),
+                ("mnemonic", mnemonic(record).into()),
+                ("registers", Json::Array(registers)),
+                ("memory", Json::Array(memory)),
+            ])
+            .to_string()
+        }
+    }
+}
+
+/// Writes the records of instructions inside the address ranges, or of every
+/// instruction when there are none.
+pub struct Tracer {
+    format: TraceFormat,
+    ranges: Vec<AddressRange>,
+    output: Box<dyn Write>,
+}
+
+impl Tracer {
+    /// Creates a tracer, writing the CSV header straight away.
+    pub fn new(
+        format: TraceFormat,
+        ranges: Vec<AddressRange>,
+        mut output: Box<dyn Write>,
+    ) -> io::Result<Tracer> {
+        if format == TraceFormat::Csv {
+            writeln!(output, "step,pc,instruction,mnemonic,registers,memory")?;
+        }
+        Ok(Tracer {
+            format,
+            ranges,
+            output,
+        })
+    }
+
+    pub fn record(&mut self, record: &StepRecord) -> io::Result<()> {
+        let traced: bool = self.ranges.is_empty()
+            || self
+                .ranges
+                .iter()
+                .any(|(start, end)| (*start..=*end).contains(&record.pc));
+        if traced {
+            writeln!(self.output, "{}", format_record(record, self.format))?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator2::Emulator;
+    use crate::program::Program;
+
+    fn records() -> Vec<StepRecord> {
+        let source: &str = "        MOV 05 -> R1
+        ADDI R1, R1 -> R2
+loop:   MOV R2 -> [80]
+        HALT
+";
+        let program: Program = Program::assemble_source("test.nha", source).unwrap();
+        let mut emulator: Emulator = Emulator::new(program.bytes);
+        emulator.run_for(4).unwrap()
+    }
+
+    #[test]
+    fn test_trace_formats() {
+        let records: Vec<StepRecord> = records();
+        assert_eq!(
+            format_record(&records[1], TraceFormat::Text),
+            "       1 02: 5211  ADDI R1, R1 -> R2    R2=0A (was 00)"
+        );
+        assert_eq!(
+            format_record(&records[1], TraceFormat::Csv),
+            "1,02,5211,\"ADDI R1, R1 -> R2\",R2=0A,"
+        );
+        assert_eq!(
+            format_record(&records[2], TraceFormat::JsonLines),
+            "{\"step\":2,\"pc\":4,\"instruction\":12928,\"mnemonic\":\"MOV R2 -> [80]\",\
+\"registers\":[],\"memory\":[{\"address\":128,\"old\":0,\"new\":10}]}"
+        );
+    }
+
+    #[test]
+    fn test_trace_filters() {
+        let mut labels: HashMap<String, u8> = HashMap::new();
+        labels.insert("start".to_string(), 0x00);
+        labels.insert("loop".to_string(), 0x04);
+        assert_eq!(parse_range("start", &labels), Ok((0x00, 0x03)));
+        assert_eq!(parse_range("loop", &labels), Ok((0x04, 0xFF)));
+        assert_eq!(parse_range("02-loop", &labels), Ok((0x02, 0x04)));
+        assert_eq!(parse_range("06", &labels), Ok((0x06, 0x06)));
+        assert!(parse_range("10-02", &labels).is_err());
+        assert!(parse_range("nowhere", &labels).is_err());
+    }
+}
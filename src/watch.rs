@@ -0,0 +1,117 @@
+use std::fs;
+use std::thread;
+use std::time::{Duration, SystemTime};
+
+use crate::assembler_cleaner::bitmap_files;
+use crate::display;
+use crate::emulator2::{Emulator, Stop};
+use crate::lint;
+use crate::program::Program;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(300);
+
+/// Enough for any program that fits in memory to finish; more means it is looping.
+const INSTRUCTION_LIMIT: usize = 100_000;
+
+/// Files whose changes should trigger a rebuild: the source file and any
+/// images its `.bitmap` directives read.
+fn watched_files(source_path: &str) -> Vec<String> {
+    let mut paths: Vec<String> = vec![source_path.to_string()];
+    if let Ok(contents) = fs::read_to_string(source_path) {
+        paths.extend(
+            bitmap_files(source_path, &contents)
+                .iter()
+                .map(|path| path.display().to_string()),
+        );
+    }
+    paths
+}
+
+fn modified_times(paths: &[String]) -> Vec<Option<SystemTime>> {
+    paths
+        .iter()
+        .map(|path| fs::metadata(path).and_then(|m| m.modified()).ok())
+        .collect()
+}
+
+/// Assembles, lints and runs the program headlessly, and describes the outcome.
+pub fn build_report(source_path: &str) -> String {
+    let source: String = match fs::read_to_string(source_path) {
+        Ok(source) => source,
+        Err(e) => return format!("Error reading file {}: {}\n", source_path, e),
+    };
+    let program: Program = match Program::assemble_source(source_path, &source) {
+        Ok(program) => program,
+        Err(e) => return format!("{}\n", e),
+    };
+
+    let mut report: String = String::new();
+    for warning in lint::lint(&program, source_path, &source) {
+        report.push_str(&format!("{}\n", warning));
+    }
+
+    let mut emulator: Emulator = Emulator::new(program.bytes);
+    emulator.set_step_limit(Some(INSTRUCTION_LIMIT));
+
+    match emulator.run() {
+        Ok(Stop::Halted) => report.push_str(&format!(
+            "Halted after {} instructions.\n",
+            emulator.steps()
+        )),
+        Ok(stop) => report.push_str(&format!(
+            "Stopped after {} instructions: {}.\n",
+            emulator.steps(),
+            stop
+        )),
+        Err(error) => report.push_str(&format!("{}\n", error)),
+    }
+
+    report.push_str(&format!(
+        "Final register values: {:02X?}\n",
+        emulator.registers()
+    ));
+    report.push_str(&format!(
+        "Display ({} of {} pixels lit):\n",
+        display::lit_pixels(emulator.memory()),
+        display::WIDTH * display::HEIGHT
+    ));
+    report.push_str(&display::render_ascii(emulator.memory()));
+    report
+}
+
+/// Rebuilds and reruns `source_path` every time it changes. Never returns.
+pub fn watch(source_path: &str) {
+    let mut last_seen: Vec<Option<SystemTime>> = Vec::new();
+
+    loop {
+        // Editing the source can change which images it reads
+        let paths: Vec<String> = watched_files(source_path);
+        let current: Vec<Option<SystemTime>> = modified_times(&paths);
+        if current != last_seen {
+            last_seen = current;
+            println!("=== {} ===", source_path);
+            print!("{}", build_report(source_path));
+            println!("Watching for changes...");
+        }
+        thread::sleep(POLL_INTERVAL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_for_chessboard() {
+        let report: String = build_report("./test1.nha");
+        assert!(report.contains("Halted after"));
+        assert!(report.contains("Final register values: [FF, FF, 01, 0F, F0"));
+        assert!(report.contains("Display (512 of 1024 pixels lit):"));
+    }
+
+    #[test]
+    fn test_report_for_missing_file() {
+        let report: String = build_report("./does-not-exist.nha");
+        assert!(report.starts_with("Error reading file ./does-not-exist.nha"));
+    }
+}